@@ -0,0 +1,347 @@
+use crate::encryption::CryptoError;
+use crate::secret::SecretBytes;
+
+#[cfg(not(any(feature = "openssl", feature = "rustcrypto")))]
+compile_error!("enable a crypto backend, either the openssl or the rustcrypto feature");
+
+// The primitives the vault is built from. Each backend has to produce the exact same bytes, so a
+// vault written by one opens with the other. Argon2id is pure Rust already and shared by both, see
+// CryptoManager::generate_key.
+pub trait CryptoBackend: Sync {
+    fn name(&self) -> &'static str;
+
+    fn random_bytes(&self, buffer: &mut [u8]) -> Result<(), CryptoError>;
+
+    fn sha256(&self, data: &[u8]) -> [u8; 32];
+
+    // HMAC-SHA256 of the parts one after another
+    fn hmac_sha256(&self, key: &[u8], parts: &[&[u8]]) -> Result<Vec<u8>, CryptoError>;
+
+    fn pbkdf2_hmac_sha256(
+        &self,
+        password: &[u8],
+        salt: &[u8],
+        iterations: u32,
+        key: &mut [u8],
+    ) -> Result<(), CryptoError>;
+
+    // the ciphertext with the 16 byte tag on the end
+    fn aes_256_gcm_seal(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, CryptoError>;
+
+    // None if the tag does not match, whatever the reason
+    fn aes_256_gcm_open(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        sealed: &[u8],
+    ) -> Option<SecretBytes>;
+
+    // only ever needed to read vaults written before aes_256_gcm, None if the padding is wrong
+    fn aes_256_cbc_decrypt(&self, key: &[u8], iv: &[u8], ciphertext: &[u8]) -> Option<SecretBytes>;
+}
+
+// OpenSSL when it is enabled, it is what every vault so far was written with
+pub fn default_backend() -> &'static dyn CryptoBackend {
+    #[cfg(feature = "openssl")]
+    return &OpenSslBackend;
+    #[cfg(not(feature = "openssl"))]
+    return &RustCryptoBackend;
+}
+
+// Takes as long whatever the inputs, as long as their lengths match
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn backend_error(e: impl std::fmt::Display) -> CryptoError {
+    CryptoError::Backend(e.to_string())
+}
+
+#[cfg(feature = "openssl")]
+pub struct OpenSslBackend;
+
+#[cfg(feature = "openssl")]
+impl CryptoBackend for OpenSslBackend {
+    fn name(&self) -> &'static str {
+        "openssl"
+    }
+
+    fn random_bytes(&self, buffer: &mut [u8]) -> Result<(), CryptoError> {
+        openssl::rand::rand_bytes(buffer).map_err(backend_error)
+    }
+
+    fn sha256(&self, data: &[u8]) -> [u8; 32] {
+        openssl::sha::sha256(data)
+    }
+
+    fn hmac_sha256(&self, key: &[u8], parts: &[&[u8]]) -> Result<Vec<u8>, CryptoError> {
+        use openssl::hash::MessageDigest;
+        use openssl::pkey::PKey;
+        use openssl::sign::Signer;
+
+        let hmac_key = PKey::hmac(key).map_err(backend_error)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &hmac_key).map_err(backend_error)?;
+        for part in parts {
+            signer.update(part).map_err(backend_error)?;
+        }
+        signer.sign_to_vec().map_err(backend_error)
+    }
+
+    fn pbkdf2_hmac_sha256(
+        &self,
+        password: &[u8],
+        salt: &[u8],
+        iterations: u32,
+        key: &mut [u8],
+    ) -> Result<(), CryptoError> {
+        openssl::pkcs5::pbkdf2_hmac(
+            password,
+            salt,
+            iterations as usize,
+            openssl::hash::MessageDigest::sha256(),
+            key,
+        )
+        .map_err(|e| CryptoError::KeyDerivationFailed(e.to_string()))
+    }
+
+    fn aes_256_gcm_seal(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        let mut tag = [0u8; 16];
+        let mut sealed = openssl::symm::encrypt_aead(
+            openssl::symm::Cipher::aes_256_gcm(),
+            key,
+            Some(nonce),
+            aad,
+            plaintext,
+            &mut tag,
+        )
+        .map_err(backend_error)?;
+        sealed.extend_from_slice(&tag);
+        Ok(sealed)
+    }
+
+    fn aes_256_gcm_open(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        sealed: &[u8],
+    ) -> Option<SecretBytes> {
+        let (ciphertext, tag) = sealed.split_at(sealed.len().checked_sub(16)?);
+        openssl::symm::decrypt_aead(
+            openssl::symm::Cipher::aes_256_gcm(),
+            key,
+            Some(nonce),
+            aad,
+            ciphertext,
+            tag,
+        )
+        .ok()
+        .map(SecretBytes::new)
+    }
+
+    fn aes_256_cbc_decrypt(&self, key: &[u8], iv: &[u8], ciphertext: &[u8]) -> Option<SecretBytes> {
+        openssl::symm::decrypt(
+            openssl::symm::Cipher::aes_256_cbc(),
+            key,
+            Some(iv),
+            ciphertext,
+        )
+        .ok()
+        .map(SecretBytes::new)
+    }
+}
+
+// Pure Rust, for static builds and cross-compiling without OpenSSL around
+#[cfg(feature = "rustcrypto")]
+pub struct RustCryptoBackend;
+
+#[cfg(feature = "rustcrypto")]
+impl CryptoBackend for RustCryptoBackend {
+    fn name(&self) -> &'static str {
+        "rustcrypto"
+    }
+
+    fn random_bytes(&self, buffer: &mut [u8]) -> Result<(), CryptoError> {
+        use rand::RngCore;
+        rand::rngs::OsRng
+            .try_fill_bytes(buffer)
+            .map_err(backend_error)
+    }
+
+    fn sha256(&self, data: &[u8]) -> [u8; 32] {
+        use sha2::Digest;
+        sha2::Sha256::digest(data).into()
+    }
+
+    fn hmac_sha256(&self, key: &[u8], parts: &[&[u8]]) -> Result<Vec<u8>, CryptoError> {
+        use hmac::Mac;
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(key).map_err(backend_error)?;
+        for part in parts {
+            mac.update(part);
+        }
+        Ok(mac.finalize().into_bytes().to_vec())
+    }
+
+    fn pbkdf2_hmac_sha256(
+        &self,
+        password: &[u8],
+        salt: &[u8],
+        iterations: u32,
+        key: &mut [u8],
+    ) -> Result<(), CryptoError> {
+        pbkdf2::pbkdf2_hmac::<sha2::Sha256>(password, salt, iterations, key);
+        Ok(())
+    }
+
+    fn aes_256_gcm_seal(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        use aes_gcm::aead::{Aead, KeyInit, Payload};
+        let cipher = aes_gcm::Aes256Gcm::new_from_slice(key).map_err(backend_error)?;
+        if nonce.len() != 12 {
+            return Err(backend_error("aes_256_gcm needs a 12 byte nonce"));
+        }
+        cipher
+            .encrypt(
+                aes_gcm::Nonce::from_slice(nonce),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(backend_error)
+    }
+
+    fn aes_256_gcm_open(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        sealed: &[u8],
+    ) -> Option<SecretBytes> {
+        use aes_gcm::aead::{Aead, KeyInit, Payload};
+        let cipher = aes_gcm::Aes256Gcm::new_from_slice(key).ok()?;
+        if nonce.len() != 12 {
+            return None;
+        }
+        cipher
+            .decrypt(
+                aes_gcm::Nonce::from_slice(nonce),
+                Payload { msg: sealed, aad },
+            )
+            .ok()
+            .map(SecretBytes::new)
+    }
+
+    fn aes_256_cbc_decrypt(&self, key: &[u8], iv: &[u8], ciphertext: &[u8]) -> Option<SecretBytes> {
+        use cbc::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+        cbc::Decryptor::<aes::Aes256>::new_from_slices(key, iv)
+            .ok()?
+            .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+            .ok()
+            .map(SecretBytes::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"same", b"same"));
+        assert!(!constant_time_eq(b"same", b"sane"));
+        assert!(!constant_time_eq(b"same", b"same "));
+    }
+
+    #[test]
+    fn test_default_backend_round_trips() {
+        let backend = default_backend();
+        let key = [7u8; 32];
+        let nonce = [9u8; 12];
+        let sealed = backend
+            .aes_256_gcm_seal(&key, &nonce, b"aad", b"plaintext")
+            .unwrap();
+        assert_eq!(sealed.len(), b"plaintext".len() + 16);
+        assert_eq!(
+            &*backend
+                .aes_256_gcm_open(&key, &nonce, b"aad", &sealed)
+                .unwrap(),
+            b"plaintext"
+        );
+        assert!(backend
+            .aes_256_gcm_open(&key, &nonce, b"other aad", &sealed)
+            .is_none());
+    }
+
+    // Both backends built in, as with --all-features: every primitive has to agree byte for byte
+    #[cfg(all(feature = "openssl", feature = "rustcrypto"))]
+    #[test]
+    fn test_backends_agree() {
+        let (openssl, rustcrypto) = (&OpenSslBackend, &RustCryptoBackend);
+        let key = [3u8; 32];
+        let nonce = [5u8; 12];
+
+        assert_eq!(openssl.sha256(b"data"), rustcrypto.sha256(b"data"));
+        assert_eq!(
+            openssl.hmac_sha256(&key, &[b"a", b"bc"]).unwrap(),
+            rustcrypto.hmac_sha256(&key, &[b"ab", b"c"]).unwrap()
+        );
+
+        let mut openssl_key = [0u8; 32];
+        let mut rustcrypto_key = [0u8; 32];
+        openssl
+            .pbkdf2_hmac_sha256(b"password", b"salt", 1000, &mut openssl_key)
+            .unwrap();
+        rustcrypto
+            .pbkdf2_hmac_sha256(b"password", b"salt", 1000, &mut rustcrypto_key)
+            .unwrap();
+        assert_eq!(openssl_key, rustcrypto_key);
+
+        let sealed = openssl
+            .aes_256_gcm_seal(&key, &nonce, b"aad", b"plaintext")
+            .unwrap();
+        assert_eq!(
+            sealed,
+            rustcrypto
+                .aes_256_gcm_seal(&key, &nonce, b"aad", b"plaintext")
+                .unwrap()
+        );
+        assert_eq!(
+            &*rustcrypto
+                .aes_256_gcm_open(&key, &nonce, b"aad", &sealed)
+                .unwrap(),
+            b"plaintext"
+        );
+
+        let iv = [1u8; 16];
+        let legacy = openssl::symm::encrypt(
+            openssl::symm::Cipher::aes_256_cbc(),
+            &key,
+            Some(&iv),
+            b"legacy data",
+        )
+        .unwrap();
+        assert_eq!(
+            &*rustcrypto.aes_256_cbc_decrypt(&key, &iv, &legacy).unwrap(),
+            b"legacy data"
+        );
+    }
+}
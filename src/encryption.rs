@@ -1,147 +1,1456 @@
-use openssl::error::ErrorStack;
-use openssl::hash::MessageDigest;
-use openssl::pkcs5::pbkdf2_hmac;
-use openssl::rand::rand_bytes;
-use openssl::symm::{Cipher, Crypter, Mode};
-use std::fs::{File, Permissions};
-use std::io::{Read, Write};
-#[cfg(unix)]
-use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
-
-const ITERATIONS: usize = 100_000;
-const KEY_LENGTH: usize = 32; //32 bytes = 256bit which is the key length size aes_256_cbc expects
-const IV_LENGTH: usize = 16;
+use argon2::{Algorithm, Argon2, Params, Version};
+use std::fmt;
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+use uuid::Uuid;
+
+use crate::crypto_backend::{self, constant_time_eq, CryptoBackend};
+use crate::escrow::{self, EscrowShare};
+use crate::recovery_code::RecoveryCode;
+use crate::secret::SecretBytes;
+use crate::storage::{
+    create_private_file, EntryStorage, FileStorage, MemoryStorage, StoredEntry, VaultStorage,
+};
+use crate::vault_format::{
+    CipherId, KdfParams, KeySlot, KeySlotKind, VaultFile, VaultHeader, ARGON2ID_DEFAULT_ITERATIONS,
+    ARGON2ID_DEFAULT_MEMORY_KIB, ARGON2ID_DEFAULT_PARALLELISM, CURRENT_FORMAT_VERSION,
+    DEFAULT_BACKUP_GENERATIONS, DEFAULT_PASSWORD_HISTORY_LENGTH,
+};
+
+const KEY_LENGTH: usize = 32; //32 bytes = 256bit which is the key length size aes_256_gcm expects
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
 const SALT_LENGTH: usize = 16;
+const CALIBRATION_MAX_MEMORY_KIB: u32 = 1024 * 1024;
+const KEY_CHECK_LABEL: &[u8] = b"ArmorPass key check";
+const KEY_FILE_CHECK_LABEL: &[u8] = b"ArmorPass key file check";
+const KEY_FILE_LENGTH: usize = 64;
+const MASTER_PASSWORD_SLOT_LABEL: &str = "master password";
+const ENTRY_LOOKUP_LABEL: &[u8] = b"ArmorPass entry lookup";
+const ENTRY_MANIFEST_LABEL: &[u8] = b"ArmorPass entry manifest";
+
+#[derive(Debug, PartialEq)]
+pub enum CryptoError {
+    IntegrityCheckFailed,
+    LegacyDecryptFailed,
+    NotAnArmorPassVault,
+    UnsupportedVersion(u16),
+    TruncatedVault,
+    MalformedHeader(String),
+    KeyDerivationFailed(String),
+    WrongMasterPassword,
+    KeyFileMissingOrWrong,
+    InvalidRecoveryCode,
+    WrongRecoveryCode,
+    InvalidEscrowShare(String),
+    WrongEscrowShares,
+    ExternallyRekeyed,
+    KeySlotsChangedOnBothSides,
+    UnlockedSlotRevoked,
+    VaultLocked(Option<u32>),
+    ReadOnly,
+    Backend(String),
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::IntegrityCheckFailed => write!(
+                f,
+                "vault integrity check failed, the master password is wrong or the file has been tampered with"
+            ),
+            CryptoError::LegacyDecryptFailed => write!(
+                f,
+                "could not decrypt the vault, the master password is wrong or this is not an ArmorPass vault"
+            ),
+            CryptoError::NotAnArmorPassVault => write!(f, "not an ArmorPass vault"),
+            CryptoError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported vault version {}, was it written by a newer ArmorPass?",
+                version
+            ),
+            CryptoError::TruncatedVault => write!(f, "the vault file is truncated"),
+            CryptoError::MalformedHeader(reason) => {
+                write!(f, "the vault header is malformed: {}", reason)
+            }
+            CryptoError::KeyDerivationFailed(reason) => {
+                write!(f, "could not derive a key from the master password: {}", reason)
+            }
+            CryptoError::WrongMasterPassword => write!(f, "the master password is wrong"),
+            CryptoError::KeyFileMissingOrWrong => write!(f, "key file missing or wrong"),
+            CryptoError::InvalidRecoveryCode => {
+                write!(f, "that is not a recovery code, check it for typos")
+            }
+            CryptoError::WrongRecoveryCode => {
+                write!(f, "the recovery code does not unlock this vault")
+            }
+            CryptoError::InvalidEscrowShare(reason) => {
+                write!(f, "the escrow shares can not be combined: {}", reason)
+            }
+            CryptoError::WrongEscrowShares => {
+                write!(f, "the escrow shares do not unlock this vault")
+            }
+            CryptoError::ExternallyRekeyed => write!(
+                f,
+                "the vault on disk was re-encrypted with a different key, reopen it to pick up \
+                 the change"
+            ),
+            CryptoError::KeySlotsChangedOnBothSides => write!(
+                f,
+                "the key slots were changed both here and in the vault on disk, reopen it to pick \
+                 up the other change"
+            ),
+            CryptoError::UnlockedSlotRevoked => write!(
+                f,
+                "the key slot this session was unlocked with was revoked in the vault on disk"
+            ),
+            CryptoError::VaultLocked(Some(pid)) => write!(
+                f,
+                "the vault is in use by another ArmorPass instance (PID {})",
+                pid
+            ),
+            CryptoError::VaultLocked(None) => {
+                write!(f, "the vault is in use by another ArmorPass instance")
+            }
+            CryptoError::ReadOnly => write!(f, "the vault was opened read-only"),
+            CryptoError::Backend(reason) => write!(f, "the crypto backend failed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+pub struct BackupInfo {
+    pub generation: u32,
+    pub modified: SystemTime,
+    pub size: u64,
+}
 
-pub struct CryptoManager {
-    salt: Vec<u8>,
-    iv: Vec<u8>,
+pub struct CryptoManager<S: VaultStorage = FileStorage> {
+    header: VaultHeader,
+    // the layout the vault was read in, the records are written again before anything older than
+    // the current one
+    format_version: u16,
+    associated_data: Vec<u8>,
     ciphertext: Vec<u8>,
-    key: Vec<u8>,
-    filepath: PathBuf,
+    // the data key, the records are encrypted with it and the key slots each hold a copy of it
+    key: SecretBytes,
+    // what the records were encrypted with before the vault had key slots, until the next write
+    legacy_key: Option<SecretBytes>,
+    unlocked_slot: u32,
+    // contents of the key file the vault was unlocked with, if it needs one
+    key_file: Option<SecretBytes>,
+    // where the vault is kept, it holds the lock while the vault is open for writing
+    storage: S,
+    on_disk: Option<DiskState>,
+    backend: &'static dyn CryptoBackend,
+}
+
+// What the vault looked like in storage when we last read or wrote it
+struct DiskState {
+    modified: SystemTime,
+    digest: [u8; 32],
+    // the base changes made in another copy are merged against, see merge_header
+    header: VaultHeader,
+}
+
+// A vault read from storage and unlocked
+struct Opened {
+    vault_file: VaultFile,
+    key: SecretBytes,
+    legacy_key: Option<SecretBytes>,
+    unlocked_slot: u32,
+    on_disk: DiskState,
+}
+
+impl DiskState {
+    fn of(
+        backend: &dyn CryptoBackend,
+        storage: &dyn VaultStorage,
+        contents: &[u8],
+        header: &VaultHeader,
+    ) -> io::Result<DiskState> {
+        Ok(DiskState {
+            modified: storage.metadata()?.modified,
+            digest: backend.sha256(contents),
+            header: header.clone(),
+        })
+    }
 }
 
 impl CryptoManager {
-    pub fn new(filepath: &PathBuf, password: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        match File::open(filepath) {
-            Ok(mut file) => {
-                let mut salt = vec![0u8; SALT_LENGTH];
-                file.read_exact(&mut salt)?;
+    // Opens the vault for reading and writing, creating it if it does not exist yet. Holds an
+    // exclusive lock on it for as long as the CryptoManager lives so a second instance can not
+    // silently overwrite our changes, or we theirs.
+    pub fn new(filepath: &Path, password: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        CryptoManager::with_key_file(filepath, password, None)
+    }
+
+    // As new, but unlocking needs the key file as well as the password. A vault created this way
+    // needs the key file from then on.
+    pub fn with_key_file(
+        filepath: &Path,
+        password: &str,
+        key_file: Option<&[u8]>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        CryptoManager::with_storage(FileStorage::new(filepath.to_path_buf()), password, key_file)
+    }
 
-                let mut iv = vec![0u8; IV_LENGTH];
-                file.read_exact(&mut iv)?;
+    // Opens an existing vault without taking the lock, any attempt to write it back fails
+    pub fn open_read_only(
+        filepath: &Path,
+        password: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        CryptoManager::open_read_only_with_key_file(filepath, password, None)
+    }
 
-                let mut ciphertext = Vec::new();
-                file.read_to_end(&mut ciphertext)?;
+    pub fn open_read_only_with_key_file(
+        filepath: &Path,
+        password: &str,
+        key_file: Option<&[u8]>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        CryptoManager::open_storage_read_only(
+            FileStorage::new(filepath.to_path_buf()),
+            password,
+            key_file,
+        )
+    }
 
-                let key = CryptoManager::generate_key(password, &salt)?;
+    // Opens the vault with its recovery code in place of the master password, taking the lock.
+    // The session is meant to set a new master password straight away, see reset_master_password.
+    pub fn with_recovery_code(
+        filepath: &Path,
+        recovery_code: &RecoveryCode,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        CryptoManager::with_storage_and_recovery_code(
+            FileStorage::new(filepath.to_path_buf()),
+            recovery_code,
+        )
+    }
 
-                Ok(CryptoManager {
-                    salt,
-                    iv,
-                    ciphertext,
-                    key,
-                    filepath: filepath.clone(),
-                })
-            }
-            Err(_) => {
-                let salt = CryptoManager::generate_salt(SALT_LENGTH)?;
-                let iv = CryptoManager::generate_iv(IV_LENGTH)?;
-                let key = CryptoManager::generate_key(password, &salt)?;
+    // Same again for escrow shares, enough of them rebuild the secret their key slot is sealed
+    // under
+    pub fn with_escrow_shares(
+        filepath: &Path,
+        shares: &[EscrowShare],
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        CryptoManager::with_storage_and_escrow_shares(
+            FileStorage::new(filepath.to_path_buf()),
+            shares,
+        )
+    }
+}
+
+// The same again for a vault kept anywhere else, see VaultStorage
+impl<S: VaultStorage> CryptoManager<S> {
+    pub fn with_storage(
+        storage: S,
+        password: &str,
+        key_file: Option<&[u8]>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        CryptoManager::create(
+            crypto_backend::default_backend(),
+            storage,
+            password,
+            key_file,
+        )
+    }
+
+    pub fn open_storage_read_only(
+        storage: S,
+        password: &str,
+        key_file: Option<&[u8]>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        CryptoManager::open(
+            crypto_backend::default_backend(),
+            storage,
+            KeySlotKind::Password,
+            password,
+            key_file,
+        )
+    }
+
+    pub fn with_storage_and_recovery_code(
+        storage: S,
+        recovery_code: &RecoveryCode,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        CryptoManager::open_for_reset(storage, KeySlotKind::RecoveryCode, recovery_code.secret())
+    }
+
+    pub fn with_storage_and_escrow_shares(
+        storage: S,
+        shares: &[EscrowShare],
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let secret = escrow::combine(shares)?;
+        CryptoManager::open_for_reset(
+            storage,
+            KeySlotKind::EscrowShares,
+            escrow::slot_secret(&secret).expose(),
+        )
+    }
+
+    fn create(
+        backend: &'static dyn CryptoBackend,
+        mut storage: S,
+        password: &str,
+        key_file: Option<&[u8]>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        storage.lock()?;
+        match CryptoManager::read(backend, &storage, KeySlotKind::Password, password, key_file) {
+            Ok(opened) => Ok(CryptoManager::from_opened(
+                backend, storage, opened, key_file,
+            )),
+            Err(e) if is_not_found(e.as_ref()) => {
+                let key = CryptoManager::generate_data_key(backend)?;
+                let master_slot = CryptoManager::wrap_key(
+                    backend,
+                    &key,
+                    0,
+                    KeySlotKind::Password,
+                    MASTER_PASSWORD_SLOT_LABEL,
+                    password,
+                    key_file,
+                    KdfParams::default(),
+                )?;
 
+                // the nonce is generated on every write, nothing has been sealed with one yet
                 Ok(CryptoManager {
-                    salt,
-                    iv,
+                    header: VaultHeader {
+                        cipher: CipherId::Aes256Gcm,
+                        nonce: Vec::new(),
+                        backup_generations: DEFAULT_BACKUP_GENERATIONS,
+                        password_history_length: None,
+                        key_slots: vec![master_slot],
+                        legacy_key: None,
+                    },
+                    format_version: CURRENT_FORMAT_VERSION,
+                    associated_data: Vec::new(),
                     ciphertext: Vec::new(),
                     key,
-                    filepath: filepath.clone(),
+                    legacy_key: None,
+                    unlocked_slot: 0,
+                    key_file: key_file.map(SecretBytes::from),
+                    storage,
+                    on_disk: None,
+                    backend,
                 })
             }
+            Err(e) => Err(e),
         }
     }
 
+    fn open_for_reset(
+        mut storage: S,
+        kind: KeySlotKind,
+        secret: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        storage.lock()?;
+        CryptoManager::open(
+            crypto_backend::default_backend(),
+            storage,
+            kind,
+            secret,
+            None,
+        )
+    }
+
+    fn open(
+        backend: &'static dyn CryptoBackend,
+        storage: S,
+        kind: KeySlotKind,
+        secret: &str,
+        key_file: Option<&[u8]>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let opened = CryptoManager::read(backend, &storage, kind, secret, key_file)?;
+        Ok(CryptoManager::from_opened(
+            backend, storage, opened, key_file,
+        ))
+    }
+
+    fn read(
+        backend: &dyn CryptoBackend,
+        storage: &S,
+        kind: KeySlotKind,
+        secret: &str,
+        key_file: Option<&[u8]>,
+    ) -> Result<Opened, Box<dyn std::error::Error>> {
+        let contents = storage.load()?;
+        let mut vault_file = VaultFile::parse(&contents)?;
+        let on_disk = DiskState::of(backend, storage, &contents, &vault_file.header)?;
+
+        let (key, legacy_key, unlocked_slot) = match vault_file.header.legacy_key.take() {
+            // vaults from before key slots only ever had a master password
+            Some(_) if kind != KeySlotKind::Password => {
+                return Err(Box::new(CryptoManager::wrong_secret(kind)));
+            }
+            Some(legacy) => {
+                let legacy_key =
+                    CryptoManager::generate_key(backend, secret, None, &legacy.kdf, &legacy.salt)?;
+                if let Some(key_check) = &legacy.key_check {
+                    let expected = CryptoManager::generate_key_check(backend, &legacy_key)?;
+                    if !constant_time_eq(key_check, &expected) {
+                        return Err(Box::new(CryptoError::WrongMasterPassword));
+                    }
+                }
+
+                // keeps the kdf settings the vault already had, the salt and data key are new
+                let key = CryptoManager::generate_data_key(backend)?;
+                let master_slot = CryptoManager::wrap_key(
+                    backend,
+                    &key,
+                    0,
+                    KeySlotKind::Password,
+                    MASTER_PASSWORD_SLOT_LABEL,
+                    secret,
+                    key_file,
+                    legacy.kdf,
+                )?;
+                vault_file.header.key_slots = vec![master_slot];
+                (key, Some(legacy_key), 0)
+            }
+            None => {
+                let (slot, key) = CryptoManager::unlock_key_slots(
+                    backend,
+                    &vault_file.header.key_slots,
+                    kind,
+                    secret,
+                    key_file,
+                )?;
+                (key, None, slot)
+            }
+        };
+
+        Ok(Opened {
+            vault_file,
+            key,
+            legacy_key,
+            unlocked_slot,
+            on_disk,
+        })
+    }
+
+    fn from_opened(
+        backend: &'static dyn CryptoBackend,
+        storage: S,
+        opened: Opened,
+        key_file: Option<&[u8]>,
+    ) -> Self {
+        CryptoManager {
+            header: opened.vault_file.header,
+            format_version: opened.vault_file.version,
+            associated_data: opened.vault_file.associated_data,
+            ciphertext: opened.vault_file.ciphertext,
+            key: opened.key,
+            legacy_key: opened.legacy_key,
+            unlocked_slot: opened.unlocked_slot,
+            key_file: key_file.map(SecretBytes::from),
+            storage,
+            on_disk: Some(opened.on_disk),
+            backend,
+        }
+    }
+
+    // Re-reads the vault from storage, keeping hold of the lock
+    pub fn reload(&mut self, password: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let opened = CryptoManager::read(
+            self.backend,
+            &self.storage,
+            KeySlotKind::Password,
+            password,
+            self.key_file.as_deref(),
+        )?;
+        self.header = opened.vault_file.header;
+        self.format_version = opened.vault_file.version;
+        self.associated_data = opened.vault_file.associated_data;
+        self.ciphertext = opened.vault_file.ciphertext;
+        self.key = opened.key;
+        self.legacy_key = opened.legacy_key;
+        self.unlocked_slot = opened.unlocked_slot;
+        self.on_disk = Some(opened.on_disk);
+        Ok(())
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        !self.storage.is_locked()
+    }
+
+    // pid of an instance that crashed while holding the lock, its lock was cleared on the way in
+    pub fn stale_lock_pid(&self) -> Option<u32> {
+        self.storage.stale_lock_pid()
+    }
+
+    pub fn is_persisted(&self) -> bool {
+        !self.ciphertext.is_empty()
+    }
+
     pub fn encrypt_and_persist(&mut self, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-        let encrypted_data = self.encrypt_data(data)?;
+        self.encrypt_records(data)?;
+        self.write_vault(true)
+    }
+
+    // Like encrypt_and_persist, but the vault being replaced is not kept as a backup. For changes
+    // nobody would want to roll back, such as when an entry was last used, which would otherwise
+    // push the useful backups out.
+    pub fn encrypt_and_persist_in_place(
+        &mut self,
+        data: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.encrypt_records(data)?;
+        self.write_vault(false)
+    }
+
+    fn encrypt_records(&mut self, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        if self.is_read_only() {
+            return Err(Box::new(CryptoError::ReadOnly));
+        }
+        // from the first write on, the entry rows must always have a manifest to match
+        if !self.is_persisted() {
+            self.persist_entries_manifest()?;
+        }
+        // a nonce must never be reused with the same key, and reusing it would also let anyone
+        // holding old copies of the vault compare them to see which records changed
+        self.header.nonce = CryptoManager::generate_nonce(self.backend, NONCE_LENGTH)?;
+        // vaults read from an older format are always written back in the current one
+        self.header.cipher = CipherId::Aes256Gcm;
+        let associated_data = VaultFile::records_associated_data(&self.header)?;
+        let encrypted_data = self.encrypt_data(data, &associated_data)?;
+        self.format_version = CURRENT_FORMAT_VERSION;
+        self.associated_data = associated_data;
         self.ciphertext = encrypted_data;
-        let mut file = File::create(&self.filepath)?;
-        file.write_all(&self.salt)?;
-        file.write_all(&self.iv)?;
-        file.write_all(&self.ciphertext)?;
-        #[cfg(unix)]
-        {
-            let permissions = Permissions::from_mode(0o600);
-            std::fs::set_permissions(&self.filepath, permissions)?;
+        self.legacy_key = None;
+        Ok(())
+    }
+
+    // Writes the header on its own, leaving the records as they are. Only possible once the
+    // records have been written under the data key.
+    pub fn persist_header(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.is_read_only() {
+            return Err(Box::new(CryptoError::ReadOnly));
+        }
+        self.write_vault(true)
+    }
+
+    // Whether the records have to be encrypted again before the header can be written on its own
+    pub fn needs_records_written(&self) -> bool {
+        !self.is_persisted()
+            || self.legacy_key.is_some()
+            || self.format_version != CURRENT_FORMAT_VERSION
+    }
+
+    // The header is written as the records were sealed with it, only the key slots are serialised
+    // again
+    fn write_vault(&mut self, keep_backup: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let contents = VaultFile::serialize(
+            &self.associated_data,
+            &self.header.key_slots,
+            &self.ciphertext,
+        )?;
+
+        if keep_backup {
+            self.storage
+                .store(&contents, self.header.backup_generations)?;
+        } else {
+            self.storage.store_in_place(&contents)?;
+        }
+        self.on_disk = Some(DiskState::of(
+            self.backend,
+            &self.storage,
+            &contents,
+            &self.header,
+        )?);
+        Ok(())
+    }
+
+    // Returns the decrypted contents of the vault if something other than us, a sync tool for
+    // example, has replaced it since we last read or wrote it. An unchanged mtime is trusted
+    // without reading the file, but a new mtime on its own is not a change, the contents have to
+    // differ too.
+    pub fn read_external_changes(
+        &mut self,
+    ) -> Result<Option<SecretBytes>, Box<dyn std::error::Error>> {
+        let Some(on_disk) = &self.on_disk else {
+            return Ok(None);
+        };
+        let modified = match self.storage.metadata() {
+            Ok(metadata) => metadata.modified,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Box::new(e)),
+        };
+        if modified == on_disk.modified {
+            return Ok(None);
+        }
+        let contents = self.storage.load()?;
+        let digest = self.backend.sha256(&contents);
+        if digest == on_disk.digest {
+            self.on_disk = Some(DiskState {
+                modified,
+                digest,
+                header: on_disk.header.clone(),
+            });
+            return Ok(None);
+        }
+
+        // the other copy can only be merged if it was sealed with the data key we hold
+        let vault_file = VaultFile::parse(&contents)?;
+        if vault_file.header.legacy_key.is_some() {
+            return Err(Box::new(CryptoError::ExternallyRekeyed));
+        }
+        let merged_header = self.merge_header(&on_disk.header, &vault_file.header)?;
+        let current = DiskState {
+            modified,
+            digest,
+            header: vault_file.header.clone(),
+        };
+        let external = CryptoManager {
+            header: vault_file.header,
+            format_version: vault_file.version,
+            associated_data: vault_file.associated_data,
+            ciphertext: vault_file.ciphertext,
+            key: self.key.clone(),
+            legacy_key: None,
+            unlocked_slot: self.unlocked_slot,
+            key_file: None,
+            storage: MemoryStorage::new(),
+            on_disk: None,
+            backend: self.backend,
+        };
+        let data =
+            external
+                .decrypt_and_retrieve()
+                .map_err(|e| match e.downcast_ref::<CryptoError>() {
+                    Some(CryptoError::IntegrityCheckFailed) => {
+                        Box::new(CryptoError::ExternallyRekeyed)
+                    }
+                    _ => e,
+                })?;
+        // from here on what is on disk counts as read, our next write includes it
+        self.header = merged_header;
+        self.on_disk = Some(current);
+        Ok(Some(data))
+    }
+
+    // Our header with the changes made to theirs since `base` folded in. A key slot or setting
+    // changed on one side only takes that side's version, so a slot revoked or re-keyed in the
+    // other copy stays that way. Slots changed on both sides can not be merged.
+    fn merge_header(
+        &self,
+        base: &VaultHeader,
+        theirs: &VaultHeader,
+    ) -> Result<VaultHeader, CryptoError> {
+        fn pick<T: PartialEq + Clone>(base: &T, ours: &T, theirs: &T) -> T {
+            if ours == base {
+                theirs.clone()
+            } else {
+                ours.clone()
+            }
+        }
+
+        let slot = |slots: &[KeySlot], id: u32| slots.iter().find(|slot| slot.id == id).cloned();
+        let mut ids: Vec<u32> = [&base.key_slots, &self.header.key_slots, &theirs.key_slots]
+            .into_iter()
+            .flatten()
+            .map(|slot| slot.id)
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        let mut key_slots = Vec::new();
+        for id in ids {
+            let base_slot = slot(&base.key_slots, id);
+            let our_slot = slot(&self.header.key_slots, id);
+            let their_slot = slot(&theirs.key_slots, id);
+            if our_slot != base_slot && their_slot != base_slot && our_slot != their_slot {
+                return Err(CryptoError::KeySlotsChangedOnBothSides);
+            }
+            key_slots.extend(pick(&base_slot, &our_slot, &their_slot));
+        }
+        if !key_slots.iter().any(|slot| slot.id == self.unlocked_slot) {
+            return Err(CryptoError::UnlockedSlotRevoked);
+        }
+
+        Ok(VaultHeader {
+            backup_generations: pick(
+                &base.backup_generations,
+                &self.header.backup_generations,
+                &theirs.backup_generations,
+            ),
+            password_history_length: pick(
+                &base.password_history_length,
+                &self.header.password_history_length,
+                &theirs.password_history_length,
+            ),
+            key_slots,
+            ..self.header.clone()
+        })
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn backup_generations(&self) -> u32 {
+        self.header.backup_generations
+    }
+
+    // takes effect on the next write, which also prunes any generations beyond the new count
+    pub fn set_backup_generations(&mut self, generations: u32) {
+        self.header.backup_generations = generations;
+    }
+
+    pub fn password_history_length(&self) -> u32 {
+        self.header
+            .password_history_length
+            .unwrap_or(DEFAULT_PASSWORD_HISTORY_LENGTH)
+    }
+
+    // takes effect on the next write
+    pub fn set_password_history_length(&mut self, length: u32) {
+        self.header.password_history_length = Some(length);
+    }
+
+    // backups are kept by the storage, still encrypted, generation 1 is the newest
+    pub fn backups(&self) -> io::Result<Vec<BackupInfo>> {
+        let mut backups = Vec::new();
+        for generation in 1..=self.header.backup_generations {
+            match self.storage.backup_metadata(generation) {
+                Ok(metadata) => backups.push(BackupInfo {
+                    generation,
+                    modified: metadata.modified,
+                    size: metadata.size,
+                }),
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(backups)
+    }
+
+    pub fn has_backup(&self, generation: u32) -> bool {
+        self.storage.backup_metadata(generation).is_ok()
+    }
+
+    // Opens a backup read-only with the secrets this vault was unlocked with
+    pub fn open_backup(
+        &self,
+        generation: u32,
+        password: &str,
+    ) -> Result<CryptoManager<MemoryStorage>, Box<dyn std::error::Error>> {
+        let contents = self.storage.load_backup(generation)?;
+        CryptoManager::open(
+            self.backend,
+            MemoryStorage::with_contents(contents),
+            KeySlotKind::Password,
+            password,
+            self.key_file.as_deref(),
+        )
+    }
+
+    // Puts a backup back in place of the vault. The vault being replaced is rotated into the
+    // backups first, so a restore can itself be undone.
+    pub fn restore_backup(&mut self, generation: u32) -> Result<(), Box<dyn std::error::Error>> {
+        if self.is_read_only() {
+            return Err(Box::new(CryptoError::ReadOnly));
+        }
+        let contents = self.storage.load_backup(generation)?;
+        self.storage
+            .store(&contents, self.header.backup_generations)?;
+        Ok(())
+    }
+
+    // Gives every backup sealed with our data key the key slots the vault has now, so a secret
+    // revoked or replaced here stops opening the backups too. Returns the generations that had to
+    // be left as they were, backups from before key slots still open with the master password
+    // they were written with.
+    pub fn reseal_backups(&mut self) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
+        if self.is_read_only() {
+            return Err(Box::new(CryptoError::ReadOnly));
+        }
+        let mut left_alone = Vec::new();
+        for backup in self.backups()? {
+            let contents = self.storage.load_backup(backup.generation)?;
+            let resealed = match VaultFile::parse(&contents) {
+                Ok(vault_file) if self.holds_key_of(&vault_file) => {
+                    vault_file.serialize_with_key_slots(&self.header.key_slots)?
+                }
+                _ => None,
+            };
+            match resealed {
+                Some(resealed) if resealed != contents => {
+                    self.storage.replace_backup(backup.generation, &resealed)?
+                }
+                Some(_) => {}
+                None => left_alone.push(backup.generation),
+            }
+        }
+        Ok(left_alone)
+    }
+
+    // whether the records of another copy of the vault were sealed with our data key
+    fn holds_key_of(&self, vault_file: &VaultFile) -> bool {
+        vault_file.header.legacy_key.is_none()
+            && vault_file.ciphertext.len() >= TAG_LENGTH
+            && self
+                .backend
+                .aes_256_gcm_open(
+                    &self.key,
+                    &vault_file.header.nonce,
+                    &vault_file.associated_data,
+                    &vault_file.ciphertext,
+                )
+                .is_some()
+    }
+
+    pub fn kdf(&self) -> &KdfParams {
+        &self.unlocked_key_slot().kdf
+    }
+
+    pub fn key_slots(&self) -> &[KeySlot] {
+        &self.header.key_slots
+    }
+
+    // the slot the vault was unlocked with, the master password
+    pub fn unlocked_key_slot(&self) -> &KeySlot {
+        self.header
+            .key_slots
+            .iter()
+            .find(|slot| slot.id == self.unlocked_slot)
+            .expect("the slot the vault was unlocked with is never removed")
+    }
+
+    pub fn verify_password(&self, password: &str) -> Result<(), CryptoError> {
+        let key = CryptoManager::unwrap_key(
+            self.backend,
+            self.unlocked_key_slot(),
+            password,
+            self.key_file.as_deref(),
+        )?;
+        if constant_time_eq(&key, &self.key) {
+            Ok(())
+        } else {
+            Err(CryptoError::WrongMasterPassword)
+        }
+    }
+
+    // Seals another copy of the data key under a new secret, returning the new slot's id. If the
+    // vault was unlocked with a key file the new slot needs it too, unless it is for a recovery
+    // code or escrow shares, which have to work on their own. Takes effect on the next write.
+    pub fn add_key_slot(
+        &mut self,
+        kind: KeySlotKind,
+        label: &str,
+        secret: &str,
+        kdf: KdfParams,
+    ) -> Result<u32, Box<dyn std::error::Error>> {
+        let id = self
+            .header
+            .key_slots
+            .iter()
+            .map(|slot| slot.id + 1)
+            .max()
+            .unwrap_or(0);
+        let slot = CryptoManager::wrap_key(
+            self.backend,
+            &self.key,
+            id,
+            kind,
+            label,
+            secret,
+            match kind {
+                KeySlotKind::Password => self.key_file.as_deref(),
+                KeySlotKind::RecoveryCode | KeySlotKind::EscrowShares => None,
+            },
+            kdf,
+        )?;
+        self.header.key_slots.push(slot);
+        Ok(id)
+    }
+
+    // Drops a slot, returning false if there is none with that id. The slot the vault was
+    // unlocked with can not be removed. Takes effect on the next write.
+    pub fn remove_key_slot(&mut self, id: u32) -> bool {
+        let count = self.header.key_slots.len();
+        self.header
+            .key_slots
+            .retain(|slot| slot.id != id || slot.id == self.unlocked_slot);
+        self.header.key_slots.len() != count
+    }
+
+    // Seals the data key under the password with a fresh salt and the given kdf settings, in
+    // place of the slot the vault was unlocked with, and writes the data back out. Nothing
+    // changes, in memory or on disk, if any step fails.
+    pub fn rekey_and_persist(
+        &mut self,
+        password: &str,
+        kdf: KdfParams,
+        data: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let current = self.unlocked_key_slot();
+        let slot = CryptoManager::wrap_key(
+            self.backend,
+            &self.key,
+            current.id,
+            current.kind,
+            &current.label.clone(),
+            password,
+            self.key_file.as_deref(),
+            kdf,
+        )?;
+
+        let previous_header = self.header.clone();
+        let previous_associated_data = self.associated_data.clone();
+        let previous_ciphertext = self.ciphertext.clone();
+        let previous_legacy_key = self.legacy_key.clone();
+        for existing in self.header.key_slots.iter_mut() {
+            if existing.id == slot.id {
+                *existing = slot.clone();
+            }
+        }
+
+        if let Err(e) = self.encrypt_and_persist(data) {
+            self.header = previous_header;
+            self.associated_data = previous_associated_data;
+            self.ciphertext = previous_ciphertext;
+            self.legacy_key = previous_legacy_key;
+            return Err(e);
         }
         Ok(())
     }
 
-    fn encrypt_data(&self, data: &[u8]) -> Result<Vec<u8>, ErrorStack> {
-        let cipher = Cipher::aes_256_cbc();
-        let mut crypter = Crypter::new(cipher, Mode::Encrypt, &self.key, Some(&self.iv))?;
-        crypter.pad(true);
-        let mut encrypted = vec![0; data.len() + cipher.block_size()];
-        let count = crypter.update(data, &mut encrypted)?;
-        let rest = crypter.finalize(&mut encrypted[count..])?;
-        encrypted.truncate(count + rest);
-        Ok(encrypted)
+    // Used after unlocking with the recovery code or escrow shares, seals the data key under a new master password
+    // in place of the old one and writes the data back out. The session carries on as if it had
+    // been unlocked with the new password. There is no key file in a recovery session, so the new
+    // master password does not need one.
+    pub fn reset_master_password(
+        &mut self,
+        password: &str,
+        data: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let recovery_slot = self.unlocked_slot;
+        let master_slot = self
+            .header
+            .key_slots
+            .iter()
+            .filter(|slot| slot.kind == KeySlotKind::Password)
+            .min_by_key(|slot| slot.label != MASTER_PASSWORD_SLOT_LABEL)
+            .map(|slot| (slot.id, slot.kdf.clone()));
+
+        let result = match master_slot {
+            Some((id, kdf)) => {
+                self.unlocked_slot = id;
+                self.rekey_and_persist(password, kdf, data)
+            }
+            None => {
+                let id = self.add_key_slot(
+                    KeySlotKind::Password,
+                    MASTER_PASSWORD_SLOT_LABEL,
+                    password,
+                    KdfParams::default(),
+                )?;
+                self.unlocked_slot = id;
+                let result = self.encrypt_and_persist(data);
+                if result.is_err() {
+                    self.header.key_slots.retain(|slot| slot.id != id);
+                }
+                result
+            }
+        };
+        if result.is_err() {
+            self.unlocked_slot = recovery_slot;
+        }
+        result
     }
 
-    pub fn decrypt_and_retrieve(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    fn encrypt_data(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.backend
+            .aes_256_gcm_seal(&self.key, &self.header.nonce, aad, data)
+    }
+
+    pub fn decrypt_and_retrieve(&self) -> Result<SecretBytes, Box<dyn std::error::Error>> {
         if self.ciphertext.is_empty() {
-            return Ok(Vec::new());
+            return Ok(SecretBytes::zeroed(0));
         }
-        let decrypted_data = self.decrypt_data(&self.ciphertext)?;
+        let decrypted_data = match self.header.cipher {
+            CipherId::Aes256Gcm => self.decrypt_data(&self.ciphertext, &self.associated_data)?,
+            CipherId::Aes256Cbc => self.decrypt_legacy_data(&self.ciphertext)?,
+        };
         Ok(decrypted_data)
     }
 
-    fn decrypt_data(&self, encrypted_data: &[u8]) -> Result<Vec<u8>, ErrorStack> {
-        let cipher = Cipher::aes_256_cbc();
-        let mut crypter = Crypter::new(cipher, Mode::Decrypt, &self.key, Some(&self.iv))?;
-        crypter.pad(true);
-        let mut decrypted = vec![0; encrypted_data.len() + cipher.block_size()];
-        let count = crypter.update(encrypted_data, &mut decrypted)?;
-        let rest = crypter.finalize(&mut decrypted[count..])?;
-        decrypted.truncate(count + rest);
+    fn decrypt_data(&self, encrypted_data: &[u8], aad: &[u8]) -> Result<SecretBytes, CryptoError> {
+        if encrypted_data.len() < TAG_LENGTH {
+            return Err(CryptoError::IntegrityCheckFailed);
+        }
+        self.backend
+            .aes_256_gcm_open(self.records_key(), &self.header.nonce, aad, encrypted_data)
+            .ok_or(CryptoError::IntegrityCheckFailed)
+    }
+
+    fn decrypt_legacy_data(&self, encrypted_data: &[u8]) -> Result<SecretBytes, CryptoError> {
+        self.backend
+            .aes_256_cbc_decrypt(self.records_key(), &self.header.nonce, encrypted_data)
+            .ok_or(CryptoError::LegacyDecryptFailed)
+    }
+
+    fn records_key(&self) -> &[u8] {
+        self.legacy_key.as_deref().unwrap_or(&self.key)
+    }
+
+    // A copy of this vault in other storage, which must not hold a vault yet, with the same key
+    // slots and data key so the same secrets unlock it. Nothing is written until the records are.
+    pub fn copy_into<T: VaultStorage>(
+        &self,
+        mut storage: T,
+    ) -> Result<CryptoManager<T>, Box<dyn std::error::Error>> {
+        storage.lock()?;
+        let has_entries = match storage.entries() {
+            Some(entries) => !entries.load_entries()?.is_empty(),
+            None => false,
+        };
+        match storage.load() {
+            Err(e) if e.kind() == ErrorKind::NotFound && !has_entries => {}
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(Box::new(e)),
+            _ => {
+                return Err(Box::new(io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    "there is a vault there already",
+                )))
+            }
+        }
+        Ok(CryptoManager {
+            header: self.header.clone(),
+            format_version: CURRENT_FORMAT_VERSION,
+            associated_data: Vec::new(),
+            ciphertext: Vec::new(),
+            key: self.key.clone(),
+            legacy_key: None,
+            unlocked_slot: self.unlocked_slot,
+            key_file: self.key_file.clone(),
+            storage,
+            on_disk: None,
+            backend: self.backend,
+        })
+    }
+}
+
+// Vaults whose storage keeps each entry in a row of its own, see EntryStorage. Each entry is
+// sealed with the data key, bound to its lookup so rows can not be swapped around, and the rows
+// are bound together by a manifest so none can be rolled back, dropped or added on its own.
+impl<S: VaultStorage> CryptoManager<S> {
+    pub fn stores_entries_separately(&self) -> bool {
+        self.storage.entries().is_some()
+    }
+
+    // every entry kept in a row of its own, in the order they were created
+    pub fn decrypt_entries(&self) -> Result<Vec<SecretBytes>, Box<dyn std::error::Error>> {
+        let Some(storage) = self.storage.entries() else {
+            return Ok(Vec::new());
+        };
+        let entries = storage.load_entries()?;
+        self.verify_entries_manifest(storage, &entries)?;
+        self.decrypt_stored_entries(&entries)
+    }
+
+    // Seals each entry, given as its id and data, and writes them in place of their old rows,
+    // deleting the rows of the entries with the ids in `deleted`, in one step. Nothing else in the
+    // vault is written.
+    pub fn encrypt_and_persist_entries(
+        &mut self,
+        entries: &[(Uuid, &[u8])],
+        deleted: &[Uuid],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.is_read_only() {
+            return Err(Box::new(CryptoError::ReadOnly));
+        }
+        let sealed = entries
+            .iter()
+            .map(|(id, data)| self.seal_entry(*id, data))
+            .collect::<Result<Vec<_>, _>>()?;
+        let deleted = deleted
+            .iter()
+            .map(|id| self.entry_lookup(*id))
+            .collect::<Result<Vec<_>, CryptoError>>()?;
+
+        // the manifest only ever moves on from rows that match it
+        let storage = self.storage.entries().ok_or_else(entries_unsupported)?;
+        let mut rows = storage.load_entries()?;
+        self.verify_entries_manifest(storage, &rows)?;
+        rows.retain(|row| {
+            !deleted.contains(&row.lookup) && !sealed.iter().any(|entry| entry.lookup == row.lookup)
+        });
+        rows.extend(sealed.iter().cloned());
+        let manifest = self.entries_manifest(&rows)?;
+
+        match self.storage.entries_mut() {
+            Some(storage) => Ok(storage.write_entries(&sealed, &deleted, &manifest)?),
+            None => Err(Box::new(entries_unsupported())),
+        }
+    }
+
+    // An HMAC over every row, sorted by lookup, so the rows only check out as the whole set they
+    // were last written as. Keyed from the data key, like the lookups.
+    fn entries_manifest(&self, entries: &[StoredEntry]) -> Result<Vec<u8>, CryptoError> {
+        let manifest_key = SecretBytes::new(
+            self.backend
+                .hmac_sha256(&self.key, &[ENTRY_MANIFEST_LABEL])?,
+        );
+        let mut rows: Vec<(&[u8], [u8; 32])> = entries
+            .iter()
+            .map(|entry| {
+                let row = [&entry.nonce[..], &entry.ciphertext].concat();
+                (&entry.lookup[..], self.backend.sha256(&row))
+            })
+            .collect();
+        rows.sort();
+
+        let count = (rows.len() as u64).to_be_bytes();
+        let mut parts: Vec<&[u8]> = vec![b"manifest", &count];
+        for (lookup, digest) in &rows {
+            parts.push(lookup);
+            parts.push(digest);
+        }
+        self.backend.hmac_sha256(&manifest_key, &parts)
+    }
+
+    // a vault not yet written has no manifest, any other must have one matching its rows
+    fn verify_entries_manifest(
+        &self,
+        storage: &dyn EntryStorage,
+        entries: &[StoredEntry],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match storage.load_manifest()? {
+            Some(manifest) if constant_time_eq(&manifest, &self.entries_manifest(entries)?) => {
+                Ok(())
+            }
+            None if !self.is_persisted() => Ok(()),
+            _ => Err(Box::new(CryptoError::IntegrityCheckFailed)),
+        }
+    }
+
+    fn persist_entries_manifest(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(storage) = self.storage.entries() else {
+            return Ok(());
+        };
+        if storage.load_manifest()?.is_some() {
+            return Ok(());
+        }
+        let manifest = self.entries_manifest(&storage.load_entries()?)?;
+        match self.storage.entries_mut() {
+            Some(storage) => Ok(storage.write_entries(&[], &[], &manifest)?),
+            None => Ok(()),
+        }
+    }
+
+    fn seal_entry(&self, id: Uuid, data: &[u8]) -> Result<StoredEntry, CryptoError> {
+        let lookup = self.entry_lookup(id)?;
+        let nonce = CryptoManager::generate_nonce(self.backend, NONCE_LENGTH)?;
+        let ciphertext = self
+            .backend
+            .aes_256_gcm_seal(&self.key, &nonce, &lookup, data)?;
+        Ok(StoredEntry {
+            lookup,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    fn decrypt_stored_entries(
+        &self,
+        entries: &[StoredEntry],
+    ) -> Result<Vec<SecretBytes>, Box<dyn std::error::Error>> {
+        let mut decrypted = Vec::new();
+        for entry in entries {
+            let data = self
+                .backend
+                .aes_256_gcm_open(&self.key, &entry.nonce, &entry.lookup, &entry.ciphertext)
+                .ok_or(CryptoError::IntegrityCheckFailed)?;
+            decrypted.push(data);
+        }
         Ok(decrypted)
     }
 
-    fn generate_salt(length: usize) -> Result<Vec<u8>, ErrorStack> {
+    // A keyed HMAC of the entry's id, which never changes, so a renamed entry keeps its row and
+    // entries sharing an identifier and username, such as notes, have rows of their own. Its key
+    // is derived from the data key, so it gives nothing away without it and stays the same when
+    // the master password changes.
+    fn entry_lookup(&self, id: Uuid) -> Result<Vec<u8>, CryptoError> {
+        let lookup_key =
+            SecretBytes::new(self.backend.hmac_sha256(&self.key, &[ENTRY_LOOKUP_LABEL])?);
+        self.backend
+            .hmac_sha256(&lookup_key, &[b"entry", id.as_bytes()])
+    }
+}
+
+// Key handling, none of it depends on where the vault is stored
+impl CryptoManager {
+    // Writes a new random key file, refusing to overwrite one that is already there
+    pub fn create_key_file(path: &Path) -> io::Result<SecretBytes> {
+        let mut key_file = SecretBytes::zeroed(KEY_FILE_LENGTH);
+        crypto_backend::default_backend()
+            .random_bytes(&mut key_file)
+            .map_err(io::Error::other)?;
+        let mut file = create_private_file(path)?;
+        file.write_all(&key_file)?;
+        file.sync_all()?;
+        Ok(key_file)
+    }
+
+    fn generate_data_key(backend: &dyn CryptoBackend) -> Result<SecretBytes, CryptoError> {
+        let mut key = SecretBytes::zeroed(KEY_LENGTH);
+        backend.random_bytes(&mut key)?;
+        Ok(key)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn wrap_key(
+        backend: &dyn CryptoBackend,
+        data_key: &[u8],
+        id: u32,
+        kind: KeySlotKind,
+        label: &str,
+        secret: &str,
+        key_file: Option<&[u8]>,
+        kdf: KdfParams,
+    ) -> Result<KeySlot, Box<dyn std::error::Error>> {
+        let salt = CryptoManager::generate_salt(backend, SALT_LENGTH)?;
+        let wrapping_key = CryptoManager::generate_key(backend, secret, key_file, &kdf, &salt)?;
+        let key_file_check = match key_file {
+            Some(key_file) => Some(CryptoManager::generate_key_file_check(
+                backend, key_file, &salt,
+            )?),
+            None => None,
+        };
+        let mut slot = KeySlot {
+            id,
+            kind,
+            label: label.to_string(),
+            kdf,
+            salt,
+            nonce: CryptoManager::generate_nonce(backend, NONCE_LENGTH)?,
+            wrapped_key: Vec::new(),
+            key_file_check,
+        };
+
+        slot.wrapped_key = backend.aes_256_gcm_seal(
+            &wrapping_key,
+            &slot.nonce,
+            &CryptoManager::key_slot_associated_data(&slot)?,
+            data_key,
+        )?;
+        Ok(slot)
+    }
+
+    // A wrong secret and a tampered slot look the same here, either way the slot does not open.
+    // A slot that needs a key file checks for it first, so a missing or wrong one is reported as
+    // such and costs no key derivation.
+    fn unwrap_key(
+        backend: &dyn CryptoBackend,
+        slot: &KeySlot,
+        secret: &str,
+        key_file: Option<&[u8]>,
+    ) -> Result<SecretBytes, CryptoError> {
+        if slot.wrapped_key.len() < TAG_LENGTH {
+            return Err(CryptoError::WrongMasterPassword);
+        }
+        let key_file = match (&slot.key_file_check, key_file) {
+            (None, _) => None,
+            (Some(_), None) => return Err(CryptoError::KeyFileMissingOrWrong),
+            (Some(key_file_check), Some(key_file)) => {
+                let expected =
+                    CryptoManager::generate_key_file_check(backend, key_file, &slot.salt)?;
+                if !constant_time_eq(key_file_check, &expected) {
+                    return Err(CryptoError::KeyFileMissingOrWrong);
+                }
+                Some(key_file)
+            }
+        };
+        let wrapping_key =
+            CryptoManager::generate_key(backend, secret, key_file, &slot.kdf, &slot.salt)?;
+        backend
+            .aes_256_gcm_open(
+                &wrapping_key,
+                &slot.nonce,
+                &CryptoManager::key_slot_associated_data(slot)?,
+                &slot.wrapped_key,
+            )
+            .ok_or(CryptoError::WrongMasterPassword)
+    }
+
+    // Tries the secret against every slot of the given kind, returning the first one it opens. If
+    // none do and any wanted a key file it was not given, that is the more useful thing to report.
+    fn unlock_key_slots(
+        backend: &dyn CryptoBackend,
+        slots: &[KeySlot],
+        kind: KeySlotKind,
+        secret: &str,
+        key_file: Option<&[u8]>,
+    ) -> Result<(u32, SecretBytes), CryptoError> {
+        let mut error = CryptoManager::wrong_secret(kind);
+        for slot in slots.iter().filter(|slot| slot.kind == kind) {
+            match CryptoManager::unwrap_key(backend, slot, secret, key_file) {
+                Ok(key) => return Ok((slot.id, key)),
+                Err(CryptoError::WrongMasterPassword) => continue,
+                Err(CryptoError::KeyFileMissingOrWrong) => {
+                    error = CryptoError::KeyFileMissingOrWrong
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(error)
+    }
+
+    fn wrong_secret(kind: KeySlotKind) -> CryptoError {
+        match kind {
+            KeySlotKind::Password => CryptoError::WrongMasterPassword,
+            KeySlotKind::RecoveryCode => CryptoError::WrongRecoveryCode,
+            KeySlotKind::EscrowShares => CryptoError::WrongEscrowShares,
+        }
+    }
+
+    // binds everything about a slot but its wrapped key, so none of it can be swapped around
+    fn key_slot_associated_data(slot: &KeySlot) -> Result<Vec<u8>, CryptoError> {
+        serde_json::to_vec(&(slot.id, &slot.kind, &slot.label, &slot.kdf, &slot.salt))
+            .map_err(|e| CryptoError::MalformedHeader(e.to_string()))
+    }
+
+    fn generate_salt(backend: &dyn CryptoBackend, length: usize) -> Result<Vec<u8>, CryptoError> {
         let mut buffer = vec![0u8; length];
-        rand_bytes(&mut buffer)?;
+        backend.random_bytes(&mut buffer)?;
         Ok(buffer)
     }
 
-    fn generate_iv(length: usize) -> Result<Vec<u8>, ErrorStack> {
+    fn generate_nonce(backend: &dyn CryptoBackend, length: usize) -> Result<Vec<u8>, CryptoError> {
         let mut buffer = vec![0u8; length];
-        rand_bytes(&mut buffer)?;
+        backend.random_bytes(&mut buffer)?;
         Ok(buffer)
     }
 
-    fn generate_key(password: &str, salt: &[u8]) -> Result<Vec<u8>, ErrorStack> {
-        let password_bytes = password.as_bytes();
-        let mut key = vec![0u8; KEY_LENGTH];
-        pbkdf2_hmac(
-            password_bytes,
-            salt,
-            ITERATIONS,
-            MessageDigest::sha256(),
-            &mut key,
-        )?;
+    // The key file, when there is one, is mixed in after the password so both are needed
+    fn generate_key(
+        backend: &dyn CryptoBackend,
+        password: &str,
+        key_file: Option<&[u8]>,
+        kdf: &KdfParams,
+        salt: &[u8],
+    ) -> Result<SecretBytes, CryptoError> {
+        // sized up front so the buffer is never reallocated with a copy of the password left behind
+        let mut password_bytes = Vec::with_capacity(password.len() + KEY_LENGTH);
+        password_bytes.extend_from_slice(password.as_bytes());
+        if let Some(key_file) = key_file {
+            password_bytes.extend_from_slice(&backend.sha256(key_file));
+        }
+        let password_bytes = SecretBytes::new(password_bytes);
+        let password_bytes = &*password_bytes;
+        let mut key = SecretBytes::zeroed(KEY_LENGTH);
+        match kdf {
+            KdfParams::Pbkdf2HmacSha256 { iterations } => {
+                backend.pbkdf2_hmac_sha256(password_bytes, salt, *iterations, &mut key)?
+            }
+            KdfParams::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => {
+                let params = Params::new(*memory_kib, *iterations, *parallelism, Some(KEY_LENGTH))
+                    .map_err(|e| CryptoError::KeyDerivationFailed(e.to_string()))?;
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(password_bytes, salt, &mut key)
+                    .map_err(|e| CryptoError::KeyDerivationFailed(e.to_string()))?
+            }
+        }
         Ok(key)
     }
+
+    // HMAC of the slot's salt under the key file, lets a missing or wrong key file be told apart
+    // from a wrong password. The key file is random so this gives nothing away about it.
+    fn generate_key_file_check(
+        backend: &dyn CryptoBackend,
+        key_file: &[u8],
+        salt: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        backend.hmac_sha256(key_file, &[KEY_FILE_CHECK_LABEL, salt])
+    }
+
+    // HMAC of a fixed label under the derived key, version 2 vaults stored it in the header so a
+    // wrong master password was caught before decrypting and not mistaken for a tampered vault
+    fn generate_key_check(backend: &dyn CryptoBackend, key: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        backend.hmac_sha256(key, &[KEY_CHECK_LABEL])
+    }
+
+    // Benchmarks argon2id on this machine and picks settings that take roughly `target` to derive
+    // a key. Memory is doubled first since it is what hurts GPUs the most, then iterations fill
+    // whatever time is left. Never goes below the recommended defaults, however slow the machine.
+    pub fn calibrate_kdf(target: Duration) -> Result<(KdfParams, Duration), CryptoError> {
+        let backend = crypto_backend::default_backend();
+        let salt = CryptoManager::generate_salt(backend, SALT_LENGTH)?;
+        CryptoManager::calibrate_kdf_with(target, |kdf| {
+            let start = Instant::now();
+            CryptoManager::generate_key(backend, "calibration", None, kdf, &salt)?;
+            Ok(start.elapsed())
+        })
+    }
+
+    // The search calibrate_kdf does, with whatever it takes to derive a key with the given settings
+    // measured by `cost`
+    fn calibrate_kdf_with(
+        target: Duration,
+        mut cost: impl FnMut(&KdfParams) -> Result<Duration, CryptoError>,
+    ) -> Result<(KdfParams, Duration), CryptoError> {
+        let mut time_kdf = |memory_kib: u32, iterations: u32| {
+            let kdf = KdfParams::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism: ARGON2ID_DEFAULT_PARALLELISM,
+            };
+            let elapsed = cost(&kdf)?;
+            Ok::<_, CryptoError>((kdf, elapsed))
+        };
+
+        let mut memory_kib = ARGON2ID_DEFAULT_MEMORY_KIB;
+        let (_, mut single_pass) = time_kdf(memory_kib, 1)?;
+        while single_pass * 2 <= target / ARGON2ID_DEFAULT_ITERATIONS
+            && memory_kib * 2 <= CALIBRATION_MAX_MEMORY_KIB
+        {
+            memory_kib *= 2;
+            (_, single_pass) = time_kdf(memory_kib, 1)?;
+        }
+
+        let iterations = (target.as_secs_f64() / single_pass.as_secs_f64().max(f64::EPSILON))
+            .floor()
+            .clamp(ARGON2ID_DEFAULT_ITERATIONS as f64, u32::MAX as f64)
+            as u32;
+
+        time_kdf(memory_kib, iterations)
+    }
+}
+
+fn entries_unsupported() -> io::Error {
+    io::Error::new(
+        ErrorKind::Unsupported,
+        "the storage keeps the entries inside the vault",
+    )
+}
+
+fn is_not_found(error: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(error.downcast_ref::<io::Error>(), Some(e) if e.kind() == ErrorKind::NotFound)
 }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use std::fs::File;
+    use std::io::Read;
+    use std::path::PathBuf;
     use uuid::Uuid;
 
     const TEST_PASSWORD: &str = "test_password";
 
     fn teardown(filepath: &PathBuf) {
         let _ = std::fs::remove_file(filepath);
+        for generation in 1..=DEFAULT_BACKUP_GENERATIONS {
+            let _ = std::fs::remove_file(format!("{}.{}", filepath.display(), generation));
+        }
+        let _ = std::fs::remove_file(format!("{}.lock", filepath.display()));
     }
 
     fn generate_unique_file_path() -> PathBuf {
@@ -149,46 +1458,295 @@ mod tests {
         PathBuf::from(format!("/tmp/test_{}.enc", unique_id))
     }
 
+    fn backend() -> &'static dyn CryptoBackend {
+        crypto_backend::default_backend()
+    }
+
+    fn read_file(filepath: &PathBuf) -> Vec<u8> {
+        let mut file = File::open(filepath).expect("Couldnt open file");
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).expect("Couldnt read file");
+        contents
+    }
+
     #[test]
     fn test_generate_key() {
-        let salt = CryptoManager::generate_salt(SALT_LENGTH).unwrap();
-        let key = CryptoManager::generate_key(TEST_PASSWORD, &salt).unwrap();
+        let salt = CryptoManager::generate_salt(backend(), SALT_LENGTH).unwrap();
+        let key = CryptoManager::generate_key(
+            backend(),
+            TEST_PASSWORD,
+            None,
+            &KdfParams::default(),
+            &salt,
+        )
+        .unwrap();
         assert_eq!(key.len(), KEY_LENGTH);
     }
 
+    #[test]
+    fn test_generate_key_with_each_kdf() {
+        let salt = CryptoManager::generate_salt(backend(), SALT_LENGTH).unwrap();
+        let pbkdf2 = KdfParams::Pbkdf2HmacSha256 { iterations: 1_000 };
+        let argon2id = KdfParams::Argon2id {
+            memory_kib: 1024,
+            iterations: 1,
+            parallelism: 1,
+        };
+
+        let pbkdf2_key =
+            CryptoManager::generate_key(backend(), TEST_PASSWORD, None, &pbkdf2, &salt).unwrap();
+        let argon2id_key =
+            CryptoManager::generate_key(backend(), TEST_PASSWORD, None, &argon2id, &salt).unwrap();
+
+        assert_eq!(argon2id_key.len(), KEY_LENGTH);
+        assert_ne!(pbkdf2_key, argon2id_key);
+        assert_eq!(
+            argon2id_key,
+            CryptoManager::generate_key(backend(), TEST_PASSWORD, None, &argon2id, &salt).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_generate_key_rejects_invalid_argon2_parameters() {
+        let salt = CryptoManager::generate_salt(backend(), SALT_LENGTH).unwrap();
+        let kdf = KdfParams::Argon2id {
+            memory_kib: 1,
+            iterations: 0,
+            parallelism: 1,
+        };
+        assert!(matches!(
+            CryptoManager::generate_key(backend(), TEST_PASSWORD, None, &kdf, &salt),
+            Err(CryptoError::KeyDerivationFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_calibrate_kdf_never_goes_below_recommended_settings() {
+        let (kdf, unlock_time) = CryptoManager::calibrate_kdf(Duration::from_millis(1)).unwrap();
+        assert_eq!(kdf, KdfParams::default());
+        assert!(unlock_time > Duration::ZERO);
+    }
+
+    // a stand-in for argon2id that takes a microsecond per KiB per pass, nothing is allocated
+    fn simulated_kdf_cost(kdf: &KdfParams) -> Result<Duration, CryptoError> {
+        match kdf {
+            KdfParams::Argon2id {
+                memory_kib,
+                iterations,
+                ..
+            } => Ok(Duration::from_micros(
+                *memory_kib as u64 * *iterations as u64,
+            )),
+            KdfParams::Pbkdf2HmacSha256 { .. } => panic!("calibration should pick argon2id"),
+        }
+    }
+
+    #[test]
+    fn test_calibrate_kdf_spends_more_for_a_longer_target() {
+        let (kdf, unlock_time) =
+            CryptoManager::calibrate_kdf_with(Duration::from_millis(400), simulated_kdf_cost)
+                .unwrap();
+        // memory doubles while a single pass fits in half of what each default iteration may take
+        assert_eq!(
+            kdf,
+            KdfParams::Argon2id {
+                memory_kib: ARGON2ID_DEFAULT_MEMORY_KIB * 8,
+                iterations: ARGON2ID_DEFAULT_ITERATIONS,
+                parallelism: ARGON2ID_DEFAULT_PARALLELISM,
+            }
+        );
+        assert_eq!(unlock_time, simulated_kdf_cost(&kdf).unwrap());
+
+        let (kdf, _) =
+            CryptoManager::calibrate_kdf_with(Duration::from_millis(1), simulated_kdf_cost)
+                .unwrap();
+        assert_eq!(kdf, KdfParams::default());
+    }
+
+    #[test]
+    fn test_calibrate_kdf_caps_memory_and_fills_the_rest_with_iterations() {
+        let target = Duration::from_secs(100);
+        let (kdf, unlock_time) =
+            CryptoManager::calibrate_kdf_with(target, simulated_kdf_cost).unwrap();
+        match kdf {
+            KdfParams::Argon2id {
+                memory_kib,
+                iterations,
+                ..
+            } => {
+                assert_eq!(memory_kib, ARGON2ID_DEFAULT_MEMORY_KIB * 32);
+                assert!(memory_kib <= CALIBRATION_MAX_MEMORY_KIB);
+                assert!(iterations > ARGON2ID_DEFAULT_ITERATIONS);
+            }
+            KdfParams::Pbkdf2HmacSha256 { .. } => panic!("calibration should pick argon2id"),
+        }
+        assert!(unlock_time <= target);
+    }
+
+    #[test]
+    fn test_rekey_and_persist_switches_kdf() {
+        let testfilepath = generate_unique_file_path();
+        let mut crypto_manager = CryptoManager::new(&testfilepath, TEST_PASSWORD).unwrap();
+        crypto_manager.encrypt_and_persist(b"Test data").unwrap();
+        let old_salt = crypto_manager.unlocked_key_slot().salt.clone();
+        let old_key = crypto_manager.key.clone();
+        let kdf = KdfParams::Pbkdf2HmacSha256 { iterations: 1_000 };
+
+        crypto_manager
+            .rekey_and_persist(TEST_PASSWORD, kdf.clone(), b"Test data")
+            .unwrap();
+        assert_ne!(crypto_manager.unlocked_key_slot().salt, old_salt);
+        assert_eq!(crypto_manager.key, old_key);
+
+        let reopened = CryptoManager::open_read_only(&testfilepath, TEST_PASSWORD).unwrap();
+        assert_eq!(reopened.kdf(), &kdf);
+        assert_eq!(&*reopened.decrypt_and_retrieve().unwrap(), b"Test data");
+        assert_eq!(
+            reopened.verify_password("not the password"),
+            Err(CryptoError::WrongMasterPassword)
+        );
+
+        teardown(&testfilepath);
+    }
+
+    #[test]
+    fn test_key_slots_change_without_re_encrypting_the_records() {
+        let testfilepath = generate_unique_file_path();
+        let mut crypto_manager = CryptoManager::new(&testfilepath, TEST_PASSWORD).unwrap();
+        crypto_manager.encrypt_and_persist(b"Test data").unwrap();
+        let records = VaultFile::parse(&read_file(&testfilepath))
+            .unwrap()
+            .ciphertext;
+
+        let id = crypto_manager
+            .add_key_slot(
+                KeySlotKind::Password,
+                "spare",
+                "spare password",
+                KdfParams::Pbkdf2HmacSha256 { iterations: 1_000 },
+            )
+            .unwrap();
+        crypto_manager.persist_header().unwrap();
+        let vault_file = VaultFile::parse(&read_file(&testfilepath)).unwrap();
+        assert_eq!(vault_file.ciphertext, records);
+        assert_eq!(vault_file.header.key_slots.len(), 2);
+
+        let spare = CryptoManager::open_read_only(&testfilepath, "spare password").unwrap();
+        assert_eq!(spare.unlocked_key_slot().id, id);
+        assert_eq!(&*spare.decrypt_and_retrieve().unwrap(), b"Test data");
+
+        assert!(!crypto_manager.remove_key_slot(crypto_manager.unlocked_slot));
+        assert!(crypto_manager.remove_key_slot(id));
+        crypto_manager.persist_header().unwrap();
+        assert_eq!(
+            VaultFile::parse(&read_file(&testfilepath))
+                .unwrap()
+                .ciphertext,
+            records
+        );
+        let error = CryptoManager::open_read_only(&testfilepath, "spare password")
+            .err()
+            .expect("a revoked slot should no longer unlock the vault");
+        assert_eq!(
+            error.downcast_ref::<CryptoError>(),
+            Some(&CryptoError::WrongMasterPassword)
+        );
+
+        teardown(&testfilepath);
+    }
+
+    #[test]
+    fn test_key_file_is_needed_alongside_the_password() {
+        let testfilepath = generate_unique_file_path();
+        let key_file_path = PathBuf::from(format!("{}.key", testfilepath.display()));
+        let key_file = CryptoManager::create_key_file(&key_file_path).unwrap();
+        assert!(CryptoManager::create_key_file(&key_file_path).is_err());
+
+        let mut crypto_manager =
+            CryptoManager::with_key_file(&testfilepath, TEST_PASSWORD, Some(&key_file)).unwrap();
+        crypto_manager.encrypt_and_persist(b"Test data").unwrap();
+        drop(crypto_manager);
+
+        let mut wrong_key_file = key_file.clone();
+        wrong_key_file[0] ^= 0x01;
+        for (password, key_file, expected) in [
+            (TEST_PASSWORD, None, CryptoError::KeyFileMissingOrWrong),
+            (
+                TEST_PASSWORD,
+                Some(&*wrong_key_file),
+                CryptoError::KeyFileMissingOrWrong,
+            ),
+            (
+                "not the password",
+                Some(&*key_file),
+                CryptoError::WrongMasterPassword,
+            ),
+        ] {
+            let error =
+                CryptoManager::open_read_only_with_key_file(&testfilepath, password, key_file)
+                    .err()
+                    .expect("the vault should need both the password and the key file");
+            assert_eq!(error.downcast_ref::<CryptoError>(), Some(&expected));
+        }
+
+        let reopened = CryptoManager::open_read_only_with_key_file(
+            &testfilepath,
+            TEST_PASSWORD,
+            Some(&key_file),
+        )
+        .unwrap();
+        assert_eq!(&*reopened.decrypt_and_retrieve().unwrap(), b"Test data");
+
+        let _ = std::fs::remove_file(&key_file_path);
+        teardown(&testfilepath);
+    }
+
     #[test]
     fn test_encrypt_decrypt() {
         let testfilepath = generate_unique_file_path();
-        let salt = CryptoManager::generate_salt(SALT_LENGTH).unwrap();
-        let iv = CryptoManager::generate_iv(IV_LENGTH).unwrap();
-        let key = CryptoManager::generate_key(TEST_PASSWORD, &salt).unwrap();
+        let nonce = CryptoManager::generate_nonce(backend(), NONCE_LENGTH).unwrap();
+        let key = CryptoManager::generate_data_key(backend()).unwrap();
 
         let crypto_manager = CryptoManager {
-            salt,
-            iv,
+            header: VaultHeader {
+                cipher: CipherId::Aes256Gcm,
+                nonce,
+                backup_generations: DEFAULT_BACKUP_GENERATIONS,
+                password_history_length: None,
+                key_slots: Vec::new(),
+                legacy_key: None,
+            },
+            format_version: CURRENT_FORMAT_VERSION,
+            associated_data: Vec::new(),
             ciphertext: Vec::new(),
             key,
-            filepath: testfilepath.clone(),
+            legacy_key: None,
+            unlocked_slot: 0,
+            key_file: None,
+            storage: FileStorage::new(testfilepath.clone()),
+            on_disk: None,
+            backend: backend(),
         };
 
         let data = b"Hello, world!";
-        let encrypted_data = crypto_manager.encrypt_data(data).unwrap();
-        let decrypted_data = crypto_manager.decrypt_data(&encrypted_data).unwrap();
+        let aad = VaultFile::records_associated_data(&crypto_manager.header).unwrap();
+        let encrypted_data = crypto_manager.encrypt_data(data, &aad).unwrap();
+        let decrypted_data = crypto_manager.decrypt_data(&encrypted_data, &aad).unwrap();
 
-        assert_eq!(decrypted_data, data);
+        assert_eq!(&*decrypted_data, data);
         teardown(&testfilepath);
     }
 
     #[test]
     fn test_generate_salt() {
-        let salt = CryptoManager::generate_salt(SALT_LENGTH).unwrap();
+        let salt = CryptoManager::generate_salt(backend(), SALT_LENGTH).unwrap();
         assert_eq!(salt.len(), SALT_LENGTH);
     }
 
     #[test]
-    fn test_generate_iv() {
-        let iv = CryptoManager::generate_iv(IV_LENGTH).unwrap();
-        assert_eq!(iv.len(), IV_LENGTH);
+    fn test_generate_nonce() {
+        let nonce = CryptoManager::generate_nonce(backend(), NONCE_LENGTH).unwrap();
+        assert_eq!(nonce.len(), NONCE_LENGTH);
     }
 
     #[test]
@@ -210,56 +1768,459 @@ mod tests {
     }
 
     #[test]
-    fn test_salt_stored_correctly_in_encrypted_file() {
+    fn test_header_stored_correctly_in_encrypted_file() {
         let testfilepath = generate_unique_file_path();
         let mut crypto_manager = CryptoManager::new(&testfilepath, TEST_PASSWORD).unwrap();
         let data = b"Test data";
 
         let _ = crypto_manager.encrypt_and_persist(data);
 
-        let mut file = File::open(testfilepath.clone()).expect("Couldnt open file");
-        let mut contents = Vec::new();
-        file.read_to_end(&mut contents).expect("Couldnt read file");
+        let vault_file = VaultFile::parse(&read_file(&testfilepath)).unwrap();
 
-        let salt_from_file = &contents[0..SALT_LENGTH];
+        assert_eq!(vault_file.header, crypto_manager.header);
+        assert_eq!(vault_file.header.key_slots.len(), 1);
+        assert_eq!(vault_file.header.key_slots[0].salt.len(), SALT_LENGTH);
+        assert_eq!(vault_file.header.nonce.len(), NONCE_LENGTH);
 
-        assert_eq!(salt_from_file, crypto_manager.salt.as_slice());
+        teardown(&testfilepath);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_vault_is_only_readable_by_its_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let testfilepath = generate_unique_file_path();
+        let mut crypto_manager = CryptoManager::new(&testfilepath, TEST_PASSWORD).unwrap();
+        crypto_manager.encrypt_and_persist(b"Test data").unwrap();
+
+        let mode = std::fs::metadata(&testfilepath)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        teardown(&testfilepath);
+    }
+
+    #[test]
+    fn test_backups_rotate_and_keep_the_configured_number() {
+        let testfilepath = generate_unique_file_path();
+        let mut crypto_manager = CryptoManager::new(&testfilepath, TEST_PASSWORD).unwrap();
+        crypto_manager.set_backup_generations(2);
+
+        for data in [b"first", b"secnd", b"third", b"forth"] {
+            crypto_manager.encrypt_and_persist(data).unwrap();
+        }
+
+        let backups = crypto_manager.backups().unwrap();
+        assert_eq!(backups.len(), 2);
+        assert!(!crypto_manager.storage().backup_path(3).exists());
+        for (backup, expected) in backups.iter().zip([b"third", b"secnd"]) {
+            let backup_path = crypto_manager.storage().backup_path(backup.generation);
+            let restored = CryptoManager::open_read_only(&backup_path, TEST_PASSWORD).unwrap();
+            assert_eq!(&*restored.decrypt_and_retrieve().unwrap(), expected);
+        }
+
+        crypto_manager.restore_backup(2).unwrap();
+        let reopened = CryptoManager::open_read_only(&testfilepath, TEST_PASSWORD).unwrap();
+        assert_eq!(&*reopened.decrypt_and_retrieve().unwrap(), b"secnd");
+        let undo =
+            CryptoManager::open_read_only(&crypto_manager.storage().backup_path(1), TEST_PASSWORD)
+                .unwrap();
+        assert_eq!(&*undo.decrypt_and_retrieve().unwrap(), b"forth");
 
         teardown(&testfilepath);
     }
 
     #[test]
-    fn test_iv_stored_correctly_in_encrypted_file() {
+    fn test_each_persist_uses_a_fresh_nonce() {
         let testfilepath = generate_unique_file_path();
         let mut crypto_manager = CryptoManager::new(&testfilepath, TEST_PASSWORD).unwrap();
         let data = b"Test data";
 
-        let _ = crypto_manager.encrypt_and_persist(data);
+        crypto_manager.encrypt_and_persist(data).unwrap();
+        let first_write = VaultFile::parse(&read_file(&testfilepath)).unwrap();
+        crypto_manager.encrypt_and_persist(data).unwrap();
+        let second_write = VaultFile::parse(&read_file(&testfilepath)).unwrap();
 
-        let mut file = File::open(testfilepath.clone()).expect("Couldn't open fle");
-        let mut contents = Vec::new();
-        file.read_to_end(&mut contents)
-            .expect("Could not read file");
+        assert_ne!(first_write.header.nonce, second_write.header.nonce);
+        assert_ne!(first_write.ciphertext, second_write.ciphertext);
+
+        let reopened = CryptoManager::open_read_only(&testfilepath, TEST_PASSWORD).unwrap();
+        assert_eq!(&*reopened.decrypt_and_retrieve().unwrap(), data);
+
+        teardown(&testfilepath);
+    }
+
+    #[test]
+    fn test_version_3_vault_opens_and_is_rewritten_before_its_header() {
+        let testfilepath = generate_unique_file_path();
+        let mut crypto_manager = CryptoManager::new(&testfilepath, TEST_PASSWORD).unwrap();
+        let nonce = vec![2u8; NONCE_LENGTH];
+        crypto_manager.header.nonce = nonce.clone();
+        let header = &crypto_manager.header;
+        let header_json = format!(
+            r#"{{"cipher":"aes-256-gcm","nonce":{},"backup_generations":5,"key_slots":{}}}"#,
+            serde_json::to_string(&nonce).unwrap(),
+            serde_json::to_string(&header.key_slots).unwrap()
+        );
+        let associated_json = format!(
+            r#"{{"cipher":"aes-256-gcm","nonce":{},"backup_generations":5,"key_slots":[]}}"#,
+            serde_json::to_string(&nonce).unwrap()
+        );
+        let prefix = |json: &str| {
+            let mut bytes = b"ARMRPASS".to_vec();
+            bytes.extend_from_slice(&3u16.to_le_bytes());
+            bytes.extend_from_slice(&(json.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(json.as_bytes());
+            bytes
+        };
+        let ciphertext = crypto_manager
+            .encrypt_data(b"Test data", &prefix(&associated_json))
+            .unwrap();
+        std::fs::write(&testfilepath, [prefix(&header_json), ciphertext].concat()).unwrap();
+        drop(crypto_manager);
+
+        let mut crypto_manager = CryptoManager::new(&testfilepath, TEST_PASSWORD).unwrap();
+        assert_eq!(
+            &*crypto_manager.decrypt_and_retrieve().unwrap(),
+            b"Test data"
+        );
+        assert!(crypto_manager.needs_records_written());
+
+        crypto_manager.encrypt_and_persist(b"Test data").unwrap();
+        assert!(!crypto_manager.needs_records_written());
+        let reopened = CryptoManager::open_read_only(&testfilepath, TEST_PASSWORD).unwrap();
+        assert_eq!(reopened.format_version, CURRENT_FORMAT_VERSION);
+        assert_eq!(&*reopened.decrypt_and_retrieve().unwrap(), b"Test data");
+
+        teardown(&testfilepath);
+    }
+
+    #[test]
+    fn test_tampered_vault_fails_integrity_check() {
+        let testfilepath = generate_unique_file_path();
+        let mut crypto_manager = CryptoManager::new(&testfilepath, TEST_PASSWORD).unwrap();
+        crypto_manager.encrypt_and_persist(b"Test data").unwrap();
+        let vault_file = VaultFile::parse(&read_file(&testfilepath)).unwrap();
+
+        // flip a bit in the ciphertext
+        let mut tampered_ciphertext = read_file(&testfilepath);
+        let ciphertext_offset = tampered_ciphertext.len() - vault_file.ciphertext.len();
+        tampered_ciphertext[ciphertext_offset + 1] ^= 0x01;
+
+        // flip a bit in the nonce stored in the header
+        let mut tampered_header = vault_file.header.clone();
+        tampered_header.nonce[0] ^= 0x01;
+        let tampered_nonce = VaultFile::serialize(
+            &VaultFile::records_associated_data(&tampered_header).unwrap(),
+            &tampered_header.key_slots,
+            &vault_file.ciphertext,
+        )
+        .unwrap();
+
+        for contents in [tampered_ciphertext, tampered_nonce] {
+            let tampered_path = generate_unique_file_path();
+            std::fs::write(&tampered_path, &contents).unwrap();
+
+            let tampered = CryptoManager::new(&tampered_path, TEST_PASSWORD).unwrap();
+            let error = tampered.decrypt_and_retrieve().unwrap_err();
+            assert_eq!(
+                error.downcast_ref::<CryptoError>(),
+                Some(&CryptoError::IntegrityCheckFailed)
+            );
+            teardown(&tampered_path);
+        }
+
+        teardown(&testfilepath);
+    }
+
+    #[test]
+    fn test_wrong_password_is_reported_as_such() {
+        let testfilepath = generate_unique_file_path();
+        let mut crypto_manager = CryptoManager::new(&testfilepath, TEST_PASSWORD).unwrap();
+        crypto_manager.encrypt_and_persist(b"").unwrap();
+        drop(crypto_manager);
+
+        let error = CryptoManager::new(&testfilepath, "not the password")
+            .err()
+            .expect("a wrong password should not open the vault");
+        assert_eq!(
+            error.downcast_ref::<CryptoError>(),
+            Some(&CryptoError::WrongMasterPassword)
+        );
+
+        teardown(&testfilepath);
+    }
+
+    #[test]
+    fn test_second_instance_is_refused_while_the_vault_is_locked() {
+        let testfilepath = generate_unique_file_path();
+        let mut crypto_manager = CryptoManager::new(&testfilepath, TEST_PASSWORD).unwrap();
+        crypto_manager.encrypt_and_persist(b"Test data").unwrap();
+
+        let error = CryptoManager::new(&testfilepath, TEST_PASSWORD)
+            .err()
+            .expect("a locked vault should not open twice");
+        assert_eq!(
+            error.downcast_ref::<CryptoError>(),
+            Some(&CryptoError::VaultLocked(Some(std::process::id())))
+        );
+
+        let mut read_only = CryptoManager::open_read_only(&testfilepath, TEST_PASSWORD).unwrap();
+        assert_eq!(&*read_only.decrypt_and_retrieve().unwrap(), b"Test data");
+        let error = read_only.encrypt_and_persist(b"Other data").unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CryptoError>(),
+            Some(&CryptoError::ReadOnly)
+        );
+
+        drop(crypto_manager);
+        let reopened = CryptoManager::new(&testfilepath, TEST_PASSWORD).unwrap();
+        assert_eq!(reopened.stale_lock_pid(), None);
+
+        teardown(&testfilepath);
+    }
+
+    #[test]
+    fn test_lock_left_by_a_crashed_instance_is_reported_as_stale() {
+        let testfilepath = generate_unique_file_path();
+        std::fs::write(format!("{}.lock", testfilepath.display()), "4194304").unwrap();
 
-        let iv_from_file = &contents[SALT_LENGTH..(SALT_LENGTH + IV_LENGTH)];
+        let crypto_manager = CryptoManager::new(&testfilepath, TEST_PASSWORD).unwrap();
+        assert_eq!(crypto_manager.stale_lock_pid(), Some(4194304));
 
-        assert_eq!(iv_from_file, crypto_manager.iv.as_slice());
+        teardown(&testfilepath);
+    }
+
+    #[test]
+    fn test_random_file_is_not_an_armorpass_vault() {
+        let testfilepath = generate_unique_file_path();
+        std::fs::write(&testfilepath, b"definitely not a vault").unwrap();
+
+        let error = CryptoManager::new(&testfilepath, TEST_PASSWORD)
+            .err()
+            .expect("a random file should not open as a vault");
+        assert_eq!(
+            error.downcast_ref::<CryptoError>(),
+            Some(&CryptoError::NotAnArmorPassVault)
+        );
+
+        teardown(&testfilepath);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_entries_are_sealed_in_rows_of_their_own() {
+        use crate::storage::{EntryStorage, SqliteStorage};
+
+        let testfilepath = generate_unique_file_path().with_extension("db");
+        let mut crypto_manager = CryptoManager::with_storage(
+            SqliteStorage::open(testfilepath.clone()).unwrap(),
+            "password",
+            None,
+        )
+        .unwrap();
+        crypto_manager.encrypt_and_persist(b"[]").unwrap();
+        let ids = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        crypto_manager
+            .encrypt_and_persist_entries(
+                &[(ids[0], b"one"), (ids[1], b"two"), (ids[2], b"three")],
+                &[],
+            )
+            .unwrap();
+        let found = crypto_manager.decrypt_entries().unwrap();
+        assert_eq!(
+            found,
+            vec![
+                SecretBytes::from(&b"one"[..]),
+                SecretBytes::from(&b"two"[..]),
+                SecretBytes::from(&b"three"[..])
+            ]
+        );
+
+        // nothing about an entry is in the database in the clear
+        let database = read_file(&testfilepath);
+        assert!(!database
+            .windows(16)
+            .any(|window| window == ids[0].as_bytes()));
+        assert!(!database.windows(5).any(|window| window == b"three"));
+
+        // a row moved under another entry's lookup no longer decrypts, even with a manifest
+        // written to match
+        let mut tampered = SqliteStorage::open(testfilepath.clone()).unwrap();
+        let entries = tampered.load_entries().unwrap();
+        let manifest = tampered.load_manifest().unwrap().unwrap();
+        let mut swapped = entries.clone();
+        swapped[0].nonce = entries[1].nonce.clone();
+        swapped[0].ciphertext = entries[1].ciphertext.clone();
+        let forged = crypto_manager.entries_manifest(&swapped).unwrap();
+        tampered.write_entries(&swapped[..1], &[], &forged).unwrap();
+        let error = crypto_manager.decrypt_entries().unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CryptoError>(),
+            Some(&CryptoError::IntegrityCheckFailed)
+        );
+        tampered
+            .write_entries(&entries[..1], &[], &manifest)
+            .unwrap();
+
+        crypto_manager
+            .encrypt_and_persist_entries(&[], &[ids[0]])
+            .unwrap();
+        assert_eq!(crypto_manager.decrypt_entries().unwrap().len(), 2);
+
+        // the rows only check out as the set last written, neither an older copy of one row nor a
+        // row dropped gets past the manifest
+        let old_row = tampered.load_entries().unwrap().remove(0);
+        crypto_manager
+            .encrypt_and_persist_entries(&[(ids[1], b"changed")], &[])
+            .unwrap();
+        let rows = tampered.load_entries().unwrap();
+        let manifest = tampered.load_manifest().unwrap().unwrap();
+        for (written, deleted) in [
+            (vec![old_row], vec![]),
+            (vec![], vec![rows[1].lookup.clone()]),
+        ] {
+            tampered
+                .write_entries(&written, &deleted, &manifest)
+                .unwrap();
+            for error in [
+                crypto_manager.decrypt_entries().unwrap_err(),
+                crypto_manager
+                    .encrypt_and_persist_entries(&[(Uuid::new_v4(), b"four")], &[])
+                    .unwrap_err(),
+            ] {
+                assert_eq!(
+                    error.downcast_ref::<CryptoError>(),
+                    Some(&CryptoError::IntegrityCheckFailed)
+                );
+            }
+            tampered.write_entries(&rows, &[], &manifest).unwrap();
+            assert_eq!(crypto_manager.decrypt_entries().unwrap().len(), 2);
+        }
+
+        teardown(&testfilepath);
+    }
+
+    // A vault written by either backend, with a key file and a PBKDF2 slot alongside the
+    // argon2id one, has to open with the other and take a write from it
+    #[cfg(all(feature = "openssl", feature = "rustcrypto"))]
+    #[test]
+    fn test_vaults_open_with_either_backend() {
+        use crate::crypto_backend::{OpenSslBackend, RustCryptoBackend};
+
+        let backends: [(&'static dyn CryptoBackend, &'static dyn CryptoBackend); 2] = [
+            (&OpenSslBackend, &RustCryptoBackend),
+            (&RustCryptoBackend, &OpenSslBackend),
+        ];
+        for (writer, reader) in backends {
+            let testfilepath = generate_unique_file_path();
+            let key_file = [7u8; KEY_FILE_LENGTH];
+            let pbkdf2 = KdfParams::Pbkdf2HmacSha256 { iterations: 1_000 };
+
+            let mut written = CryptoManager::create(
+                writer,
+                FileStorage::new(testfilepath.clone()),
+                TEST_PASSWORD,
+                Some(&key_file),
+            )
+            .unwrap();
+            written
+                .add_key_slot(KeySlotKind::Password, "pbkdf2", "second password", pbkdf2)
+                .unwrap();
+            written.encrypt_and_persist(b"Test data").unwrap();
+            drop(written);
+
+            let mut read = CryptoManager::create(
+                reader,
+                FileStorage::new(testfilepath.clone()),
+                "second password",
+                Some(&key_file),
+            )
+            .unwrap();
+            assert_eq!(read.backend.name(), reader.name());
+            assert_eq!(&*read.decrypt_and_retrieve().unwrap(), b"Test data");
+            read.encrypt_and_persist(b"Written back").unwrap();
+            drop(read);
+
+            let reopened = CryptoManager::open(
+                writer,
+                FileStorage::new(testfilepath.clone()),
+                KeySlotKind::Password,
+                TEST_PASSWORD,
+                Some(&key_file),
+            )
+            .unwrap();
+            assert_eq!(&*reopened.decrypt_and_retrieve().unwrap(), b"Written back");
+
+            teardown(&testfilepath);
+        }
+    }
+
+    // only OpenSSL can write the legacy format, nothing else needs to
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn test_legacy_cbc_vault_opens_and_is_upgraded_on_write() {
+        let testfilepath = generate_unique_file_path();
+        let kdf = KdfParams::Pbkdf2HmacSha256 {
+            iterations: 100_000,
+        };
+        let salt = CryptoManager::generate_salt(backend(), 16).unwrap();
+        let iv = CryptoManager::generate_nonce(backend(), 16).unwrap();
+        let key = CryptoManager::generate_key(backend(), TEST_PASSWORD, None, &kdf, &salt).unwrap();
+        let legacy_ciphertext = openssl::symm::encrypt(
+            openssl::symm::Cipher::aes_256_cbc(),
+            &key,
+            Some(&iv),
+            b"legacy data",
+        )
+        .unwrap();
+        std::fs::write(&testfilepath, [salt, iv, legacy_ciphertext].concat()).unwrap();
+
+        let mut crypto_manager = CryptoManager::new(&testfilepath, TEST_PASSWORD).unwrap();
+        assert_eq!(crypto_manager.header.cipher, CipherId::Aes256Cbc);
+        let data = crypto_manager.decrypt_and_retrieve().unwrap();
+        assert_eq!(&*data, b"legacy data");
+
+        crypto_manager.encrypt_and_persist(&data).unwrap();
+        let vault_file = VaultFile::parse(&read_file(&testfilepath)).unwrap();
+        assert_eq!(vault_file.version, CURRENT_FORMAT_VERSION);
+
+        let upgraded = CryptoManager::open_read_only(&testfilepath, TEST_PASSWORD).unwrap();
+        assert_eq!(upgraded.header.cipher, CipherId::Aes256Gcm);
+        assert_eq!(upgraded.kdf(), &kdf);
+        assert_eq!(&*upgraded.decrypt_and_retrieve().unwrap(), b"legacy data");
 
         teardown(&testfilepath);
     }
 }
 
+// CREATING
+// 1. generate a random DATA KEY ( OF FIXED LENGTH ), it never changes for the life of the vault
+// 2. collect password, create random salt and feed the KDF the salt and pass to generate a
+//    WRAPPING KEY
+// 3. seal the DATA KEY with aes_256_gcm under the WRAPPING KEY into the first key slot, other
+//    slots seal the same DATA KEY under other secrets
+//
 // ENCRYPTING
-// 1. collect password
-// 2. create random salt of fixed length ( if creating new file ) else use one on file itself
-// 3. feed KDF the salt and pass to generate DERIVED KEY ( OF FIXED LENGTH )
-// 4. feed encryption alg the IV and DERIVED KEY to produce CIPHERTEXT
-// 5. write [SALT][IV][CIPHERTEXT] to file
-
+// 1. generate a fresh random NONCE for this write
+// 2. feed aes_256_gcm the NONCE and DATA KEY, with [MAGIC][VERSION][HEADER LENGTH][HEADER] as
+//    associated data, to produce CIPHERTEXT and an authentication TAG
+// 3. write [MAGIC][VERSION][HEADER LENGTH][HEADER][KEY SLOTS LENGTH][KEY SLOTS][CIPHERTEXT][TAG]
+//    to file, the header bytes exactly as they were sealed with
+//
 // DECRYPTING
-// 1. collect SALT/IV/CIPHERTEXT from file
-// 2. use SALT / PASS to generate DERIVED KEY
-// 3. use DERIVED KEY and IV to generate PLAINTEXT from CIPHERTEXT
+// 1. parse the header from file, older layouts are normalised by vault_format
+// 2. use SALT / PASS to generate the WRAPPING KEY of each password slot in turn until one opens,
+//    giving the DATA KEY. None opening means a wrong password
+// 3. use DATA KEY, NONCE and header to verify the TAG and generate PLAINTEXT from CIPHERTEXT,
+//    a TAG mismatch means a tampered file
 //
 // NOTES
-// create salt only on file creation
+// vaults from before key slots derive the key for their records straight from the password, they
+// get a DATA KEY and a master password slot on their next write
+// a recovery code slot is opened the same way with the code in place of the password, and an
+// escrow slot with the secret rebuilt from its shares, the session then reseals the DATA KEY under
+// a new master password
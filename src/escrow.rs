@@ -0,0 +1,322 @@
+use crate::crypto_backend::default_backend;
+use crate::encryption::CryptoError;
+use crate::secret::{SecretBytes, SecretString};
+use std::fmt::{self, Write};
+
+const SECRET_LENGTH: usize = 32;
+const SET_ID_LENGTH: usize = 4;
+const CHECKSUM_LENGTH: usize = 4;
+const SHARE_PREFIX: &str = "armorpass-share";
+pub const MIN_THRESHOLD: u8 = 2;
+
+// One of the shares an escrow secret was split into, any `threshold` shares from the same set
+// rebuild it. Written out as armorpass-share:<set>:<threshold>:<index>:<value>:<checksum>, all hex,
+// where the checksum only catches typos.
+#[derive(Clone, PartialEq)]
+pub struct EscrowShare {
+    set_id: Vec<u8>,
+    threshold: u8,
+    index: u8,
+    value: SecretBytes,
+}
+
+impl EscrowShare {
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    // Accepts a share however it was written down, in any case and with any whitespace in it
+    pub fn parse(input: &str) -> Result<EscrowShare, CryptoError> {
+        let invalid = |reason: &str| CryptoError::InvalidEscrowShare(reason.to_string());
+        // lower casing keeps every character the same length, so this never has to grow
+        let mut normalised = String::with_capacity(input.len());
+        for c in input.chars().filter(|c| !c.is_whitespace()) {
+            normalised.push(c.to_ascii_lowercase());
+        }
+        let normalised = SecretString::new(normalised);
+
+        let fields: Vec<&str> = normalised.expose().split(':').collect();
+        let [prefix, set_id, threshold, index, value, checksum] = fields[..] else {
+            return Err(invalid("that is not an ArmorPass escrow share"));
+        };
+        if prefix != SHARE_PREFIX {
+            return Err(invalid("that is not an ArmorPass escrow share"));
+        }
+        let share = EscrowShare {
+            set_id: decode_hex(set_id).ok_or_else(|| invalid("the set id is not hex"))?,
+            threshold: threshold
+                .parse()
+                .map_err(|_| invalid("the threshold is not a number"))?,
+            index: index
+                .parse()
+                .map_err(|_| invalid("the share number is not a number"))?,
+            value: decode_hex(value)
+                .map(SecretBytes::new)
+                .ok_or_else(|| invalid("the share value is not hex"))?,
+        };
+        if share.checksum() != checksum {
+            return Err(invalid("the checksum does not match, check it for typos"));
+        }
+        if share.set_id.len() != SET_ID_LENGTH
+            || share.value.len() != SECRET_LENGTH
+            || share.threshold < MIN_THRESHOLD
+            || share.index == 0
+        {
+            return Err(invalid("the share is malformed"));
+        }
+        Ok(share)
+    }
+
+    fn body(&self) -> String {
+        format!(
+            "{}:{}:{}:{}:{}",
+            SHARE_PREFIX,
+            encode_hex(&self.set_id),
+            self.threshold,
+            self.index,
+            encode_hex(&self.value)
+        )
+    }
+
+    fn checksum(&self) -> String {
+        encode_hex(&default_backend().sha256(self.body().as_bytes())[..CHECKSUM_LENGTH])
+    }
+}
+
+// only printed on purpose, through Display
+impl fmt::Debug for EscrowShare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EscrowShare")
+            .field("set_id", &encode_hex(&self.set_id))
+            .field("threshold", &self.threshold)
+            .field("index", &self.index)
+            .field("value", &"[REDACTED]")
+            .finish()
+    }
+}
+
+impl fmt::Display for EscrowShare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.body(), self.checksum())
+    }
+}
+
+pub fn generate_secret() -> Result<SecretBytes, CryptoError> {
+    let mut secret = SecretBytes::zeroed(SECRET_LENGTH);
+    default_backend().random_bytes(&mut secret)?;
+    Ok(secret)
+}
+
+// What the escrow key slot is sealed under. Written into a buffer of the final size, so no
+// partial copy is left behind by it growing.
+pub fn slot_secret(secret: &[u8]) -> SecretString {
+    let mut hex = String::with_capacity(secret.len() * 2);
+    for byte in secret {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    SecretString::new(hex)
+}
+
+// Shamir's secret sharing over GF(2^8), byte by byte. Each byte of the secret is the constant
+// term of a random polynomial of degree threshold - 1, and share x holds every polynomial at x.
+// The caller makes sure MIN_THRESHOLD <= threshold <= count.
+pub fn split(secret: &[u8], threshold: u8, count: u8) -> Result<Vec<EscrowShare>, CryptoError> {
+    let backend = default_backend();
+    let mut set_id = vec![0u8; SET_ID_LENGTH];
+    backend.random_bytes(&mut set_id)?;
+    // together with any one share these give the secret away, they are wiped like it
+    let mut coefficients = SecretBytes::zeroed(secret.len() * (threshold as usize - 1));
+    backend.random_bytes(&mut coefficients)?;
+
+    Ok((1..=count)
+        .map(|x| EscrowShare {
+            set_id: set_id.clone(),
+            threshold,
+            index: x,
+            value: secret
+                .iter()
+                .zip(coefficients.chunks(threshold as usize - 1))
+                .map(|(&constant, higher)| {
+                    // Horner's rule, highest coefficient first
+                    let y = higher
+                        .iter()
+                        .rev()
+                        .fold(0u8, |y, &coefficient| gf_mul(y, x) ^ coefficient);
+                    gf_mul(y, x) ^ constant
+                })
+                .collect::<Vec<u8>>()
+                .into(),
+        })
+        .collect())
+}
+
+// Rebuilds the secret from at least `threshold` shares of the same set, by Lagrange
+// interpolation at x = 0
+pub fn combine(shares: &[EscrowShare]) -> Result<SecretBytes, CryptoError> {
+    let invalid = CryptoError::InvalidEscrowShare;
+    let first = shares
+        .first()
+        .ok_or_else(|| invalid("no shares were given".to_string()))?;
+    if shares
+        .iter()
+        .any(|share| share.set_id != first.set_id || share.threshold != first.threshold)
+    {
+        return Err(invalid(
+            "the shares come from different splits of the vault key".to_string(),
+        ));
+    }
+    for (position, share) in shares.iter().enumerate() {
+        if shares[..position]
+            .iter()
+            .any(|other| other.index == share.index)
+        {
+            return Err(invalid(format!("share {} was given twice", share.index)));
+        }
+    }
+    if shares.len() < first.threshold as usize {
+        return Err(invalid(format!(
+            "{} of the shares are needed, only {} were given",
+            first.threshold,
+            shares.len()
+        )));
+    }
+
+    let shares = &shares[..first.threshold as usize];
+    let mut secret = SecretBytes::zeroed(SECRET_LENGTH);
+    for share in shares {
+        // the Lagrange basis polynomial of this share, at x = 0. Subtraction is xor in GF(2^8).
+        let basis = shares
+            .iter()
+            .filter(|other| other.index != share.index)
+            .fold(1u8, |basis, other| {
+                gf_mul(basis, gf_div(other.index, other.index ^ share.index))
+            });
+        for (byte, &y) in secret.iter_mut().zip(share.value.iter()) {
+            *byte ^= gf_mul(y, basis);
+        }
+    }
+    Ok(secret)
+}
+
+// multiplication in GF(2^8) modulo the AES polynomial x^8 + x^4 + x^3 + x + 1
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0u8;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+// a / b for b != 0, as a * b^254 since b^255 = 1
+fn gf_div(a: u8, b: u8) -> u8 {
+    let mut inverse = 1u8;
+    for _ in 0..254 {
+        inverse = gf_mul(inverse, b);
+    }
+    gf_mul(a, inverse)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// into a buffer of the final size, share values are decoded with it
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(hex.len() / 2);
+    for i in (0..hex.len()).step_by(2) {
+        bytes.push(u8::from_str_radix(&hex[i..i + 2], 16).ok()?);
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_any_threshold_shares_rebuild_the_secret() {
+        let secret = generate_secret().unwrap();
+        let shares = split(&secret, 2, 3).unwrap();
+        assert_eq!(shares.len(), 3);
+
+        for pair in [[0, 1], [0, 2], [2, 1]] {
+            let chosen = [shares[pair[0]].clone(), shares[pair[1]].clone()];
+            assert_eq!(combine(&chosen).unwrap(), secret);
+        }
+        assert!(matches!(
+            combine(&shares[..1]),
+            Err(CryptoError::InvalidEscrowShare(_))
+        ));
+        assert!(matches!(
+            combine(&[shares[0].clone(), shares[0].clone()]),
+            Err(CryptoError::InvalidEscrowShare(_))
+        ));
+    }
+
+    #[test]
+    fn test_shares_parse_back_from_their_printed_form() {
+        let shares = split(&generate_secret().unwrap(), 3, 5).unwrap();
+        let printed = shares[4].to_string();
+
+        assert_eq!(EscrowShare::parse(&printed).unwrap(), shares[4]);
+        assert_eq!(
+            EscrowShare::parse(&format!(" {}\n", printed.to_uppercase())).unwrap(),
+            shares[4]
+        );
+
+        let value_start = printed.match_indices(':').nth(3).unwrap().0 + 1;
+        let mut typo = printed.clone().into_bytes();
+        typo[value_start] = if typo[value_start] == b'a' {
+            b'b'
+        } else {
+            b'a'
+        };
+        let typo = String::from_utf8(typo).unwrap();
+        assert!(matches!(
+            EscrowShare::parse(&typo),
+            Err(CryptoError::InvalidEscrowShare(_))
+        ));
+        assert!(matches!(
+            EscrowShare::parse("not a share"),
+            Err(CryptoError::InvalidEscrowShare(_))
+        ));
+    }
+
+    #[test]
+    fn test_shares_from_different_splits_are_refused() {
+        let secret = generate_secret().unwrap();
+        let first = split(&secret, 2, 3).unwrap();
+        let second = split(&secret, 2, 3).unwrap();
+
+        assert!(matches!(
+            combine(&[first[0].clone(), second[1].clone()]),
+            Err(CryptoError::InvalidEscrowShare(_))
+        ));
+    }
+
+    #[test]
+    fn test_debug_does_not_show_the_share_value() {
+        let shares = split(&generate_secret().unwrap(), 2, 2).unwrap();
+        let printed = shares[0].to_string();
+        let value = printed.split(':').nth(4).unwrap();
+
+        let debug = format!("{:?}", shares[0]);
+        assert!(!debug.contains(value));
+        assert!(debug.contains("[REDACTED]"));
+    }
+}
@@ -1,5 +1,7 @@
+use crate::secret::SecretString;
 use crate::utility::{prompt_for_confirmation, prompt_for_number};
 use rand::{distributions::Uniform, seq::SliceRandom, Rng};
+use zeroize::Zeroize;
 
 #[derive(Default)]
 pub struct PasswordGeneratorOptions {
@@ -67,11 +69,11 @@ impl PasswordGenerator {
     }
 
     // Generates a password based on the specified criteria
-    pub fn generate(&self) -> String {
+    pub fn generate(&self) -> SecretString {
         if !self.unicode {
-            self.generate_ascii_password()
+            SecretString::new(self.generate_ascii_password())
         } else {
-            self.generate_unicode_password()
+            SecretString::new(self.generate_unicode_password())
         }
     }
 
@@ -96,11 +98,15 @@ impl PasswordGenerator {
 
         password.shuffle(&mut rng);
 
-        password.into_iter().collect()
+        // sized for the widest chars so it is never reallocated, the chars are wiped afterwards
+        let mut generated = String::with_capacity(password.len() * 4);
+        generated.extend(password.iter());
+        password.zeroize();
+        generated
     }
 
     fn generate_unicode_password(&self) -> String {
-        let mut password = String::new();
+        let mut password = String::with_capacity(self.length as usize * 4);
         let mut rng = rand::thread_rng();
         for _ in 0..self.length {
             password.push(self.generate_random_unicode_character(&mut rng));
@@ -165,7 +171,6 @@ impl PasswordGenerator {
 }
 
 #[cfg(test)]
-
 mod tests {
     use super::*;
 
@@ -177,6 +182,7 @@ mod tests {
         };
         let generator = PasswordGenerator::new(&options);
         let password = generator.generate();
+        let password = password.expose();
         assert_eq!(password.len(), 15);
     }
 
@@ -189,6 +195,7 @@ mod tests {
         };
         let generator = PasswordGenerator::new(&options);
         let password = generator.generate();
+        let password = password.expose();
         let min_uppercase = password.chars().filter(|c| c.is_uppercase()).count();
         assert_eq!(min_uppercase, 3);
     }
@@ -202,6 +209,7 @@ mod tests {
         };
         let generator = PasswordGenerator::new(&options);
         let password = generator.generate();
+        let password = password.expose();
         let min_numbers = password.chars().filter(|c| c.is_numeric()).count();
         assert_eq!(min_numbers, 3);
     }
@@ -219,6 +227,7 @@ mod tests {
         ];
         let generator = PasswordGenerator::new(&options);
         let password = generator.generate();
+        let password = password.expose();
 
         assert!(
             password.chars().all(|c| special_chars.contains(&c)),
@@ -234,8 +243,9 @@ mod tests {
         };
         let generator = PasswordGenerator::new(&options);
         let password = generator.generate();
+        let password = password.expose();
         let is_unicode = password.chars().any(|c| c as u32 > 127); //if all characters found were
                                                                    //ascii, it would be a christmas miracle
-        assert_eq!(is_unicode, true);
+        assert!(is_unicode);
     }
 }
@@ -1,8 +1,15 @@
 #![allow(non_snake_case)]
 pub mod autocomplete;
+pub mod crypto_backend;
 pub mod encryption;
+pub mod entry_type;
+pub mod escrow;
 pub mod generator;
 pub mod password_manager;
+pub mod recovery_code;
+pub mod secret;
 pub mod shell;
+pub mod storage;
 pub mod strings;
 pub mod utility;
+pub mod vault_format;
@@ -1,15 +1,46 @@
 #![allow(non_snake_case)]
 pub mod autocomplete;
+pub mod crypto_backend;
 pub mod encryption;
+pub mod entry_type;
+pub mod escrow;
 pub mod generator;
 pub mod password_manager;
+pub mod recovery_code;
+pub mod secret;
 pub mod shell;
+pub mod storage;
 pub mod strings;
 pub mod utility;
+pub mod vault_format;
 
-use crate::shell::Shell;
+use crate::shell::{Shell, StorageKind, Unlock};
+
+#[cfg(feature = "sqlite")]
+const USAGE: &str = "usage: armorpass [--storage file|sqlite] [--recover | --shares]";
+#[cfg(not(feature = "sqlite"))]
+const USAGE: &str = "usage: armorpass [--storage file] [--recover | --shares]";
+
+// None for anything it does not understand
+fn parse_args(mut args: impl Iterator<Item = String>) -> Option<(StorageKind, Unlock)> {
+    let mut storage = StorageKind::File;
+    let mut unlock = Unlock::MasterPassword;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--storage" => storage = StorageKind::parse(&args.next()?)?,
+            "--recover" if unlock == Unlock::MasterPassword => unlock = Unlock::RecoveryCode,
+            "--shares" if unlock == Unlock::MasterPassword => unlock = Unlock::EscrowShares,
+            _ => return None,
+        }
+    }
+    Some((storage, unlock))
+}
 
 fn main() {
-    let mut armor_pass_shell = Shell::new();
+    let Some((storage, unlock)) = parse_args(std::env::args().skip(1)) else {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    };
+    let mut armor_pass_shell = Shell::new(storage, unlock);
     armor_pass_shell.run();
 }
@@ -1,40 +1,185 @@
-use crate::encryption::CryptoManager;
+use crate::encryption::{BackupInfo, CryptoError, CryptoManager};
+use crate::entry_type::EntryType;
+use crate::escrow::{self, EscrowShare};
+use crate::recovery_code::RecoveryCode;
+use crate::secret::{self, SecretBytes, SecretString};
 use crate::shell::CreatePasswordOptions;
 use crate::shell::DeletePasswordOptions;
 use crate::shell::RetrieveAllOptions;
 use crate::shell::RetrieveSingleOptions;
 use crate::shell::UpdatePasswordOptions;
-use crate::utility::{validate_identifier, ArmorPassError};
+use crate::storage::{FileStorage, VaultStorage};
+use crate::utility::{validate_custom_fields, validate_identifier, ArmorPassError};
+use crate::vault_format::{KdfParams, KeySlot, KeySlotKind};
 
+use chrono::{DateTime, Utc};
 use std::path::PathBuf;
+use uuid::Uuid;
 
-pub struct PasswordManager {
+// the same width whatever the password, so a listing gives nothing away about it
+const PASSWORD_MASK: &str = "********";
+
+pub struct PasswordManager<S: VaultStorage = FileStorage> {
     records: Vec<CredentialSet>,
-    crypto_manager: CryptoManager,
+    // the records as they were last read from or written to disk, the common ancestor when
+    // merging in changes made to the vault by something else
+    base_records: Vec<CredentialSet>,
+    crypto_manager: CryptoManager<S>,
+    conflict_resolver: ConflictResolver,
+}
+
+// Decides which side of a merge conflict wins, the default keeps the change made here
+pub type ConflictResolver = fn(&MergeConflict) -> MergeSide;
+
+// An entry that was changed both here and in the copy on disk. A side is None where the entry
+// was deleted.
+#[derive(Debug)]
+pub struct MergeConflict {
+    pub id: Uuid,
+    pub identifier: String,
+    pub username: String,
+    pub ours: Option<CredentialSet>,
+    pub theirs: Option<CredentialSet>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MergeSide {
+    Ours,
+    Theirs,
+}
+
+// Everything after the password was added later, entries written before then load with the
+// fields empty and no timestamps
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
 pub struct CredentialSet {
+    // given on creation and never changed, entries from before ids get one when the vault is
+    // opened, see backfill_ids
+    #[serde(default)]
+    pub id: Uuid,
+    // what the password and fields hold, see EntryType::schema
+    #[serde(default)]
+    pub entry_type: EntryType,
     pub identifier: String,
     pub username: String,
+    pub password: SecretString,
+    #[serde(default)]
+    pub url: String,
+    #[serde(default)]
+    pub notes: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub created: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub modified: Option<DateTime<Utc>>,
+    // when the password was last copied out of the vault
+    #[serde(default)]
+    pub last_used: Option<DateTime<Utc>>,
+    // the passwords it had before, the most recent first, as many as the vault's history length
+    #[serde(default)]
+    pub history: Vec<PasswordHistoryEntry>,
+    // extra named values such as an account id or security answers, in the order they were given
+    #[serde(default)]
+    pub fields: Vec<CustomField>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct CustomField {
+    pub name: String,
+    pub value: SecretString,
+    // masked in listings like the password
+    #[serde(default)]
+    pub concealed: bool,
+}
+
+impl CustomField {
+    pub fn mask(&self) -> MaskedCustomField {
+        MaskedCustomField {
+            name: self.name.clone(),
+            value: if self.concealed {
+                PASSWORD_MASK.to_string()
+            } else {
+                self.value.expose().to_string()
+            },
+            concealed: self.concealed,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MaskedCustomField {
+    pub name: String,
+    pub value: String,
+    pub concealed: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct PasswordHistoryEntry {
+    pub password: SecretString,
+    // when it stopped being the password
+    pub replaced: DateTime<Utc>,
+}
+
+impl PasswordHistoryEntry {
+    pub fn mask(&self) -> MaskedPasswordHistoryEntry {
+        MaskedPasswordHistoryEntry {
+            password: PASSWORD_MASK.to_string(),
+            replaced: self.replaced,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MaskedPasswordHistoryEntry {
     pub password: String,
+    pub replaced: DateTime<Utc>,
 }
 
 impl CredentialSet {
+    // puts a password that has just been replaced at the front of the history
+    fn remember_password(&mut self, password: SecretString, history_length: u32) {
+        self.history.insert(
+            0,
+            PasswordHistoryEntry {
+                password,
+                replaced: Utc::now(),
+            },
+        );
+        self.history.truncate(history_length as usize);
+    }
+
     pub fn mask(&self) -> MaskedCredentialSet {
         MaskedCredentialSet {
+            id: self.id,
+            entry_type: self.entry_type,
             identifier: self.identifier.clone(),
             username: self.username.clone(),
-            password: "*".repeat(self.password.len()),
+            password: PASSWORD_MASK.to_string(),
+            url: self.url.clone(),
+            notes: self.notes.clone(),
+            tags: self.tags.clone(),
+            created: self.created,
+            modified: self.modified,
+            last_used: self.last_used,
+            fields: self.fields.iter().map(CustomField::mask).collect(),
         }
     }
 }
 
 #[derive(Debug)]
 pub struct MaskedCredentialSet {
+    pub id: Uuid,
+    pub entry_type: EntryType,
     pub identifier: String,
     pub username: String,
     pub password: String,
+    pub url: String,
+    pub notes: String,
+    pub tags: Vec<String>,
+    pub created: Option<DateTime<Utc>>,
+    pub modified: Option<DateTime<Utc>>,
+    pub last_used: Option<DateTime<Utc>>,
+    pub fields: Vec<MaskedCustomField>,
 }
 
 impl PasswordManager {
@@ -42,44 +187,211 @@ impl PasswordManager {
         armorpass_path: PathBuf,
         password: &str,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let new_crypto_manager = CryptoManager::new(&armorpass_path, password)?;
-        let stored_credentials = new_crypto_manager.decrypt_and_retrieve()?;
-        let deserialized_records = if !stored_credentials.is_empty() {
-            serde_json::from_slice(&stored_credentials)?
-        } else {
-            Vec::new()
-        };
-        Ok(PasswordManager {
-            records: deserialized_records,
-            crypto_manager: new_crypto_manager,
-        })
+        PasswordManager::with_key_file(armorpass_path, password, None)
+    }
+
+    // Opens the vault with a key file as well as the password, a new vault will need it from then on
+    pub fn with_key_file(
+        armorpass_path: PathBuf,
+        password: &str,
+        key_file: Option<&[u8]>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        PasswordManager::with_storage(FileStorage::new(armorpass_path), password, key_file)
+    }
+
+    // Opens a vault that another instance holds, records can be read but not changed
+    pub fn open_read_only(
+        armorpass_path: PathBuf,
+        password: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        PasswordManager::open_read_only_with_key_file(armorpass_path, password, None)
+    }
+
+    pub fn open_read_only_with_key_file(
+        armorpass_path: PathBuf,
+        password: &str,
+        key_file: Option<&[u8]>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        PasswordManager::open_storage_read_only(
+            FileStorage::new(armorpass_path),
+            password,
+            key_file,
+        )
+    }
+
+    // Opens the vault with its recovery code and sets a new master password before anything else
+    // can be done with it. The old master password stops working, the recovery code keeps working.
+    pub fn with_recovery_code(
+        armorpass_path: PathBuf,
+        recovery_code: &RecoveryCode,
+        new_password: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        PasswordManager::with_storage_and_recovery_code(
+            FileStorage::new(armorpass_path),
+            recovery_code,
+            new_password,
+        )
+    }
+
+    // Opens the vault with enough escrow shares and sets a new master password, as above
+    pub fn with_escrow_shares(
+        armorpass_path: PathBuf,
+        shares: &[EscrowShare],
+        new_password: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        PasswordManager::with_storage_and_escrow_shares(
+            FileStorage::new(armorpass_path),
+            shares,
+            new_password,
+        )
+    }
+}
+
+// The same again for a vault kept anywhere else, see VaultStorage
+impl<S: VaultStorage> PasswordManager<S> {
+    pub fn with_storage(
+        storage: S,
+        password: &str,
+        key_file: Option<&[u8]>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let new_crypto_manager = CryptoManager::with_storage(storage, password, key_file)?;
+        let mut password_manager = PasswordManager::with_records(
+            PasswordManager::deserialize_records(&new_crypto_manager)?,
+            new_crypto_manager,
+        );
+        // write a brand new vault straight away so its key slot is on disk, otherwise any
+        // password would unlock it until the first credential was stored
+        if !password_manager.crypto_manager.is_persisted() {
+            password_manager.persist_credentials()?;
+        }
+        password_manager.backfill_ids()?;
+        Ok(password_manager)
+    }
+
+    pub fn open_storage_read_only(
+        storage: S,
+        password: &str,
+        key_file: Option<&[u8]>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let crypto_manager = CryptoManager::open_storage_read_only(storage, password, key_file)?;
+        let mut password_manager = PasswordManager::with_records(
+            PasswordManager::deserialize_records(&crypto_manager)?,
+            crypto_manager,
+        );
+        password_manager.backfill_ids()?;
+        Ok(password_manager)
+    }
+
+    pub fn with_storage_and_recovery_code(
+        storage: S,
+        recovery_code: &RecoveryCode,
+        new_password: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let crypto_manager = CryptoManager::with_storage_and_recovery_code(storage, recovery_code)?;
+        PasswordManager::reset_master_password(crypto_manager, new_password)
+    }
+
+    pub fn with_storage_and_escrow_shares(
+        storage: S,
+        shares: &[EscrowShare],
+        new_password: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let crypto_manager = CryptoManager::with_storage_and_escrow_shares(storage, shares)?;
+        PasswordManager::reset_master_password(crypto_manager, new_password)
+    }
+
+    fn reset_master_password(
+        crypto_manager: CryptoManager<S>,
+        new_password: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut password_manager = PasswordManager::with_records(
+            PasswordManager::deserialize_records(&crypto_manager)?,
+            crypto_manager,
+        );
+        password_manager.backfill_ids()?;
+        let json_data = password_manager.serialize_records()?;
+        password_manager
+            .crypto_manager
+            .reset_master_password(new_password, &json_data)?;
+        password_manager.reseal_backups();
+        Ok(password_manager)
+    }
+
+    fn with_records(records: Vec<CredentialSet>, crypto_manager: CryptoManager<S>) -> Self {
+        PasswordManager {
+            base_records: records.clone(),
+            records,
+            crypto_manager,
+            conflict_resolver: |_| MergeSide::Ours,
+        }
+    }
+
+    pub fn set_conflict_resolver(&mut self, resolver: ConflictResolver) {
+        self.conflict_resolver = resolver;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.crypto_manager.is_read_only()
+    }
+
+    pub fn stale_lock_pid(&self) -> Option<u32> {
+        self.crypto_manager.stale_lock_pid()
     }
 
     pub fn store_password(
         &mut self,
         options: &CreatePasswordOptions,
     ) -> Result<(), ArmorPassError> {
-        if self.password_is_duplicate(&options.password) {
+        self.ensure_writable()?;
+        let id = Uuid::new_v4();
+        if options.entry_type == EntryType::Login
+            && self.password_is_duplicate(options.password.expose())
+        {
             eprintln!("[ERROR]: Password must be unique");
             return Err(ArmorPassError::CreateDuplicatePassword);
         }
 
-        if self.username_is_duplicate(&options.username) {
+        if self.username_is_duplicate(
+            id,
+            options.entry_type,
+            &options.identifier,
+            &options.username,
+        ) {
             eprintln!("[ERROR]: username must be unique");
             return Err(ArmorPassError::CreateDuplicateUsername);
         }
 
         validate_identifier(&options.identifier)?;
+        validate_custom_fields(&options.fields)?;
+        if let Err(e) = options.entry_type.validate(
+            &options.username,
+            options.password.expose(),
+            &options.fields,
+        ) {
+            eprintln!("[ERROR]: {}", e);
+            return Err(e);
+        }
 
+        let now = Utc::now();
         let new_credentials = CredentialSet {
+            id,
+            entry_type: options.entry_type,
             identifier: options.identifier.to_string(),
             username: options.username.to_string(),
-            password: options.password.to_string(),
+            password: options.password.clone(),
+            url: options.url.clone(),
+            notes: options.notes.clone(),
+            tags: options.tags.clone(),
+            created: Some(now),
+            modified: Some(now),
+            last_used: None,
+            history: Vec::new(),
+            fields: options.fields.clone(),
         };
 
         self.records.push(new_credentials);
 
-        Self::persist_credentials(self)?;
+        self.persist_entry(id)?;
 
         Ok(())
     }
@@ -100,44 +412,172 @@ impl PasswordManager {
         }
     }
 
+    pub fn retrieve_credential_by_id(&self, id: Uuid) -> Option<&CredentialSet> {
+        self.records.iter().find(|record| record.id == id)
+    }
+
     pub fn update_password(
         &mut self,
         options: &UpdatePasswordOptions,
     ) -> Result<(), ArmorPassError> {
-        // Find a mutable reference to the record that needs updating.
-        if let Some(record) = self.records.iter_mut().find(|record| {
-            record.identifier == options.identifier && record.username == options.username
-        }) {
-            // If found, update the password field.
-            record.password = options.password.to_string();
-            Self::persist_credentials(self)?;
-            Ok(())
-        } else {
-            Err(ArmorPassError::NoRecordFound)
+        self.ensure_writable()?;
+        let id = self
+            .find_id(&options.identifier, &options.username)
+            .ok_or(ArmorPassError::NoRecordFound)?;
+        self.update_credential_by_id(id, options)
+    }
+
+    // Updates the password of the entry with this id and whichever of its other fields are given.
+    // The identifier and username in the options are what it is called from now on, so it can be
+    // renamed. A password that changes goes into the entry's history.
+    pub fn update_credential_by_id(
+        &mut self,
+        id: Uuid,
+        options: &UpdatePasswordOptions,
+    ) -> Result<(), ArmorPassError> {
+        self.ensure_writable()?;
+        let index = self
+            .records
+            .iter()
+            .position(|record| record.id == id)
+            .ok_or(ArmorPassError::NoRecordFound)?;
+        let identifier = self.records[index].identifier.clone();
+        let username = self.records[index].username.clone();
+        if options.identifier != identifier {
+            validate_identifier(&options.identifier)?;
         }
+        let entry_type = self.records[index].entry_type;
+        if (options.identifier != identifier || options.username != username)
+            && self.username_is_duplicate(id, entry_type, &options.identifier, &options.username)
+        {
+            return Err(ArmorPassError::CreateDuplicateUsername);
+        }
+        let fields = options
+            .fields
+            .as_ref()
+            .unwrap_or(&self.records[index].fields);
+        validate_custom_fields(fields)?;
+        entry_type.validate(&options.username, options.password.expose(), fields)?;
+
+        let history_length = self.password_history_length();
+        let record = &mut self.records[index];
+        record.identifier = options.identifier.clone();
+        record.username = options.username.clone();
+        if record.password != options.password {
+            let previous = std::mem::replace(&mut record.password, options.password.clone());
+            record.remember_password(previous, history_length);
+        }
+        if let Some(url) = &options.url {
+            record.url = url.clone();
+        }
+        if let Some(notes) = &options.notes {
+            record.notes = notes.clone();
+        }
+        if let Some(tags) = &options.tags {
+            record.tags = tags.clone();
+        }
+        if let Some(fields) = &options.fields {
+            record.fields = fields.clone();
+        }
+        record.modified = Some(Utc::now());
+        self.persist_entry(id)
+    }
+
+    // Rolls the entry back to the password its history holds at `index`, 0 being the most recent.
+    // The password it replaces goes into the history, so the revert can itself be undone.
+    pub fn revert_password(&mut self, id: Uuid, index: usize) -> Result<(), ArmorPassError> {
+        self.ensure_writable()?;
+        let history_length = self.password_history_length();
+        let record = self
+            .records
+            .iter_mut()
+            .find(|record| record.id == id)
+            .ok_or(ArmorPassError::NoRecordFound)?;
+        if index >= record.history.len() {
+            return Err(ArmorPassError::NoHistoryEntryFound);
+        }
+        let reverted = record.history.remove(index);
+        let previous = std::mem::replace(&mut record.password, reverted.password);
+        record.remember_password(previous, history_length);
+        record.modified = Some(Utc::now());
+        self.persist_entry(id)
+    }
+
+    pub fn password_history_length(&self) -> u32 {
+        self.crypto_manager.password_history_length()
+    }
+
+    // Sets how many earlier passwords each entry keeps, the oldest are dropped from any entry that
+    // has more
+    pub fn set_password_history_length(&mut self, length: u32) -> Result<(), ArmorPassError> {
+        self.ensure_writable()?;
+        self.crypto_manager.set_password_history_length(length);
+        let mut trimmed = Vec::new();
+        for record in self
+            .records
+            .iter_mut()
+            .filter(|record| record.history.len() > length as usize)
+        {
+            record.history.truncate(length as usize);
+            trimmed.push(record.clone());
+        }
+        if self.crypto_manager.stores_entries_separately() {
+            self.persist_entries(&trimmed, &[])?;
+        }
+        self.persist_credentials()
+    }
+
+    // Stamps the entry as used now, for when its password has been copied out. That is not worth
+    // a backup generation, the vault is written over in place.
+    pub fn record_use(&mut self, identifier: &str, username: &str) -> Result<(), ArmorPassError> {
+        self.ensure_writable()?;
+        let id = self
+            .find_id(identifier, username)
+            .ok_or(ArmorPassError::NoRecordFound)?;
+        self.record_use_by_id(id)
+    }
+
+    pub fn record_use_by_id(&mut self, id: Uuid) -> Result<(), ArmorPassError> {
+        self.ensure_writable()?;
+        let record = self
+            .records
+            .iter_mut()
+            .find(|record| record.id == id)
+            .ok_or(ArmorPassError::NoRecordFound)?;
+        record.last_used = Some(Utc::now());
+        if self.crypto_manager.stores_entries_separately() {
+            return self.persist_entry(id);
+        }
+        self.write_credentials(false)
     }
 
     pub fn delete_credential(
         &mut self,
         options: &DeletePasswordOptions,
     ) -> Result<(), ArmorPassError> {
-        // Store the original length to determine if a record was deleted.
-        let original_len = self.records.len();
-
-        // Retain only the records that do not match the identifier and username.
-        self.records.retain(|record| {
-            record.identifier != options.identifier || record.username != options.username
-        });
-
-        // Check if the records collection has changed in size.
-        if self.records.len() == original_len {
-            // No records were deleted, return an error.
-            Err(ArmorPassError::NoRecordFound)
-        } else {
-            // A record was deleted, persist the changes.
-            Self::persist_credentials(self)?;
-            Ok(())
-        }
+        self.ensure_writable()?;
+        let id = self
+            .find_id(&options.identifier, &options.username)
+            .ok_or(ArmorPassError::NoRecordFound)?;
+        self.delete_credential_by_id(id)
+    }
+
+    pub fn delete_credential_by_id(&mut self, id: Uuid) -> Result<(), ArmorPassError> {
+        self.ensure_writable()?;
+        let index = self
+            .records
+            .iter()
+            .position(|record| record.id == id)
+            .ok_or(ArmorPassError::NoRecordFound)?;
+        self.records.remove(index);
+        self.persist_entry(id)
+    }
+
+    fn find_id(&self, identifier: &str, username: &str) -> Option<Uuid> {
+        self.records
+            .iter()
+            .find(|record| record.identifier == identifier && record.username == username)
+            .map(|record| record.id)
     }
 
     pub fn retrieve_all_credentials(&self, options: &RetrieveAllOptions) -> Vec<&CredentialSet> {
@@ -160,35 +600,535 @@ impl PasswordManager {
             .collect()
     }
 
-    fn persist_credentials(&mut self) -> Result<(), ArmorPassError> {
-        let json_data = serde_json::to_string(&self.records).map_err(|e| {
+    // Writes a copy of the vault into storage that does not hold one yet, with the same key slots
+    // so the same secrets unlock it. This is how a vault moves between a single file and a SQLite
+    // database, either way round. The vault here is left as it was.
+    pub fn convert_into<T: VaultStorage>(
+        &self,
+        storage: T,
+    ) -> Result<PasswordManager<T>, Box<dyn std::error::Error>> {
+        let crypto_manager = self.crypto_manager.copy_into(storage)?;
+        let mut converted = PasswordManager::with_records(self.records.clone(), crypto_manager);
+        // the entries go first, the copy can not be opened until the vault itself is written
+        if converted.crypto_manager.stores_entries_separately() {
+            converted.persist_entries(&self.records, &[])?;
+        }
+        converted.persist_credentials()?;
+        Ok(converted)
+    }
+
+    pub fn kdf_params(&self) -> &KdfParams {
+        self.crypto_manager.kdf()
+    }
+
+    pub fn change_kdf(
+        &mut self,
+        master_password: &str,
+        kdf: KdfParams,
+    ) -> Result<(), ArmorPassError> {
+        self.rekey(master_password, master_password, kdf)
+    }
+
+    pub fn change_master_password(
+        &mut self,
+        current_password: &str,
+        new_password: &str,
+    ) -> Result<(), ArmorPassError> {
+        let kdf = self.crypto_manager.kdf().clone();
+        self.rekey(current_password, new_password, kdf)
+    }
+
+    fn rekey(
+        &mut self,
+        current_password: &str,
+        new_password: &str,
+        kdf: KdfParams,
+    ) -> Result<(), ArmorPassError> {
+        self.ensure_writable()?;
+        self.verify_master_password(current_password)?;
+
+        self.merge_external_changes()?;
+        let json_data = self.serialize_records()?;
+
+        self.crypto_manager
+            .rekey_and_persist(new_password, kdf, &json_data)
+            .map_err(|e| {
+                ArmorPassError::FailedToPersistToDisk(format!(
+                    "Failed to re-encrypt vault with new key: {}",
+                    e
+                ))
+            })?;
+        self.base_records = self.records.clone();
+        self.reseal_backups();
+
+        Ok(())
+    }
+
+    pub fn backups(&self) -> Result<Vec<BackupInfo>, ArmorPassError> {
+        self.crypto_manager.backups().map_err(|e| {
+            ArmorPassError::FailedToPersistToDisk(format!("Failed to list backups: {}", e))
+        })
+    }
+
+    pub fn backup_generations(&self) -> u32 {
+        self.crypto_manager.backup_generations()
+    }
+
+    // a SQLite database keeps no backups, see SqliteStorage
+    pub fn keeps_backups(&self) -> bool {
+        self.crypto_manager.storage().keeps_backups()
+    }
+
+    pub fn set_backup_generations(&mut self, generations: u32) -> Result<(), ArmorPassError> {
+        self.ensure_writable()?;
+        self.crypto_manager.set_backup_generations(generations);
+        Self::persist_credentials(self)
+    }
+
+    pub fn key_slots(&self) -> &[KeySlot] {
+        self.crypto_manager.key_slots()
+    }
+
+    pub fn unlocked_key_slot(&self) -> &KeySlot {
+        self.crypto_manager.unlocked_key_slot()
+    }
+
+    // Lets another password unlock the vault alongside the master password, returning the id of
+    // its key slot
+    pub fn add_password_slot(
+        &mut self,
+        master_password: &str,
+        label: &str,
+        password: &str,
+    ) -> Result<u32, ArmorPassError> {
+        self.ensure_writable()?;
+        self.verify_master_password(master_password)?;
+
+        let kdf = self.kdf_params().clone();
+        let id = self
+            .crypto_manager
+            .add_key_slot(KeySlotKind::Password, label, password, kdf)
+            .map_err(|e| {
+                ArmorPassError::FailedToPersistToDisk(format!("Failed to add key slot: {}", e))
+            })?;
+        self.persist_key_slots()?;
+        Ok(id)
+    }
+
+    // Issues a new recovery code that can unlock the vault without the master password or key
+    // file, replacing any code issued before
+    pub fn add_recovery_code(
+        &mut self,
+        master_password: &str,
+    ) -> Result<RecoveryCode, ArmorPassError> {
+        self.ensure_writable()?;
+        self.verify_master_password(master_password)?;
+
+        let recovery_code = RecoveryCode::generate().map_err(|e| {
             ArmorPassError::FailedToPersistToDisk(format!(
-                "Failed to serialize records to json: {}",
+                "Failed to generate recovery code: {}",
                 e
             ))
         })?;
+        self.replace_key_slot(
+            KeySlotKind::RecoveryCode,
+            "recovery code",
+            recovery_code.secret(),
+        )?;
+        Ok(recovery_code)
+    }
+
+    // Splits a new escrow secret that can unlock the vault into `count` shares, any `threshold`
+    // of which rebuild it. Shares from an earlier split stop working.
+    pub fn split_into_escrow_shares(
+        &mut self,
+        master_password: &str,
+        threshold: u8,
+        count: u8,
+    ) -> Result<Vec<EscrowShare>, ArmorPassError> {
+        self.ensure_writable()?;
+        if threshold < escrow::MIN_THRESHOLD || threshold > count {
+            return Err(ArmorPassError::InvalidEscrowThreshold);
+        }
+        self.verify_master_password(master_password)?;
 
+        let generated = escrow::generate_secret()
+            .and_then(|secret| Ok((escrow::split(&secret, threshold, count)?, secret)));
+        let (shares, secret) = generated.map_err(|e| {
+            ArmorPassError::FailedToPersistToDisk(format!("Failed to split the vault key: {}", e))
+        })?;
+        self.replace_key_slot(
+            KeySlotKind::EscrowShares,
+            &format!("{} of {} escrow shares", threshold, count),
+            escrow::slot_secret(&secret).expose(),
+        )?;
+        Ok(shares)
+    }
+
+    // Swaps whatever slots of this kind there are for a single new one. Only used for secrets
+    // that are strong on their own, so the default kdf settings are plenty.
+    fn replace_key_slot(
+        &mut self,
+        kind: KeySlotKind,
+        label: &str,
+        secret: &str,
+    ) -> Result<(), ArmorPassError> {
+        let previous: Vec<u32> = self
+            .key_slots()
+            .iter()
+            .filter(|slot| slot.kind == kind)
+            .map(|slot| slot.id)
+            .collect();
+        for id in previous {
+            self.crypto_manager.remove_key_slot(id);
+        }
         self.crypto_manager
-            .encrypt_and_persist(&json_data.into_bytes())
+            .add_key_slot(kind, label, secret, KdfParams::default())
+            .map_err(|e| {
+                ArmorPassError::FailedToPersistToDisk(format!("Failed to add key slot: {}", e))
+            })?;
+        self.persist_key_slots()
+    }
+
+    pub fn revoke_key_slot(
+        &mut self,
+        master_password: &str,
+        id: u32,
+    ) -> Result<(), ArmorPassError> {
+        self.ensure_writable()?;
+        self.verify_master_password(master_password)?;
+
+        if id == self.unlocked_key_slot().id {
+            return Err(ArmorPassError::KeySlotInUse);
+        }
+        if !self.crypto_manager.remove_key_slot(id) {
+            return Err(ArmorPassError::NoKeySlotFound);
+        }
+        self.persist_key_slots()
+    }
+
+    // Rolls the vault back to a backup generation, but only once it has been shown to decrypt with
+    // the current master password. The vault being replaced becomes the newest backup.
+    pub fn restore_backup(
+        &mut self,
+        generation: u32,
+        master_password: &str,
+    ) -> Result<(), ArmorPassError> {
+        self.ensure_writable()?;
+        if !self.crypto_manager.has_backup(generation) {
+            return Err(ArmorPassError::NoBackupFound);
+        }
+
+        let backup = self
+            .crypto_manager
+            .open_backup(generation, master_password)
+            .map_err(|e| match e.downcast_ref::<CryptoError>() {
+                Some(CryptoError::WrongMasterPassword) => ArmorPassError::WrongMasterPassword,
+                _ => ArmorPassError::CorruptBackup(e.to_string()),
+            })?;
+        PasswordManager::deserialize_records(&backup)
+            .map_err(|e| ArmorPassError::CorruptBackup(e.to_string()))?;
+
+        self.crypto_manager
+            .restore_backup(generation)
+            .map_err(|e| {
+                ArmorPassError::FailedToPersistToDisk(format!("Failed to restore backup: {}", e))
+            })?;
+
+        self.crypto_manager
+            .reload(master_password)
+            .map_err(|e| ArmorPassError::CorruptBackup(e.to_string()))?;
+        self.records = PasswordManager::deserialize_records(&self.crypto_manager)
+            .map_err(|e| ArmorPassError::CorruptBackup(e.to_string()))?;
+        self.base_records = self.records.clone();
+        self.backfill_ids()
+    }
+
+    fn verify_master_password(&self, master_password: &str) -> Result<(), ArmorPassError> {
+        self.crypto_manager
+            .verify_password(master_password)
+            .map_err(|e| match e {
+                CryptoError::WrongMasterPassword => ArmorPassError::WrongMasterPassword,
+                e => ArmorPassError::FailedToPersistToDisk(format!(
+                    "Failed to verify master password: {}",
+                    e
+                )),
+            })
+    }
+
+    // Writes out a change to the key slots without re-encrypting the records, unless they need
+    // writing anyway
+    fn persist_key_slots(&mut self) -> Result<(), ArmorPassError> {
+        if self.merge_external_changes()? || self.crypto_manager.needs_records_written() {
+            self.persist_credentials()?;
+        } else {
+            self.crypto_manager.persist_header().map_err(|e| {
+                ArmorPassError::FailedToPersistToDisk(format!("Failed to persist key slots: {}", e))
+            })?;
+        }
+        self.reseal_backups();
+        Ok(())
+    }
+
+    // Gives the backups the key slots just written, so a secret revoked or replaced does not keep
+    // opening them. The vault itself is already written by now, what could not be done is only
+    // warned about.
+    fn reseal_backups(&mut self) {
+        match self.crypto_manager.reseal_backups() {
+            Ok(left_alone) if !left_alone.is_empty() => eprintln!(
+                "[Warn]: Backups {:?} could not be re-sealed, they still open with the secrets \
+                 they were written with",
+                left_alone
+            ),
+            Ok(_) => {}
+            Err(e) => eprintln!("[Warn]: Could not re-seal the backups: {}", e),
+        }
+    }
+
+    fn ensure_writable(&self) -> Result<(), ArmorPassError> {
+        if self.is_read_only() {
+            return Err(ArmorPassError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    fn deserialize_records(
+        crypto_manager: &CryptoManager<S>,
+    ) -> Result<Vec<CredentialSet>, Box<dyn std::error::Error>> {
+        let stored_credentials = crypto_manager.decrypt_and_retrieve()?;
+        let mut records: Vec<CredentialSet> = if stored_credentials.is_empty() {
+            Vec::new()
+        } else {
+            serde_json::from_slice(&stored_credentials)?
+        };
+        for entry in crypto_manager.decrypt_entries()? {
+            records.push(serde_json::from_slice(&entry)?);
+        }
+        Ok(records)
+    }
+
+    fn serialize_records(&self) -> Result<SecretBytes, ArmorPassError> {
+        // entries kept in rows of their own are not written into the vault as well
+        let records: &[CredentialSet] = if self.crypto_manager.stores_entries_separately() {
+            &[]
+        } else {
+            &self.records
+        };
+        secret::to_json(&records).map_err(|e| {
+            ArmorPassError::FailedToPersistToDisk(format!(
+                "Failed to serialize records to json: {}",
+                e
+            ))
+        })
+    }
+
+    fn persist_credentials(&mut self) -> Result<(), ArmorPassError> {
+        self.write_credentials(true)
+    }
+
+    // the vault being replaced is kept as a backup unless `keep_backup` is false
+    fn write_credentials(&mut self, keep_backup: bool) -> Result<(), ArmorPassError> {
+        self.merge_external_changes()?;
+        let json_data = self.serialize_records()?;
+
+        let written = if keep_backup {
+            self.crypto_manager.encrypt_and_persist(&json_data)
+        } else {
+            self.crypto_manager.encrypt_and_persist_in_place(&json_data)
+        };
+        written.map_err(|e| {
+            ArmorPassError::FailedToPersistToDisk(format!(
+                "Failed to encrypt and persist data: {}",
+                e
+            ))
+        })?;
+        self.base_records = self.records.clone();
+
+        Ok(())
+    }
+
+    // Writes out a change to the entry with this id, added, changed or deleted. Where the storage
+    // keeps entries in rows of their own only its row is written, otherwise the whole vault is.
+    fn persist_entry(&mut self, id: Uuid) -> Result<(), ArmorPassError> {
+        if !self.crypto_manager.stores_entries_separately() {
+            return self.persist_credentials();
+        }
+        let changed: Vec<CredentialSet> = self
+            .records
+            .iter()
+            .filter(|record| record.id == id)
+            .cloned()
+            .collect();
+        let deleted: &[Uuid] = if changed.is_empty() { &[id] } else { &[] };
+        self.persist_entries(&changed, deleted)?;
+        self.base_records = self.records.clone();
+
+        Ok(())
+    }
+
+    // Entries stored before they had ids are given one as the vault is opened, and written back
+    // straight away unless it is read-only so the id sticks
+    fn backfill_ids(&mut self) -> Result<(), ArmorPassError> {
+        let mut backfilled = Vec::new();
+        for record in self.records.iter_mut().filter(|record| record.id.is_nil()) {
+            record.id = Uuid::new_v4();
+            backfilled.push(record.clone());
+        }
+        if backfilled.is_empty() || self.is_read_only() {
+            return Ok(());
+        }
+        if !self.crypto_manager.stores_entries_separately() {
+            return self.persist_credentials();
+        }
+        self.persist_entries(&backfilled, &[])?;
+        self.base_records = self.records.clone();
+
+        Ok(())
+    }
+
+    // writes these entries and drops the rows of the entries with the ids in `deleted`, in one step
+    fn persist_entries(
+        &mut self,
+        records: &[CredentialSet],
+        deleted: &[Uuid],
+    ) -> Result<(), ArmorPassError> {
+        let serialized = records
+            .iter()
+            .map(secret::to_json)
+            .collect::<serde_json::Result<Vec<_>>>()
             .map_err(|e| {
                 ArmorPassError::FailedToPersistToDisk(format!(
-                    "Failed to encrypt and persist data: {}",
+                    "Failed to serialize entry to json: {}",
                     e
                 ))
             })?;
+        let entries: Vec<(Uuid, &[u8])> = records
+            .iter()
+            .zip(&serialized)
+            .map(|(record, json)| (record.id, &json[..]))
+            .collect();
+        self.crypto_manager
+            .encrypt_and_persist_entries(&entries, deleted)
+            .map_err(|e| {
+                ArmorPassError::FailedToPersistToDisk(format!(
+                    "Failed to encrypt and persist entry: {}",
+                    e
+                ))
+            })
+    }
 
-        Ok(())
+    // If the vault on disk was replaced since we last touched it, folds its records into ours so
+    // the coming write does not throw them away
+    fn merge_external_changes(&mut self) -> Result<bool, ArmorPassError> {
+        // entries in rows of their own are written one at a time under the lock, there is never a
+        // whole other copy of them to merge
+        if self.crypto_manager.stores_entries_separately() {
+            return Ok(false);
+        }
+        let external = self.crypto_manager.read_external_changes().map_err(|e| {
+            ArmorPassError::FailedToPersistToDisk(format!(
+                "Failed to read changes made to the vault on disk: {}",
+                e
+            ))
+        })?;
+        let Some(external) = external else {
+            return Ok(false);
+        };
+        let theirs: Vec<CredentialSet> = if external.is_empty() {
+            Vec::new()
+        } else {
+            serde_json::from_slice(&external).map_err(|e| {
+                ArmorPassError::FailedToPersistToDisk(format!(
+                    "Failed to read changes made to the vault on disk: {}",
+                    e
+                ))
+            })?
+        };
+
+        println!("[INFO]: The vault was changed on disk, merging in those changes");
+        self.records = merge_records(
+            &self.base_records,
+            &self.records,
+            &theirs,
+            self.conflict_resolver,
+        );
+        self.base_records = theirs;
+        Ok(true)
     }
 
+    // only logins are held to unique passwords, the other kinds keep what they are given
     fn password_is_duplicate(&self, password: &str) -> bool {
         self.records
             .iter()
-            .any(|record| record.password == password)
+            .any(|record| record.entry_type == EntryType::Login && record.password == password)
     }
 
-    fn username_is_duplicate(&self, username: &str) -> bool {
+    // Logins keep their usernames unique across the vault. Entries of kinds with a username need
+    // an identifier and username no other such entry has, so they can be looked up by them.
+    // Kinds without one, such as notes, are found by id and can share an identifier.
+    fn username_is_duplicate(
+        &self,
+        id: Uuid,
+        entry_type: EntryType,
+        identifier: &str,
+        username: &str,
+    ) -> bool {
+        let has_username = |entry_type: EntryType| entry_type.schema().username.is_some();
         self.records
             .iter()
-            .any(|record| record.username == username)
+            .filter(|record| record.id != id)
+            .any(|record| {
+                (has_username(entry_type)
+                    && has_username(record.entry_type)
+                    && record.identifier == identifier
+                    && record.username == username)
+                    || (entry_type == EntryType::Login
+                        && record.entry_type == EntryType::Login
+                        && record.username == username)
+            })
+    }
+}
+
+// A three way merge at the level of whole entries, keyed by id. A change made on one side only is
+// taken as is, the resolver only decides entries both sides changed.
+fn merge_records(
+    base: &[CredentialSet],
+    ours: &[CredentialSet],
+    theirs: &[CredentialSet],
+    resolver: ConflictResolver,
+) -> Vec<CredentialSet> {
+    let find = |records: &[CredentialSet], key: &CredentialSet| {
+        records.iter().find(|record| record.id == key.id).cloned()
+    };
+
+    let mut merged = Vec::new();
+    // entries on disk keep their order, entries only we have go on the end
+    let keys = theirs
+        .iter()
+        .chain(ours.iter().filter(|record| find(theirs, record).is_none()));
+    for key in keys {
+        let base_record = find(base, key);
+        let our_record = find(ours, key);
+        let their_record = find(theirs, key);
+
+        let winner = if our_record == their_record || their_record == base_record {
+            our_record
+        } else if our_record == base_record {
+            their_record
+        } else {
+            let conflict = MergeConflict {
+                id: key.id,
+                identifier: key.identifier.clone(),
+                username: key.username.clone(),
+                ours: our_record,
+                theirs: their_record,
+            };
+            match resolver(&conflict) {
+                MergeSide::Ours => conflict.ours,
+                MergeSide::Theirs => conflict.theirs,
+            }
+        };
+        merged.extend(winner);
     }
+    merged
 }
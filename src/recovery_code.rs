@@ -0,0 +1,163 @@
+use crate::crypto_backend::default_backend;
+use crate::encryption::CryptoError;
+use crate::secret::{SecretBytes, SecretString};
+use std::fmt;
+
+// Crockford's base32, there is no I, L, O or U so nothing can be misread when typed back in
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const CODE_BYTES: usize = 20;
+const DATA_CHARACTERS: usize = CODE_BYTES * 8 / 5;
+const CHECKSUM_CHARACTERS: usize = 4;
+const CODE_CHARACTERS: usize = DATA_CHARACTERS + CHECKSUM_CHARACTERS;
+const GROUP_LENGTH: usize = 4;
+
+// 160 random bits written out as base32 with a short checksum on the end, printed in groups of
+// four, e.g. 7K2M-...-Q9XA. The checksum only catches typos, it adds nothing to the strength.
+#[derive(PartialEq)]
+pub struct RecoveryCode {
+    characters: SecretString,
+}
+
+impl RecoveryCode {
+    pub fn generate() -> Result<RecoveryCode, CryptoError> {
+        let mut bytes = SecretBytes::zeroed(CODE_BYTES);
+        default_backend().random_bytes(&mut bytes)?;
+        let data = SecretString::new(encode(&bytes, DATA_CHARACTERS));
+        let mut characters = String::with_capacity(CODE_CHARACTERS);
+        characters.push_str(data.expose());
+        characters.push_str(&checksum(data.expose()));
+        Ok(RecoveryCode {
+            characters: SecretString::new(characters),
+        })
+    }
+
+    // Accepts the code however it was written down, in any case, with or without the dashes, and
+    // with O, I and L in place of 0 and 1
+    pub fn parse(input: &str) -> Result<RecoveryCode, CryptoError> {
+        // never grown past its capacity, so a reallocation can not leave part of the code behind
+        let mut characters = String::with_capacity(CODE_CHARACTERS);
+        let mut valid = true;
+        for character in input.chars() {
+            let character = match character.to_ascii_uppercase() {
+                '-' | ' ' => continue,
+                'O' => '0',
+                'I' | 'L' => '1',
+                c if c.is_ascii() && ALPHABET.contains(&(c as u8)) => c,
+                _ => {
+                    valid = false;
+                    break;
+                }
+            };
+            if characters.len() == CODE_CHARACTERS {
+                valid = false;
+                break;
+            }
+            characters.push(character);
+        }
+        let characters = SecretString::new(characters);
+
+        if !valid || characters.expose().len() != CODE_CHARACTERS {
+            return Err(CryptoError::InvalidRecoveryCode);
+        }
+        let (data, expected_checksum) = characters.expose().split_at(DATA_CHARACTERS);
+        if checksum(data) != expected_checksum {
+            return Err(CryptoError::InvalidRecoveryCode);
+        }
+        Ok(RecoveryCode { characters })
+    }
+
+    // what the key slot is sealed under, the code without its dashes
+    pub fn secret(&self) -> &str {
+        self.characters.expose()
+    }
+}
+
+// only printed on purpose, through Display
+impl fmt::Debug for RecoveryCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RecoveryCode([REDACTED])")
+    }
+}
+
+impl fmt::Display for RecoveryCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let groups: Vec<&str> = self
+            .characters
+            .expose()
+            .as_bytes()
+            .chunks(GROUP_LENGTH)
+            .map(|group| std::str::from_utf8(group).unwrap_or_default())
+            .collect();
+        write!(f, "{}", groups.join("-"))
+    }
+}
+
+// the first `length` five bit groups of the bytes as base32
+fn encode(bytes: &[u8], length: usize) -> String {
+    (0..length)
+        .map(|index| {
+            let value = (index * 5..index * 5 + 5).fold(0usize, |value, bit| {
+                (value << 1) | ((bytes[bit / 8] >> (7 - bit % 8)) & 1) as usize
+            });
+            ALPHABET[value] as char
+        })
+        .collect()
+}
+
+fn checksum(data: &str) -> String {
+    encode(
+        &default_backend().sha256(data.as_bytes()),
+        CHECKSUM_CHARACTERS,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_code_parses_back_from_its_printed_form() {
+        let code = RecoveryCode::generate().unwrap();
+        let printed = code.to_string();
+        assert_eq!(printed.len(), 44);
+        assert_eq!(printed.matches('-').count(), 8);
+
+        assert_eq!(RecoveryCode::parse(&printed).unwrap(), code);
+        assert_eq!(
+            RecoveryCode::parse(&printed.to_lowercase().replace('-', " ")).unwrap(),
+            code
+        );
+        assert_eq!(
+            RecoveryCode::parse(&printed.replace('0', "O").replace('1', "l")).unwrap(),
+            code
+        );
+    }
+
+    #[test]
+    fn test_typos_are_caught_by_the_checksum() {
+        let code = RecoveryCode::generate().unwrap().to_string();
+        let mut typo = code.clone().into_bytes();
+        typo[0] = if typo[0] == b'A' { b'B' } else { b'A' };
+
+        assert_eq!(
+            RecoveryCode::parse(std::str::from_utf8(&typo).unwrap()).unwrap_err(),
+            CryptoError::InvalidRecoveryCode
+        );
+        assert_eq!(
+            RecoveryCode::parse(&code[..code.len() - 1]).unwrap_err(),
+            CryptoError::InvalidRecoveryCode
+        );
+        assert_eq!(
+            RecoveryCode::parse("not a recovery code").unwrap_err(),
+            CryptoError::InvalidRecoveryCode
+        );
+    }
+
+    #[test]
+    fn test_debug_does_not_show_the_code() {
+        let code = RecoveryCode::generate().unwrap();
+        let debug = format!("{:?}", code);
+        assert!(!debug.contains(code.secret()));
+        assert!(debug.contains("[REDACTED]"));
+    }
+}
@@ -0,0 +1,360 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+#[cfg(unix)]
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::{Deref, DerefMut};
+#[cfg(unix)]
+use std::sync::{Mutex, OnceLock, PoisonError};
+use zeroize::Zeroize;
+
+use crate::crypto_backend::constant_time_eq;
+
+// Bytes that must not outlive their use: keys, key files and decrypted vault contents. The buffer
+// is locked in RAM where the OS allows it, so it is never swapped to disk, and zeroed on drop.
+// Nothing is ever appended, the buffer is never reallocated and left behind unwiped.
+pub struct SecretBytes {
+    bytes: Vec<u8>,
+    locked: bool,
+}
+
+impl SecretBytes {
+    pub fn new(bytes: Vec<u8>) -> SecretBytes {
+        let locked = lock_memory(bytes.as_ptr(), bytes.capacity());
+        SecretBytes { bytes, locked }
+    }
+
+    pub fn zeroed(length: usize) -> SecretBytes {
+        SecretBytes::new(vec![0u8; length])
+    }
+}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl DerefMut for SecretBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(bytes: Vec<u8>) -> SecretBytes {
+        SecretBytes::new(bytes)
+    }
+}
+
+impl From<&[u8]> for SecretBytes {
+    fn from(bytes: &[u8]) -> SecretBytes {
+        SecretBytes::new(bytes.to_vec())
+    }
+}
+
+impl Clone for SecretBytes {
+    fn clone(&self) -> SecretBytes {
+        SecretBytes::new(self.bytes.clone())
+    }
+}
+
+// compared in constant time, so how long a comparison takes gives nothing away about the secret
+impl PartialEq for SecretBytes {
+    fn eq(&self, other: &SecretBytes) -> bool {
+        constant_time_eq(&self.bytes, &other.bytes)
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretBytes({} bytes)", self.bytes.len())
+    }
+}
+
+impl Zeroize for SecretBytes {
+    fn zeroize(&mut self) {
+        self.bytes.zeroize();
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        let (pointer, capacity) = (self.bytes.as_ptr(), self.bytes.capacity());
+        self.zeroize();
+        if self.locked {
+            unlock_memory(pointer, capacity);
+        }
+    }
+}
+
+// The same for text, the master password and the passwords kept in the vault. Debug and Display
+// never show it, reading it takes an explicit call to expose.
+#[derive(Clone, PartialEq)]
+pub struct SecretString {
+    bytes: SecretBytes,
+}
+
+impl SecretString {
+    pub fn new(text: String) -> SecretString {
+        SecretString {
+            bytes: SecretBytes::new(text.into_bytes()),
+        }
+    }
+
+    pub fn expose(&self) -> &str {
+        // only ever built from a String, see new
+        std::str::from_utf8(&self.bytes).unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+impl Default for SecretString {
+    fn default() -> SecretString {
+        SecretString::new(String::new())
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(text: String) -> SecretString {
+        SecretString::new(text)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(text: &str) -> SecretString {
+        SecretString::new(text.to_string())
+    }
+}
+
+impl PartialEq<str> for SecretString {
+    fn eq(&self, other: &str) -> bool {
+        constant_time_eq(self.expose().as_bytes(), other.as_bytes())
+    }
+}
+
+impl PartialEq<&str> for SecretString {
+    fn eq(&self, other: &&str) -> bool {
+        constant_time_eq(self.expose().as_bytes(), other.as_bytes())
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[REDACTED]")
+    }
+}
+
+impl fmt::Display for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[REDACTED]")
+    }
+}
+
+impl Zeroize for SecretString {
+    fn zeroize(&mut self) {
+        self.bytes.zeroize();
+    }
+}
+
+impl Serialize for SecretString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.expose())
+    }
+}
+
+// the String serde builds is moved in as it is, there is no copy left behind
+impl<'de> Deserialize<'de> for SecretString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<SecretString, D::Error> {
+        String::deserialize(deserializer).map(SecretString::new)
+    }
+}
+
+// Serializes to JSON in a buffer of exactly the right size, measured by a first pass, so it is never
+// reallocated with a copy of the plaintext left behind
+pub fn to_json<T: Serialize>(value: &T) -> serde_json::Result<SecretBytes> {
+    let mut length = ByteCount(0);
+    serde_json::to_writer(&mut length, value)?;
+    let mut json = SecretBytes::new(Vec::with_capacity(length.0));
+    serde_json::to_writer(&mut json.bytes, value)?;
+    Ok(json)
+}
+
+struct ByteCount(usize);
+
+impl Write for ByteCount {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// mlock and munlock work on whole pages, and small secrets share pages with each other and with
+// the data key. Each page is counted and only unlocked once the last secret on it is dropped,
+// otherwise dropping any short lived secret would let the ones left on its page be swapped out.
+#[cfg(unix)]
+static LOCKED_PAGES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+#[cfg(unix)]
+fn page_size() -> usize {
+    static PAGE_SIZE: OnceLock<usize> = OnceLock::new();
+    *PAGE_SIZE.get_or_init(|| match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    })
+}
+
+#[cfg(unix)]
+fn pages(pointer: *const u8, length: usize) -> impl Iterator<Item = usize> {
+    let page_size = page_size();
+    let start = pointer as usize & !(page_size - 1);
+    let end = (pointer as usize + length + page_size - 1) & !(page_size - 1);
+    (start..end).step_by(page_size)
+}
+
+// mlock fails once RLIMIT_MEMLOCK is used up, the secret is then only wiped and not pinned
+#[cfg(unix)]
+fn lock_memory(pointer: *const u8, length: usize) -> bool {
+    if length == 0 {
+        return false;
+    }
+    let mut locked_pages = LOCKED_PAGES.lock().unwrap_or_else(PoisonError::into_inner);
+    for (counted, page) in pages(pointer, length).enumerate() {
+        if !locked_pages.contains_key(&page)
+            && unsafe { libc::mlock(page as *const libc::c_void, page_size()) } != 0
+        {
+            release_pages(&mut locked_pages, pages(pointer, length).take(counted));
+            return false;
+        }
+        *locked_pages.entry(page).or_insert(0) += 1;
+    }
+    true
+}
+
+#[cfg(unix)]
+fn unlock_memory(pointer: *const u8, length: usize) {
+    let mut locked_pages = LOCKED_PAGES.lock().unwrap_or_else(PoisonError::into_inner);
+    release_pages(&mut locked_pages, pages(pointer, length));
+}
+
+#[cfg(unix)]
+fn release_pages(locked_pages: &mut BTreeMap<usize, usize>, pages: impl Iterator<Item = usize>) {
+    for page in pages {
+        let Some(count) = locked_pages.get_mut(&page) else {
+            continue;
+        };
+        *count -= 1;
+        if *count == 0 {
+            locked_pages.remove(&page);
+            unsafe {
+                libc::munlock(page as *const libc::c_void, page_size());
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lock_memory(_pointer: *const u8, _length: usize) -> bool {
+    false
+}
+
+#[cfg(not(unix))]
+fn unlock_memory(_pointer: *const u8, _length: usize) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the buffer is still allocated after zeroize, only its length is reset, so it can be read
+    fn buffer_after_zeroize<T: Zeroize>(
+        secret: &mut T,
+        pointer: *const u8,
+        length: usize,
+    ) -> Vec<u8> {
+        secret.zeroize();
+        unsafe { std::slice::from_raw_parts(pointer, length) }.to_vec()
+    }
+
+    #[test]
+    fn test_secret_bytes_are_cleared() {
+        let mut secret = SecretBytes::new(vec![0xab; 64]);
+        let pointer = secret.as_ptr();
+
+        let buffer = buffer_after_zeroize(&mut secret, pointer, 64);
+        assert_eq!(buffer, vec![0u8; 64]);
+        assert!(secret.is_empty());
+    }
+
+    #[test]
+    fn test_secret_string_is_cleared() {
+        let text = "correct horse battery staple".to_string();
+        let length = text.len();
+        let mut secret = SecretString::new(text);
+        let pointer = secret.expose().as_ptr();
+        assert_eq!(secret, "correct horse battery staple");
+
+        let buffer = buffer_after_zeroize(&mut secret, pointer, length);
+        assert_eq!(buffer, vec![0u8; length]);
+        assert_eq!(secret.expose(), "");
+    }
+
+    // another secret on the same page is stood in for by locking the page again by hand
+    #[cfg(unix)]
+    #[test]
+    fn test_a_page_stays_locked_while_any_secret_on_it_lives() {
+        let secret = SecretBytes::new(vec![0xab; 16]);
+        if !secret.locked {
+            return; // RLIMIT_MEMLOCK used up, nothing is pinned to test
+        }
+        let page = pages(secret.as_ptr(), 16).next().unwrap();
+        let count = || {
+            LOCKED_PAGES
+                .lock()
+                .unwrap()
+                .get(&page)
+                .copied()
+                .unwrap_or(0)
+        };
+
+        assert!(lock_memory(secret.as_ptr(), 16));
+        assert!(count() >= 2);
+        unlock_memory(secret.as_ptr(), 16);
+        assert!(count() >= 1, "the page was unlocked under a live secret");
+    }
+
+    #[test]
+    fn test_secret_string_is_redacted_when_formatted() {
+        let secret = SecretString::from("p@ssw0rd");
+        assert_eq!(format!("{:?}", secret), "[REDACTED]");
+        assert_eq!(format!("{}", secret), "[REDACTED]");
+        assert_eq!(secret.expose(), "p@ssw0rd");
+    }
+
+    #[test]
+    fn test_json_is_written_without_reallocating() {
+        let passwords = vec![
+            SecretString::from("p@ssw0rd"),
+            SecretString::from("h\"nter2"),
+        ];
+        let json = to_json(&passwords).unwrap();
+        assert_eq!(&*json, br#"["p@ssw0rd","h\"nter2"]"#);
+        assert_eq!(json.bytes.capacity(), json.len());
+    }
+
+    #[test]
+    fn test_secret_string_round_trips_through_serde() {
+        let secret = SecretString::from("p@ssw0rd");
+        let json = serde_json::to_string(&secret).unwrap();
+        assert_eq!(json, "\"p@ssw0rd\"");
+        assert_eq!(serde_json::from_str::<SecretString>(&json).unwrap(), secret);
+    }
+}
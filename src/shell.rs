@@ -1,12 +1,40 @@
+use crate::encryption::{CryptoError, CryptoManager};
+use crate::entry_type::{EntryType, ENTRY_TYPES};
+use crate::escrow::EscrowShare;
 use crate::generator::PasswordGenerator;
 use crate::generator::PasswordGeneratorOptions;
-use crate::password_manager::PasswordManager;
+use crate::password_manager::{CredentialSet, CustomField, PasswordManager};
+use crate::recovery_code::RecoveryCode;
+use crate::secret::{SecretBytes, SecretString};
+use crate::storage::{create_private_file, FileStorage, VaultStorage};
 use crate::strings::{PROMPT_MAIN_COMMAND, PROMPT_MASTER_PASSWORD};
 use crate::utility::armor_file_exists;
 use crate::utility::copy_to_clipboard_then_clear;
 use crate::utility::get_home_dir;
+use crate::utility::parse_tags;
+use crate::utility::print_backup_list;
 use crate::utility::print_credential_list;
+use crate::utility::print_custom_field_list;
+use crate::utility::print_entry_choices;
+use crate::utility::print_key_slot_list;
+use crate::utility::print_password_history;
+use crate::utility::print_recovery_code;
 use crate::utility::prompt;
+use crate::utility::prompt_for_confirmation;
+use crate::utility::prompt_for_edit;
+use crate::utility::prompt_for_merge_conflict;
+use crate::utility::prompt_for_multiline_secret;
+use crate::utility::prompt_for_secret;
+use crate::utility::prompt_for_u32;
+use crate::utility::ArmorPassError;
+use crate::vault_format::{
+    KdfParams, KeySlotKind, ARGON2ID_DEFAULT_ITERATIONS, ARGON2ID_DEFAULT_MEMORY_KIB,
+    ARGON2ID_DEFAULT_PARALLELISM,
+};
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::Duration;
 
 enum Command {
     Create(CreatePasswordOptions),
@@ -14,21 +42,45 @@ enum Command {
     Retrieve(RetrieveSingleOptions),
     RetrieveAll(RetrieveAllOptions),
     Update(UpdatePasswordOptions),
+    Kdf,
+    Calibrate,
+    Passwd,
+    Backups,
+    Restore,
+    Slots,
+    AddSlot,
+    RevokeSlot,
+    RecoveryCode,
+    Escrow,
+    History,
+    Revert,
+    CopyField,
+    Convert,
     Quit,
 }
 
 #[derive(Default)]
 pub struct CreatePasswordOptions {
+    pub entry_type: EntryType,
     pub identifier: String,
     pub username: String,
-    pub password: String,
+    pub password: SecretString,
+    pub url: String,
+    pub notes: String,
+    pub tags: Vec<String>,
+    pub fields: Vec<CustomField>,
 }
 
+// the fields left as None are kept as they are
 #[derive(Default)]
 pub struct UpdatePasswordOptions {
     pub identifier: String,
     pub username: String,
-    pub password: String,
+    pub password: SecretString,
+    pub url: Option<String>,
+    pub notes: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub fields: Option<Vec<CustomField>>,
 }
 
 #[derive(Default)]
@@ -66,6 +118,20 @@ impl Command {
             cs if cs.eq_ignore_ascii_case("update") => {
                 Some(Command::Update(UpdatePasswordOptions::default()))
             }
+            cs if cs.eq_ignore_ascii_case("kdf") => Some(Command::Kdf),
+            cs if cs.eq_ignore_ascii_case("calibrate") => Some(Command::Calibrate),
+            cs if cs.eq_ignore_ascii_case("passwd") => Some(Command::Passwd),
+            cs if cs.eq_ignore_ascii_case("backups") => Some(Command::Backups),
+            cs if cs.eq_ignore_ascii_case("restore") => Some(Command::Restore),
+            cs if cs.eq_ignore_ascii_case("slots") => Some(Command::Slots),
+            cs if cs.eq_ignore_ascii_case("addslot") => Some(Command::AddSlot),
+            cs if cs.eq_ignore_ascii_case("revokeslot") => Some(Command::RevokeSlot),
+            cs if cs.eq_ignore_ascii_case("recoverycode") => Some(Command::RecoveryCode),
+            cs if cs.eq_ignore_ascii_case("escrow") => Some(Command::Escrow),
+            cs if cs.eq_ignore_ascii_case("history") => Some(Command::History),
+            cs if cs.eq_ignore_ascii_case("revert") => Some(Command::Revert),
+            cs if cs.eq_ignore_ascii_case("copyfield") => Some(Command::CopyField),
+            cs if cs.eq_ignore_ascii_case("convert") => Some(Command::Convert),
             cs if cs.eq_ignore_ascii_case("quit")
                 || cs.eq_ignore_ascii_case("exit")
                 || cs.eq_ignore_ascii_case("q") =>
@@ -83,21 +149,106 @@ impl Command {
             Command::Retrieve(options) => shell.handle_retrieve_command(options),
             Command::RetrieveAll(options) => shell.handle_retrieve_all_command(options),
             Command::Update(options) => shell.handle_update_command(options),
+            Command::Kdf => shell.handle_kdf_command(),
+            Command::Calibrate => shell.handle_calibrate_command(),
+            Command::Passwd => shell.handle_passwd_command(),
+            Command::Backups => shell.handle_backups_command(),
+            Command::Restore => shell.handle_restore_command(),
+            Command::Slots => shell.handle_slots_command(),
+            Command::AddSlot => shell.handle_add_slot_command(),
+            Command::RevokeSlot => shell.handle_revoke_slot_command(),
+            Command::RecoveryCode => shell.handle_recovery_code_command(),
+            Command::Escrow => shell.handle_escrow_command(),
+            Command::History => shell.handle_history_command(),
+            Command::Revert => shell.handle_revert_command(),
+            Command::CopyField => shell.handle_copy_field_command(),
+            Command::Convert => shell.handle_convert_command(),
             Command::Quit => shell.should_terminate = true,
         }
     }
 }
 
+// Where the shell keeps the vault, picked with --storage when it starts
+#[derive(Clone, Copy, PartialEq)]
+pub enum StorageKind {
+    File,
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+impl StorageKind {
+    pub fn parse(name: &str) -> Option<StorageKind> {
+        match name {
+            n if n.eq_ignore_ascii_case("file") => Some(StorageKind::File),
+            #[cfg(feature = "sqlite")]
+            n if n.eq_ignore_ascii_case("sqlite") => Some(StorageKind::Sqlite),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            StorageKind::File => "file",
+            #[cfg(feature = "sqlite")]
+            StorageKind::Sqlite => "sqlite",
+        }
+    }
+
+    fn vault_path(self) -> PathBuf {
+        let home_dir = get_home_dir()
+            .expect("[ERROR]: could not find home directory, is HOME env variable missing?");
+        match self {
+            StorageKind::File => home_dir.join(".armorpass.enc"),
+            #[cfg(feature = "sqlite")]
+            StorageKind::Sqlite => home_dir.join(".armorpass.db"),
+        }
+    }
+
+    fn open(self) -> io::Result<Box<dyn VaultStorage>> {
+        match self {
+            StorageKind::File => Ok(Box::new(FileStorage::new(self.vault_path()))),
+            #[cfg(feature = "sqlite")]
+            StorageKind::Sqlite => Ok(Box::new(crate::storage::SqliteStorage::open(
+                self.vault_path(),
+            )?)),
+        }
+    }
+
+    // the storage the convert command copies the vault into, if this build has another
+    fn other(self) -> Option<StorageKind> {
+        match self {
+            #[cfg(feature = "sqlite")]
+            StorageKind::File => Some(StorageKind::Sqlite),
+            #[cfg(not(feature = "sqlite"))]
+            StorageKind::File => None,
+            #[cfg(feature = "sqlite")]
+            StorageKind::Sqlite => Some(StorageKind::File),
+        }
+    }
+}
+
+// What the vault is unlocked with, picked with --recover or --shares when the shell starts. Every
+// line typed at the master password prompt is taken as a password, whatever it says.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Unlock {
+    MasterPassword,
+    RecoveryCode,
+    EscrowShares,
+}
+
 enum ShellState {
     Main,
     Authenticate,
+    Recover,
+    CombineShares,
     Initialization,
 }
 
 pub struct Shell {
     state: ShellState,
     should_terminate: bool,
-    password_manager: Option<PasswordManager>,
+    storage: StorageKind,
+    password_manager: Option<PasswordManager<Box<dyn VaultStorage>>>,
 }
 
 impl Default for Shell {
@@ -105,21 +256,27 @@ impl Default for Shell {
         Shell {
             should_terminate: false,
             state: ShellState::Authenticate,
+            storage: StorageKind::File,
             password_manager: None,
         }
     }
 }
 
 impl Shell {
-    pub fn new() -> Shell {
-        let initial_state = if armor_file_exists() {
-            ShellState::Authenticate
-        } else {
+    pub fn new(storage: StorageKind, unlock: Unlock) -> Shell {
+        let initial_state = if !armor_file_exists(&storage.vault_path()) {
             ShellState::Initialization
+        } else {
+            match unlock {
+                Unlock::MasterPassword => ShellState::Authenticate,
+                Unlock::RecoveryCode => ShellState::Recover,
+                Unlock::EscrowShares => ShellState::CombineShares,
+            }
         };
         Shell {
             should_terminate: false,
             state: initial_state,
+            storage,
             password_manager: None,
         }
     }
@@ -132,8 +289,14 @@ impl Shell {
                     self.handle_main_command(&input);
                 }
                 ShellState::Authenticate => {
-                    let masterpassword = prompt(PROMPT_MASTER_PASSWORD);
-                    self.handle_authentication_prompt(&masterpassword);
+                    let masterpassword = prompt_for_secret(PROMPT_MASTER_PASSWORD);
+                    self.handle_authentication_prompt(masterpassword.expose());
+                }
+                ShellState::Recover => {
+                    self.handle_recovery_prompt();
+                }
+                ShellState::CombineShares => {
+                    self.handle_escrow_prompt();
                 }
                 ShellState::Initialization => {
                     self.handle_initialization();
@@ -157,23 +320,172 @@ impl Shell {
         println!("3. Retrieve - Use this command to retrieve details of an existing item.");
         println!("4. RetrieveAll - Use this command to retrieve everything for an identifier");
         println!("5. Update - Use this command to update details of an existing item.");
-        println!("6. Quit - Use this command to exit the application.");
+        println!("6. Kdf - Use this command to view or change the key derivation settings.");
+        println!("7. Calibrate - Use this command to tune key derivation to this machine.");
+        println!("8. Passwd - Use this command to change the master password.");
+        println!("9. Backups - Use this command to list backups and set how many are kept.");
+        println!("10. Restore - Use this command to roll the vault back to a backup.");
+        println!("11. Slots - Use this command to list the key slots that can unlock the vault.");
+        println!("12. AddSlot - Use this command to let another password unlock the vault.");
+        println!("13. RevokeSlot - Use this command to remove a key slot.");
+        println!("14. RecoveryCode - Use this command to issue a new recovery code.");
+        println!("15. Escrow - Use this command to split the vault key into escrow shares.");
+        println!("16. History - Use this command to list the earlier passwords of an item.");
+        println!("17. Revert - Use this command to go back to an earlier password of an item.");
+        println!("18. CopyField - Use this command to copy one custom field of an item.");
+        println!("19. Convert - Use this command to copy the vault into the other storage.");
+        println!("20. Quit - Use this command to exit the application.");
         println!("\nType a command and press Enter to execute it.");
     }
 
     fn handle_authentication_prompt(&mut self, masterpassword: &str) {
-        let home_dir = get_home_dir()
-            .expect("[ERROR]: could not find home directory, is HOME env variable missing?");
-        let file_path = home_dir.join(".armorpass.enc");
-        match PasswordManager::new(file_path, masterpassword) {
+        let storage = self.storage;
+        let opened = self.open_with_key_file_prompt(|key_file| {
+            PasswordManager::with_storage(storage.open()?, masterpassword, key_file)
+        });
+        match opened {
+            Ok(password_manager) => {
+                if let Some(pid) = password_manager.stale_lock_pid() {
+                    println!(
+                        "[Warn]: ArmorPass (PID {}) did not exit cleanly last time, its lock has been cleared",
+                        pid
+                    );
+                }
+                self.start_session(password_manager);
+            }
+            Err(e) => match e.downcast_ref::<CryptoError>() {
+                Some(CryptoError::VaultLocked(_)) => {
+                    eprintln!("[ERROR]: {}", e);
+                    self.handle_read_only_prompt(masterpassword);
+                }
+                _ => report_failed_authentication(e.as_ref()),
+            },
+        }
+    }
+
+    fn handle_read_only_prompt(&mut self, masterpassword: &str) {
+        if !prompt_for_confirmation("Open the vault read-only instead? (default no): ") {
+            self.should_terminate = true;
+            return;
+        }
+        let storage = self.storage;
+        let opened = self.open_with_key_file_prompt(|key_file| {
+            PasswordManager::open_storage_read_only(storage.open()?, masterpassword, key_file)
+        });
+        match opened {
             Ok(password_manager) => {
-                self.state = ShellState::Main;
-                self.password_manager = Some(password_manager);
+                println!("[INFO]: Vault opened read-only, changes will not be saved");
+                self.start_session(password_manager);
+            }
+            Err(e) => report_failed_authentication(e.as_ref()),
+        }
+    }
+
+    // Opens the vault without a key file first, and only asks for one if the vault wants it
+    fn open_with_key_file_prompt<F>(
+        &mut self,
+        open: F,
+    ) -> Result<PasswordManager<Box<dyn VaultStorage>>, Box<dyn std::error::Error>>
+    where
+        F: Fn(
+            Option<&[u8]>,
+        )
+            -> Result<PasswordManager<Box<dyn VaultStorage>>, Box<dyn std::error::Error>>,
+    {
+        match open(None) {
+            Err(e)
+                if e.downcast_ref::<CryptoError>() == Some(&CryptoError::KeyFileMissingOrWrong) =>
+            {
+                let key_file_path = self.prompt_for_key_file_path();
+                let key_file = SecretBytes::new(std::fs::read(&key_file_path).map_err(|_| e)?);
+                open(Some(&key_file))
             }
+            opened => opened,
+        }
+    }
+
+    // Unlocks the vault with the recovery code and has a new master password set straight away
+    fn handle_recovery_prompt(&mut self) {
+        let input = prompt_for_secret("Please enter your recovery code (leave empty to cancel): ");
+        if input.is_empty() {
+            self.state = ShellState::Authenticate;
+            return;
+        }
+        let recovery_code = match RecoveryCode::parse(input.expose()) {
+            Ok(recovery_code) => recovery_code,
             Err(e) => {
-                eprintln!("Failed auth attempt: {}", e);
+                eprintln!("[ERROR]: {}", e);
+                return;
+            }
+        };
+        let password = self.prompt_for_new_master_password();
+
+        let opened = self.storage.open().map_err(Box::from).and_then(|storage| {
+            PasswordManager::with_storage_and_recovery_code(
+                storage,
+                &recovery_code,
+                password.expose(),
+            )
+        });
+        match opened {
+            Ok(password_manager) => {
+                println!("[INFO]: master password reset, the old one no longer unlocks the vault");
+                println!(
+                    "[INFO]: the recovery code still works, use recoverycode to issue a new one"
+                );
+                self.start_session(password_manager);
+            }
+            Err(e) => eprintln!("[ERROR]: could not recover the vault: {}", e),
+        }
+    }
+
+    // Collects escrow shares until there are enough to unlock the vault, then has a new master
+    // password set straight away
+    fn handle_escrow_prompt(&mut self) {
+        let mut shares: Vec<EscrowShare> = Vec::new();
+        loop {
+            let input = prompt_for_secret(&format!(
+                "Enter escrow share {}, or the path of a file holding it (leave empty to cancel): ",
+                shares.len() + 1
+            ));
+            if input.is_empty() {
+                self.state = ShellState::Authenticate;
+                return;
+            }
+            let share = match std::fs::read_to_string(input.expose()) {
+                Ok(contents) => EscrowShare::parse(SecretString::new(contents).expose()),
+                Err(_) => EscrowShare::parse(input.expose()),
+            };
+            match share {
+                Ok(share) => shares.push(share),
+                Err(e) => {
+                    eprintln!("[ERROR]: {}", e);
+                    continue;
+                }
+            }
+            if shares.len() >= shares[0].threshold() as usize {
+                break;
             }
         }
+        let password = self.prompt_for_new_master_password();
+
+        let opened = self.storage.open().map_err(Box::from).and_then(|storage| {
+            PasswordManager::with_storage_and_escrow_shares(storage, &shares, password.expose())
+        });
+        match opened {
+            Ok(password_manager) => {
+                println!("[INFO]: master password reset, the old one no longer unlocks the vault");
+                println!("[INFO]: the escrow shares still work, use escrow to split a new set");
+                self.start_session(password_manager);
+            }
+            Err(e) => eprintln!("[ERROR]: could not recover the vault: {}", e),
+        }
+    }
+
+    fn start_session(&mut self, mut password_manager: PasswordManager<Box<dyn VaultStorage>>) {
+        password_manager.set_conflict_resolver(prompt_for_merge_conflict);
+        self.state = ShellState::Main;
+        self.password_manager = Some(password_manager);
     }
 
     fn handle_initialization(&mut self) {
@@ -181,19 +493,53 @@ impl Shell {
         let mut input;
         let mut input2;
         loop {
-            input = prompt("Please set your password");
-            input2 = prompt("Please re-enter your password for confirmation");
+            input = prompt_for_secret("Please set your password");
+            input2 = prompt_for_secret("Please re-enter your password for confirmation");
             if input == input2 {
                 break;
             }
         }
-        let home_dir = get_home_dir()
-            .expect("[ERROR]: could not find home directory, is HOME env variable missing?");
-        let file_path = home_dir.join(".armorpass.enc");
-        match PasswordManager::new(file_path, &input) {
-            Ok(password_manager) => {
-                self.state = ShellState::Main;
-                self.password_manager = Some(password_manager);
+
+        let mut key_file = None;
+        if prompt_for_confirmation(
+            "Require a key file as well as the password to unlock? (default no): ",
+        ) {
+            let key_file_path = self.prompt_for_key_file_path();
+            match CryptoManager::create_key_file(&key_file_path) {
+                Ok(contents) => {
+                    println!(
+                        "[INFO]: key file written to {}, the vault can not be opened without it so keep a copy somewhere safe",
+                        key_file_path.display()
+                    );
+                    key_file = Some(contents);
+                }
+                Err(e) => {
+                    eprintln!(
+                        "[ERROR]: could not create key file {}: {}",
+                        key_file_path.display(),
+                        e
+                    );
+                    return;
+                }
+            }
+        }
+
+        let with_recovery_code = prompt_for_confirmation(
+            "Print a recovery code that can reset a forgotten master password? (default no): ",
+        );
+
+        let opened = self.storage.open().map_err(Box::from).and_then(|storage| {
+            PasswordManager::with_storage(storage, input.expose(), key_file.as_deref())
+        });
+        match opened {
+            Ok(mut password_manager) => {
+                if with_recovery_code {
+                    match password_manager.add_recovery_code(input.expose()) {
+                        Ok(recovery_code) => print_recovery_code(&recovery_code),
+                        Err(e) => eprintln!("[ERROR]: could not create a recovery code: {}", e),
+                    }
+                }
+                self.start_session(password_manager);
             }
             Err(e) => {
                 eprintln!("Failed auth attempt: {}", e);
@@ -202,25 +548,68 @@ impl Shell {
     }
 
     fn handle_create_command(&mut self, options: &mut CreatePasswordOptions) {
+        options.entry_type = self.prompt_for_entry_type();
+        let schema = options.entry_type.schema();
         options.identifier = self.prompt_for_identifier();
-        options.username = self.prompt_for_username();
+        if options.entry_type == EntryType::Login {
+            options.username = self.prompt_for_username();
+        } else {
+            if let Some(label) = schema.username {
+                options.username = prompt(&format!("Enter the {}: ", label.to_lowercase()));
+            }
+            options.password = self.prompt_for_main_secret(options.entry_type);
+        }
+        for field_schema in schema.fields {
+            let value_prompt = format!(
+                "Enter the {}{}: ",
+                field_schema.name,
+                if field_schema.required {
+                    ""
+                } else {
+                    " (optional)"
+                }
+            );
+            let value = if field_schema.concealed {
+                prompt_for_secret(&value_prompt)
+            } else {
+                SecretString::from(prompt(&value_prompt))
+            };
+            if !value.is_empty() {
+                options.fields.push(CustomField {
+                    name: field_schema.name.to_string(),
+                    value,
+                    concealed: field_schema.concealed,
+                });
+            }
+        }
+        if schema.has_url {
+            options.url = prompt("Enter the login url (optional): ");
+        }
+        options.notes = prompt("Enter any notes (optional): ");
+        options.tags = parse_tags(&prompt("Enter tags, separated by commas (optional): "));
+        self.prompt_for_new_custom_fields(&mut options.fields);
 
-        let mut password_generator_options = PasswordGeneratorOptions::default();
-        password_generator_options.prompt_for_options();
-        let password_generator = PasswordGenerator::new(&password_generator_options);
-        options.password = password_generator.generate();
+        if options.entry_type == EntryType::Login {
+            let mut password_generator_options = PasswordGeneratorOptions::default();
+            password_generator_options.prompt_for_options();
+            let password_generator = PasswordGenerator::new(&password_generator_options);
+            options.password = password_generator.generate();
+        }
 
         let password_manager = self.get_password_manager_mut();
         let _ = password_manager.store_password(options);
     }
 
     fn handle_delete_command(&mut self, options: &mut DeletePasswordOptions) {
-        options.identifier = self.prompt_for_identifier();
-        options.username = self.prompt_for_username();
+        let Some(credential) = self.prompt_for_existing_credential() else {
+            return;
+        };
+        options.identifier = credential.identifier;
+        options.username = credential.username;
 
         let password_manager = self.get_password_manager_mut();
 
-        match password_manager.delete_credential(options) {
+        match password_manager.delete_credential_by_id(credential.id) {
             Ok(_) => {
                 println!(
                     "successfully deleted credential with identifer: {} and username: {}",
@@ -243,45 +632,653 @@ impl Shell {
     }
 
     fn handle_retrieve_command(&mut self, options: &mut RetrieveSingleOptions) {
-        options.identifier = self.prompt_for_identifier();
-        options.username = self.prompt_for_username();
+        let Some(credential) = self.prompt_for_existing_credential() else {
+            return;
+        };
+        options.identifier = credential.identifier;
+        options.username = credential.username;
+        copy_to_clipboard_then_clear(credential.password.expose());
         let password_manager = self.get_password_manager_mut();
-        match password_manager.retrieve_credential(options) {
-            Some(credential) => {
-                copy_to_clipboard_then_clear(&credential.password);
-            }
-            None => eprintln!(
-                "[Warn]: Could not find a record for that identifier/username combination"
-            ),
+        if password_manager.is_read_only() {
+            return;
+        }
+        if let Err(e) = password_manager.record_use_by_id(credential.id) {
+            eprintln!("[Warn]: Could not record that the password was used: {}", e);
         }
     }
 
     fn handle_update_command(&mut self, options: &mut UpdatePasswordOptions) {
-        options.identifier = self.prompt_for_identifier();
-        options.username = self.prompt_for_username();
+        let Some(current) = self.prompt_for_existing_credential() else {
+            return;
+        };
+        options.identifier = current.identifier.clone();
+        options.username = current.username.clone();
+        print_credential_list(vec![current.mask()]);
 
-        let mut password_generator_options = PasswordGeneratorOptions::default();
-        password_generator_options.prompt_for_options();
-        let password_generator = PasswordGenerator::new(&password_generator_options);
-        options.password = password_generator.generate();
+        if current.entry_type.schema().has_url {
+            options.url = prompt_for_edit("login url", &current.url);
+        }
+        options.notes = prompt_for_edit("note", &current.notes);
+        options.tags = prompt_for_edit(
+            "set of tags, separated by commas,",
+            &current.tags.join(", "),
+        )
+        .map(|tags| parse_tags(&tags));
+        options.fields = Some(self.prompt_for_custom_field_edits(&current.fields));
+
+        let password_label = current.entry_type.schema().password.to_lowercase();
+        let replacement = if current.entry_type != EntryType::Login {
+            prompt_for_confirmation(&format!("Change the {}? (default no): ", password_label))
+                .then(|| self.prompt_for_main_secret(current.entry_type))
+        } else if prompt_for_confirmation("Generate a new password? (default no): ") {
+            let mut password_generator_options = PasswordGeneratorOptions::default();
+            password_generator_options.prompt_for_options();
+            let password_generator = PasswordGenerator::new(&password_generator_options);
+            Some(password_generator.generate())
+        } else {
+            None
+        };
+        options.password = password_for_update(&current, replacement);
 
         let password_manager = self.get_password_manager_mut();
 
-        if password_manager.update_password(options).is_ok() {
-            println!(
-                "succesfully updated password for identifier: {} with username: {}",
+        match password_manager.update_credential_by_id(current.id, options) {
+            Ok(_) => println!(
+                "succesfully updated {} for identifier: {} with username: {}",
+                password_label,
                 options.identifier.as_str(),
                 options.username.as_str()
+            ),
+            Err(e) => eprintln!("[ERROR]: could not update the item: {}", e),
+        }
+    }
+
+    fn handle_history_command(&mut self) {
+        let Some(credential) = self.prompt_for_existing_credential() else {
+            return;
+        };
+        if credential.history.is_empty() {
+            println!("[INFO]: there are no earlier passwords for that item");
+        } else {
+            print_password_history(
+                credential
+                    .history
+                    .iter()
+                    .map(|entry| entry.mask())
+                    .collect(),
+            );
+        }
+
+        let password_manager = self.get_password_manager_mut();
+        let length = password_manager.password_history_length();
+        if let Some(new_length) = prompt_for_u32(&format!(
+            "Enter the number of earlier passwords each item keeps (currently {}, leave empty to keep): ",
+            length
+        )) {
+            match password_manager.set_password_history_length(new_length) {
+                Ok(_) => println!("[INFO]: keeping {} earlier passwords per item", new_length),
+                Err(e) => eprintln!("[ERROR]: could not change the password history length: {}", e),
+            }
+        }
+    }
+
+    fn handle_revert_command(&mut self) {
+        let Some(credential) = self.prompt_for_existing_credential() else {
+            return;
+        };
+        if credential.history.is_empty() {
+            println!("[INFO]: there are no earlier passwords to go back to");
+            return;
+        }
+        print_password_history(
+            credential
+                .history
+                .iter()
+                .map(|entry| entry.mask())
+                .collect(),
+        );
+
+        let index = match prompt_for_u32("Enter the number of the password to go back to: ") {
+            Some(number) if number > 0 => number as usize - 1,
+            Some(_) => {
+                eprintln!("[ERROR]: {}", ArmorPassError::NoHistoryEntryFound);
+                return;
+            }
+            None => return,
+        };
+        let password_manager = self.get_password_manager_mut();
+        match password_manager.revert_password(credential.id, index) {
+            Ok(_) => println!(
+                "[INFO]: went back to password {}, the one it replaced is now first in the history",
+                index + 1
+            ),
+            Err(e) => eprintln!("[ERROR]: could not go back to that password: {}", e),
+        }
+    }
+
+    fn handle_copy_field_command(&mut self) {
+        let Some(credential) = self.prompt_for_existing_credential() else {
+            return;
+        };
+        if credential.fields.is_empty() {
+            println!("[INFO]: that item has no custom fields");
+            return;
+        }
+        print_custom_field_list(credential.fields.iter().map(CustomField::mask).collect());
+
+        let Some(number) = prompt_for_u32("Enter the number of the field to copy: ") else {
+            return;
+        };
+        match number
+            .checked_sub(1)
+            .and_then(|index| credential.fields.get(index as usize))
+        {
+            Some(field) => copy_to_clipboard_then_clear(field.value.expose()),
+            None => eprintln!("[Warn]: Could not find a field with that number"),
+        }
+    }
+
+    fn handle_kdf_command(&mut self) {
+        let password_manager = self.get_password_manager_mut();
+        println!(
+            "[INFO]: current key derivation settings: {}",
+            password_manager.kdf_params()
+        );
+        if !prompt_for_confirmation("Do you want to change them? (default no): ") {
+            return;
+        }
+
+        let (memory_kib, iterations, parallelism) = match password_manager.kdf_params() {
+            KdfParams::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => (*memory_kib, *iterations, *parallelism),
+            // pbkdf2 vaults are moved over to argon2id, starting from the recommended settings
+            KdfParams::Pbkdf2HmacSha256 { .. } => (
+                ARGON2ID_DEFAULT_MEMORY_KIB,
+                ARGON2ID_DEFAULT_ITERATIONS,
+                ARGON2ID_DEFAULT_PARALLELISM,
+            ),
+        };
+        let memory_mib = prompt_for_u32(&format!(
+            "Enter argon2id memory in MiB (default {}): ",
+            memory_kib / 1024
+        ))
+        .unwrap_or(memory_kib / 1024);
+        let iterations = prompt_for_u32(&format!(
+            "Enter argon2id iterations (default {}): ",
+            iterations
+        ))
+        .unwrap_or(iterations);
+        let parallelism = prompt_for_u32(&format!(
+            "Enter argon2id parallelism (default {}): ",
+            parallelism
+        ))
+        .unwrap_or(parallelism);
+        let kdf = KdfParams::Argon2id {
+            memory_kib: memory_mib.saturating_mul(1024),
+            iterations,
+            parallelism,
+        };
+
+        self.apply_kdf(kdf);
+    }
+
+    fn handle_calibrate_command(&mut self) {
+        let target_ms = prompt_for_u32("Enter target unlock time in milliseconds (default 1000): ")
+            .unwrap_or(1000);
+        println!("[INFO]: benchmarking key derivation, this can take a few seconds...");
+        let (kdf, unlock_time) =
+            match CryptoManager::calibrate_kdf(Duration::from_millis(target_ms.into())) {
+                Ok(calibration) => calibration,
+                Err(e) => {
+                    eprintln!("[ERROR]: calibration failed: {}", e);
+                    return;
+                }
+            };
+        println!(
+            "[INFO]: {} unlocks in {} ms on this machine",
+            kdf,
+            unlock_time.as_millis()
+        );
+        if prompt_for_confirmation("Apply these settings to the vault? (default no): ") {
+            self.apply_kdf(kdf);
+        }
+    }
+
+    fn handle_passwd_command(&mut self) {
+        let current_password = prompt_for_secret(PROMPT_MASTER_PASSWORD);
+        let mut new_password;
+        loop {
+            new_password = prompt_for_secret("Please enter your new master password: ");
+            let confirmation =
+                prompt_for_secret("Please re-enter your new master password for confirmation: ");
+            if new_password == confirmation {
+                break;
+            }
+            eprintln!("[ERROR]: passwords did not match, try again");
+        }
+
+        let password_manager = self.get_password_manager_mut();
+        match password_manager
+            .change_master_password(current_password.expose(), new_password.expose())
+        {
+            Ok(_) => println!("[INFO]: master password changed"),
+            Err(ArmorPassError::WrongMasterPassword) => {
+                eprintln!("[ERROR]: master password is incorrect, it has not been changed")
+            }
+            Err(e) => eprintln!("[ERROR]: could not change master password: {:?}", e),
+        }
+    }
+
+    fn handle_backups_command(&mut self) {
+        let password_manager = self.get_password_manager_mut();
+        if !password_manager.keeps_backups() {
+            println!("[INFO]: a SQLite vault keeps no backups, back up the database file instead");
+            return;
+        }
+        match password_manager.backups() {
+            Ok(backups) if backups.is_empty() => println!("[INFO]: there are no backups yet"),
+            Ok(backups) => print_backup_list(&backups),
+            Err(e) => eprintln!("[ERROR]: {}", e),
+        }
+
+        let generations = password_manager.backup_generations();
+        if let Some(new_generations) = prompt_for_u32(&format!(
+            "Enter the number of backups to keep (currently {}, leave empty to keep): ",
+            generations
+        )) {
+            match password_manager.set_backup_generations(new_generations) {
+                Ok(_) => println!("[INFO]: keeping {} backups", new_generations),
+                Err(e) => eprintln!("[ERROR]: could not change the number of backups: {}", e),
+            }
+        }
+    }
+
+    fn handle_restore_command(&mut self) {
+        let password_manager = self.get_password_manager_mut();
+        if !password_manager.keeps_backups() {
+            println!("[INFO]: a SQLite vault keeps no backups to restore");
+            return;
+        }
+        match password_manager.backups() {
+            Ok(backups) if backups.is_empty() => {
+                println!("[INFO]: there are no backups to restore");
+                return;
+            }
+            Ok(backups) => print_backup_list(&backups),
+            Err(e) => {
+                eprintln!("[ERROR]: {}", e);
+                return;
+            }
+        }
+
+        let generation = match prompt_for_u32("Enter the generation to restore: ") {
+            Some(generation) => generation,
+            None => return,
+        };
+        let masterpassword = prompt_for_secret(PROMPT_MASTER_PASSWORD);
+        match password_manager.restore_backup(generation, masterpassword.expose()) {
+            Ok(_) => println!(
+                "[INFO]: restored backup {}, the vault it replaced is now backup 1",
+                generation
+            ),
+            Err(ArmorPassError::WrongMasterPassword) => {
+                eprintln!("[ERROR]: that backup does not decrypt with the current master password")
+            }
+            Err(e) => eprintln!("[ERROR]: could not restore backup: {}", e),
+        }
+    }
+
+    // Copies the vault into the other kind of storage, which must not hold a vault yet. This
+    // vault is left as it was, the shell goes on using it until started with the other --storage.
+    fn handle_convert_command(&mut self) {
+        let Some(target) = self.storage.other() else {
+            eprintln!(
+                "[ERROR]: there is no other storage to convert to, build with --features sqlite"
+            );
+            return;
+        };
+        let path = target.vault_path();
+        let storage = match target.open() {
+            Ok(storage) => storage,
+            Err(e) => {
+                eprintln!("[ERROR]: could not open {}: {}", path.display(), e);
+                return;
+            }
+        };
+        match self.get_password_manager_mut().convert_into(storage) {
+            Ok(_) => println!(
+                "[INFO]: vault copied to {}, start ArmorPass with --storage {} to use it",
+                path.display(),
+                target.name()
+            ),
+            Err(e) => eprintln!("[ERROR]: could not convert the vault: {}", e),
+        }
+    }
+
+    fn handle_slots_command(&mut self) {
+        let password_manager = self.get_password_manager_mut();
+        print_key_slot_list(
+            password_manager.key_slots(),
+            password_manager.unlocked_key_slot().id,
+        );
+    }
+
+    fn handle_add_slot_command(&mut self) {
+        let label = prompt("Enter a label for the new key slot: ");
+        let mut password;
+        loop {
+            password = prompt_for_secret("Please enter the password for the new key slot: ");
+            let confirmation = prompt_for_secret("Please re-enter the password for confirmation: ");
+            if password == confirmation {
+                break;
+            }
+            eprintln!("[ERROR]: passwords did not match, try again");
+        }
+        let masterpassword = prompt_for_secret(PROMPT_MASTER_PASSWORD);
+
+        let password_manager = self.get_password_manager_mut();
+        match password_manager.add_password_slot(masterpassword.expose(), &label, password.expose())
+        {
+            Ok(id) => println!("[INFO]: added key slot {}", id),
+            Err(ArmorPassError::WrongMasterPassword) => {
+                eprintln!("[ERROR]: master password is incorrect, no key slot was added")
+            }
+            Err(e) => eprintln!("[ERROR]: could not add key slot: {}", e),
+        }
+    }
+
+    fn handle_revoke_slot_command(&mut self) {
+        self.handle_slots_command();
+        let id = match prompt_for_u32("Enter the id of the key slot to revoke: ") {
+            Some(id) => id,
+            None => return,
+        };
+        let masterpassword = prompt_for_secret(PROMPT_MASTER_PASSWORD);
+
+        let password_manager = self.get_password_manager_mut();
+        match password_manager.revoke_key_slot(masterpassword.expose(), id) {
+            Ok(_) => println!("[INFO]: revoked key slot {}", id),
+            Err(ArmorPassError::WrongMasterPassword) => {
+                eprintln!("[ERROR]: master password is incorrect, the key slot was kept")
+            }
+            Err(e) => eprintln!("[ERROR]: could not revoke key slot: {}", e),
+        }
+    }
+
+    fn handle_recovery_code_command(&mut self) {
+        let password_manager = self.get_password_manager_mut();
+        let replaces_a_code = password_manager
+            .key_slots()
+            .iter()
+            .any(|slot| slot.kind == KeySlotKind::RecoveryCode);
+        if replaces_a_code
+            && !prompt_for_confirmation(
+                "This replaces your current recovery code, continue? (default no): ",
             )
+        {
+            return;
+        }
+        let masterpassword = prompt_for_secret(PROMPT_MASTER_PASSWORD);
+
+        let password_manager = self.get_password_manager_mut();
+        match password_manager.add_recovery_code(masterpassword.expose()) {
+            Ok(recovery_code) => print_recovery_code(&recovery_code),
+            Err(ArmorPassError::WrongMasterPassword) => {
+                eprintln!("[ERROR]: master password is incorrect, no recovery code was issued")
+            }
+            Err(e) => eprintln!("[ERROR]: could not create a recovery code: {}", e),
         }
     }
 
-    fn get_password_manager_mut(&mut self) -> &mut PasswordManager {
+    fn handle_escrow_command(&mut self) {
+        let count = match prompt_for_u32("How many escrow shares should be made? ") {
+            Some(count) => count,
+            None => return,
+        };
+        let threshold = match prompt_for_u32("How many of them should it take to unlock? ") {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let (Ok(count), Ok(threshold)) = (u8::try_from(count), u8::try_from(threshold)) else {
+            eprintln!("[ERROR]: at most 255 escrow shares can be made");
+            return;
+        };
+        let to_files = prompt_for_confirmation(
+            "Write each share to a file instead of printing them? (default no): ",
+        );
+        let masterpassword = prompt_for_secret(PROMPT_MASTER_PASSWORD);
+
+        let password_manager = self.get_password_manager_mut();
+        let shares = match password_manager.split_into_escrow_shares(
+            masterpassword.expose(),
+            threshold,
+            count,
+        ) {
+            Ok(shares) => shares,
+            Err(ArmorPassError::WrongMasterPassword) => {
+                eprintln!("[ERROR]: master password is incorrect, no escrow shares were made");
+                return;
+            }
+            Err(e) => {
+                eprintln!("[ERROR]: could not split the vault key: {}", e);
+                return;
+            }
+        };
+        println!(
+            "[INFO]: any {} of these {} shares unlock the vault, shares from an earlier split no longer do",
+            threshold, count
+        );
+        for share in &shares {
+            if to_files {
+                self.write_escrow_share(share);
+            } else {
+                println!("\n    share {}: {}", share.index(), share);
+            }
+        }
+        println!();
+    }
+
+    fn write_escrow_share(&mut self, share: &EscrowShare) {
+        let home_dir = get_home_dir()
+            .expect("[ERROR]: could not find home directory, is HOME env variable missing?");
+        let default_path = home_dir.join(format!("armorpass-share-{}.txt", share.index()));
+        loop {
+            let input = prompt(&format!(
+                "Enter the path to write share {} to (default {}): ",
+                share.index(),
+                default_path.display()
+            ));
+            let path = if input.trim().is_empty() {
+                default_path.clone()
+            } else {
+                PathBuf::from(input.trim())
+            };
+            let written = create_private_file(&path)
+                .and_then(|mut file| writeln!(file, "{}", share).and_then(|_| file.sync_all()));
+            match written {
+                Ok(_) => {
+                    println!(
+                        "[INFO]: share {} written to {}",
+                        share.index(),
+                        path.display()
+                    );
+                    return;
+                }
+                Err(e) => eprintln!("[ERROR]: could not write {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    fn prompt_for_new_master_password(&mut self) -> SecretString {
+        loop {
+            let password = prompt_for_secret("Please set a new master password: ");
+            let confirmation =
+                prompt_for_secret("Please re-enter the new master password for confirmation: ");
+            if password == confirmation {
+                return password;
+            }
+            eprintln!("[ERROR]: passwords did not match, try again");
+        }
+    }
+
+    fn apply_kdf(&mut self, kdf: KdfParams) {
+        let masterpassword = prompt_for_secret(PROMPT_MASTER_PASSWORD);
+        let password_manager = self.get_password_manager_mut();
+        match password_manager.change_kdf(masterpassword.expose(), kdf) {
+            Ok(_) => println!(
+                "[INFO]: vault re-encrypted with {}",
+                password_manager.kdf_params()
+            ),
+            Err(ArmorPassError::WrongMasterPassword) => {
+                eprintln!("[ERROR]: master password is incorrect, settings left unchanged")
+            }
+            Err(e) => eprintln!("[ERROR]: could not change key derivation settings: {:?}", e),
+        }
+    }
+
+    fn get_password_manager_mut(&mut self) -> &mut PasswordManager<Box<dyn VaultStorage>> {
         self.password_manager
             .as_mut()
             .expect("[ERROR]: havent yet unencrypted file for operation, authentication required")
     }
 
+    // Asks for an identifier, and which item is meant when several share it. Notes and the other
+    // kinds without a username can share one, so items are picked from a list rather than by
+    // username, and go by their id from then on.
+    fn prompt_for_existing_credential(&mut self) -> Option<CredentialSet> {
+        let options = RetrieveAllOptions {
+            identifier: self.prompt_for_identifier(),
+        };
+        let mut candidates: Vec<CredentialSet> = self
+            .get_password_manager_mut()
+            .retrieve_all_credentials(&options)
+            .into_iter()
+            .cloned()
+            .collect();
+        match candidates.len() {
+            0 => {
+                eprintln!("[Warn]: Could not find any records for that identifier");
+                None
+            }
+            1 => candidates.pop(),
+            _ => {
+                print_entry_choices(candidates.iter().map(CredentialSet::mask).collect());
+                let chosen = prompt_for_u32("Enter the number of the item: ")?
+                    .checked_sub(1)
+                    .and_then(|index| candidates.get(index as usize))
+                    .cloned();
+                if chosen.is_none() {
+                    eprintln!("[Warn]: Could not find an item with that number");
+                }
+                chosen
+            }
+        }
+    }
+
+    fn prompt_for_entry_type(&mut self) -> EntryType {
+        let names: Vec<&str> = ENTRY_TYPES.iter().map(EntryType::name).collect();
+        loop {
+            let input = prompt(&format!(
+                "Enter the kind of item, one of {} (default login): ",
+                names.join(", ")
+            ));
+            if input.is_empty() {
+                return EntryType::Login;
+            }
+            match EntryType::parse(&input) {
+                Some(entry_type) => return entry_type,
+                None => eprintln!("[ERROR]: there is no kind of item called {}", input),
+            }
+        }
+    }
+
+    // The secret a kind of item other than a login keeps in place of a password. Notes run over
+    // several lines and private keys are read from their file.
+    fn prompt_for_main_secret(&mut self, entry_type: EntryType) -> SecretString {
+        let schema = entry_type.schema();
+        match entry_type {
+            EntryType::SecureNote => prompt_for_multiline_secret(
+                "Enter the note, then a line holding a lone . to end it:",
+            ),
+            EntryType::SshKey => {
+                let path = prompt("Enter the path of the private key file: ");
+                match fs::read_to_string(&path) {
+                    Ok(private_key) => SecretString::new(private_key),
+                    Err(e) => {
+                        eprintln!("[ERROR]: could not read {}: {}", path, e);
+                        SecretString::default()
+                    }
+                }
+            }
+            _ => prompt_for_secret(&format!(
+                "Enter the {}{}: ",
+                schema.password.to_lowercase(),
+                if schema.password_required {
+                    ""
+                } else {
+                    " (optional)"
+                }
+            )),
+        }
+    }
+
+    // Asks for fields to add one after another, until an empty name is entered
+    fn prompt_for_new_custom_fields(&mut self, fields: &mut Vec<CustomField>) {
+        loop {
+            let name =
+                prompt("Enter the name of a custom field (optional, leave empty when done): ");
+            if name.is_empty() {
+                return;
+            }
+            if fields.iter().any(|field| field.name == name) {
+                eprintln!("[ERROR]: {}", ArmorPassError::InvalidCustomFieldName);
+                continue;
+            }
+            let concealed =
+                prompt_for_confirmation("Conceal its value like a password? (default no): ");
+            let value_prompt = format!("Enter the value of {}: ", name);
+            let value = if concealed {
+                prompt_for_secret(&value_prompt)
+            } else {
+                SecretString::from(prompt(&value_prompt))
+            };
+            fields.push(CustomField {
+                name,
+                value,
+                concealed,
+            });
+        }
+    }
+
+    // Goes through the fields an item has, keeping, changing or removing each, then asks for new ones
+    fn prompt_for_custom_field_edits(&mut self, current: &[CustomField]) -> Vec<CustomField> {
+        let mut fields = Vec::new();
+        for field in current {
+            let value_prompt = format!(
+                "Enter a new value for {} (leave empty to keep it or enter - to remove it): ",
+                field.name
+            );
+            let value = if field.concealed {
+                prompt_for_secret(&value_prompt)
+            } else {
+                SecretString::from(prompt(&value_prompt))
+            };
+            match value.expose() {
+                "" => fields.push(field.clone()),
+                "-" => (),
+                _ => fields.push(CustomField {
+                    value,
+                    ..field.clone()
+                }),
+            }
+        }
+        self.prompt_for_new_custom_fields(&mut fields);
+        fields
+    }
+
     fn prompt_for_identifier(&mut self) -> String {
         let mut identifer: String = String::new();
         while identifer.is_empty() {
@@ -293,4 +1290,85 @@ impl Shell {
     fn prompt_for_username(&mut self) -> String {
         prompt("Enter a username: ")
     }
+
+    fn prompt_for_key_file_path(&mut self) -> PathBuf {
+        let home_dir = get_home_dir()
+            .expect("[ERROR]: could not find home directory, is HOME env variable missing?");
+        let default_path = home_dir.join(".armorpass.key");
+        let input = prompt(&format!(
+            "Enter the path of the key file (default {}): ",
+            default_path.display()
+        ));
+        if input.trim().is_empty() {
+            default_path
+        } else {
+            PathBuf::from(input.trim())
+        }
+    }
+}
+
+// The password an update saves. The entry keeps its own unless a new one was asked for, so an
+// update that only edits the url, notes or tags never replaces it.
+fn password_for_update(current: &CredentialSet, replacement: Option<SecretString>) -> SecretString {
+    replacement.unwrap_or_else(|| current.password.clone())
+}
+
+fn report_failed_authentication(error: &(dyn std::error::Error + 'static)) {
+    match error.downcast_ref::<CryptoError>() {
+        Some(CryptoError::WrongMasterPassword) => eprintln!(
+            "Failed auth attempt: wrong master password, please try again (or start ArmorPass with --recover to use your recovery code, or --shares to combine escrow shares)"
+        ),
+        Some(CryptoError::KeyFileMissingOrWrong) => {
+            eprintln!("Failed auth attempt: key file missing or wrong")
+        }
+        _ => eprintln!("Failed auth attempt: {}", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_update_of_metadata_alone_keeps_the_password() {
+        let mut password_manager =
+            PasswordManager::with_storage(MemoryStorage::new(), "master password", None).unwrap();
+        let create = CreatePasswordOptions {
+            identifier: "website.com".to_string(),
+            username: "user".to_string(),
+            password: SecretString::from("the real site password"),
+            ..Default::default()
+        };
+        password_manager.store_password(&create).unwrap();
+        let current = password_manager
+            .retrieve_credential(&RetrieveSingleOptions {
+                identifier: "website.com".to_string(),
+                username: "user".to_string(),
+            })
+            .cloned()
+            .unwrap();
+
+        // as handle_update_command builds it when only the notes and tags are edited and the
+        // password prompt is left at its default
+        let update = UpdatePasswordOptions {
+            identifier: current.identifier.clone(),
+            username: current.username.clone(),
+            password: password_for_update(&current, None),
+            notes: Some("recovery email changed".to_string()),
+            tags: Some(vec!["work".to_string()]),
+            ..Default::default()
+        };
+        password_manager.update_password(&update).unwrap();
+
+        let updated = password_manager
+            .retrieve_credential_by_id(current.id)
+            .unwrap();
+        assert_eq!(
+            updated.password,
+            SecretString::from("the real site password")
+        );
+        assert_eq!(updated.notes, "recovery email changed");
+        assert!(updated.history.is_empty());
+    }
 }
@@ -1 +1,931 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
 
+use crate::encryption::CryptoError;
+
+#[derive(Debug)]
+pub struct StorageMetadata {
+    pub modified: SystemTime,
+    pub size: u64,
+}
+
+// Where the encrypted vault lives. The vault is only ever read and written whole, the storage
+// never sees anything but ciphertext.
+pub trait VaultStorage {
+    // an io::Error of kind NotFound if nothing has been stored yet
+    fn load(&self) -> io::Result<Vec<u8>>;
+
+    // Replaces the vault in one step, a failure part way through leaves the old one as it was.
+    // In storage that keeps backups, the vault being replaced becomes backup generation 1, older
+    // generations move up one and any beyond `backup_generations` are dropped.
+    fn store(&mut self, contents: &[u8], backup_generations: u32) -> io::Result<()>;
+
+    // Replaces the vault in one step like store, but leaves the backups as they are
+    fn store_in_place(&mut self, contents: &[u8]) -> io::Result<()>;
+
+    fn metadata(&self) -> io::Result<StorageMetadata>;
+
+    // Takes the single writer lock, held until unlock or the storage is dropped. Fails with
+    // CryptoError::VaultLocked while another instance holds it.
+    fn lock(&mut self) -> Result<(), Box<dyn std::error::Error>>;
+
+    fn unlock(&mut self);
+
+    fn is_locked(&self) -> bool;
+
+    // pid of an instance that crashed while holding the lock, its lock was cleared on the way in
+    fn stale_lock_pid(&self) -> Option<u32> {
+        None
+    }
+
+    fn load_backup(&self, generation: u32) -> io::Result<Vec<u8>>;
+
+    fn backup_metadata(&self, generation: u32) -> io::Result<StorageMetadata>;
+
+    // Writes over a backup generation in one step, keeping the time it was taken. Only used to
+    // re-seal a backup with the vault's current key slots.
+    fn replace_backup(&mut self, generation: u32, contents: &[u8]) -> io::Result<()>;
+
+    // false for storage that never keeps backups, whatever backup_generations is set to
+    fn keeps_backups(&self) -> bool {
+        true
+    }
+
+    // Storage that keeps each entry in a row of its own, next to the vault rather than inside it,
+    // returns itself here
+    fn entries(&self) -> Option<&dyn EntryStorage> {
+        None
+    }
+
+    fn entries_mut(&mut self) -> Option<&mut dyn EntryStorage> {
+        None
+    }
+}
+
+// An entry sealed on its own. Nothing about it is stored in the clear, it is found by a keyed HMAC
+// of its id, see CryptoManager::entry_lookup.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredEntry {
+    pub lookup: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+// Rows of entries, so changing one entry writes that entry alone instead of the whole vault
+pub trait EntryStorage {
+    fn load_entries(&self) -> io::Result<Vec<StoredEntry>>;
+
+    // Deletes the entries with these lookups, writes the entries, each in place of any entry with
+    // the same lookup, and replaces the manifest, in one step. A renamed entry is both, its old row
+    // is never left behind or lost on its own.
+    fn write_entries(
+        &mut self,
+        entries: &[StoredEntry],
+        deleted: &[Vec<u8>],
+        manifest: &[u8],
+    ) -> io::Result<()>;
+
+    // what binds the rows together as a set, see CryptoManager::entries_manifest
+    fn load_manifest(&self) -> io::Result<Option<Vec<u8>>>;
+}
+
+// The vault as a file on the local disk, with its backups next to it as vault.1 (newest) up to
+// vault.N (oldest) and its lock in vault.lock
+pub struct FileStorage {
+    path: PathBuf,
+    lock: Option<VaultLock>,
+}
+
+impl FileStorage {
+    pub fn new(path: PathBuf) -> FileStorage {
+        FileStorage { path, lock: None }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn backup_path(&self, generation: u32) -> PathBuf {
+        let mut backup_file_name = self.path.file_name().unwrap_or_default().to_os_string();
+        backup_file_name.push(format!(".{}", generation));
+        self.path.with_file_name(backup_file_name)
+    }
+
+    fn rotate_backups(&self, generations: u32) -> io::Result<()> {
+        // drop the oldest generation along with any left over from a larger backup count
+        let mut generation = generations.max(1);
+        loop {
+            match std::fs::remove_file(self.backup_path(generation)) {
+                Ok(_) => generation += 1,
+                Err(e) if e.kind() == ErrorKind::NotFound && generation > generations => break,
+                Err(e) if e.kind() == ErrorKind::NotFound => generation += 1,
+                Err(e) => return Err(e),
+            }
+        }
+
+        if generations == 0 {
+            return Ok(());
+        }
+
+        for generation in (1..generations).rev() {
+            match std::fs::rename(
+                self.backup_path(generation),
+                self.backup_path(generation + 1),
+            ) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+
+        match std::fs::read(&self.path) {
+            Ok(current) => write_atomically(&self.backup_path(1), |file| file.write_all(&current)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl VaultStorage for FileStorage {
+    fn load(&self) -> io::Result<Vec<u8>> {
+        let mut file = File::open(&self.path)?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    fn store(&mut self, contents: &[u8], backup_generations: u32) -> io::Result<()> {
+        self.rotate_backups(backup_generations)?;
+        self.store_in_place(contents)
+    }
+
+    fn store_in_place(&mut self, contents: &[u8]) -> io::Result<()> {
+        write_atomically(&self.path, |file| file.write_all(contents))
+    }
+
+    fn metadata(&self) -> io::Result<StorageMetadata> {
+        file_metadata(&self.path)
+    }
+
+    fn lock(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.lock.is_none() {
+            self.lock = Some(VaultLock::acquire(&self.path)?);
+        }
+        Ok(())
+    }
+
+    fn unlock(&mut self) {
+        self.lock = None;
+    }
+
+    fn is_locked(&self) -> bool {
+        self.lock.is_some()
+    }
+
+    fn stale_lock_pid(&self) -> Option<u32> {
+        self.lock.as_ref().and_then(|lock| lock.stale_pid)
+    }
+
+    fn load_backup(&self, generation: u32) -> io::Result<Vec<u8>> {
+        std::fs::read(self.backup_path(generation))
+    }
+
+    fn backup_metadata(&self, generation: u32) -> io::Result<StorageMetadata> {
+        file_metadata(&self.backup_path(generation))
+    }
+
+    fn replace_backup(&mut self, generation: u32, contents: &[u8]) -> io::Result<()> {
+        let path = self.backup_path(generation);
+        let modified = std::fs::metadata(&path)?.modified()?;
+        write_atomically(&path, |file| {
+            file.write_all(contents)?;
+            file.set_modified(modified)
+        })
+    }
+}
+
+fn file_metadata(path: &Path) -> io::Result<StorageMetadata> {
+    let metadata = std::fs::metadata(path)?;
+    Ok(StorageMetadata {
+        modified: metadata.modified()?,
+        size: metadata.len(),
+    })
+}
+
+// The vault kept in memory, for tests. Clones share the same vault, like two instances opening
+// the same file, but only the clone that took the lock holds it.
+#[derive(Default)]
+pub struct MemoryStorage {
+    vault: Arc<Mutex<MemoryVault>>,
+    holds_lock: bool,
+}
+
+#[derive(Default)]
+struct MemoryVault {
+    // the newest first, the vault itself and then its backups
+    generations: Vec<(Vec<u8>, SystemTime)>,
+    locked: bool,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    // a storage already holding these contents, as if they had been stored once
+    pub fn with_contents(contents: Vec<u8>) -> MemoryStorage {
+        let storage = MemoryStorage::new();
+        storage
+            .vault()
+            .generations
+            .push((contents, SystemTime::now()));
+        storage
+    }
+
+    fn vault(&self) -> MutexGuard<'_, MemoryVault> {
+        // nothing panics while holding it, but a test that failed part way should not take the
+        // others down with it
+        self.vault
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn generation(&self, generation: usize) -> io::Result<(Vec<u8>, SystemTime)> {
+        self.vault()
+            .generations
+            .get(generation)
+            .cloned()
+            .ok_or_else(|| io::Error::from(ErrorKind::NotFound))
+    }
+}
+
+impl Clone for MemoryStorage {
+    fn clone(&self) -> MemoryStorage {
+        MemoryStorage {
+            vault: self.vault.clone(),
+            holds_lock: false,
+        }
+    }
+}
+
+impl VaultStorage for MemoryStorage {
+    fn load(&self) -> io::Result<Vec<u8>> {
+        self.generation(0).map(|(contents, _)| contents)
+    }
+
+    fn store(&mut self, contents: &[u8], backup_generations: u32) -> io::Result<()> {
+        let mut vault = self.vault();
+        // every store is seen as a change, however quickly it follows the last one
+        let modified = match vault.generations.first() {
+            Some((_, previous)) => SystemTime::now().max(*previous + Duration::from_nanos(1)),
+            None => SystemTime::now(),
+        };
+        vault.generations.insert(0, (contents.to_vec(), modified));
+        vault.generations.truncate(backup_generations as usize + 1);
+        Ok(())
+    }
+
+    fn store_in_place(&mut self, contents: &[u8]) -> io::Result<()> {
+        let mut vault = self.vault();
+        if vault.generations.is_empty() {
+            drop(vault);
+            return self.store(contents, 0);
+        }
+        let modified = SystemTime::now().max(vault.generations[0].1 + Duration::from_nanos(1));
+        vault.generations[0] = (contents.to_vec(), modified);
+        Ok(())
+    }
+
+    fn metadata(&self) -> io::Result<StorageMetadata> {
+        self.backup_metadata(0)
+    }
+
+    fn lock(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.holds_lock {
+            return Ok(());
+        }
+        let mut vault = self.vault();
+        if vault.locked {
+            return Err(Box::new(CryptoError::VaultLocked(None)));
+        }
+        vault.locked = true;
+        drop(vault);
+        self.holds_lock = true;
+        Ok(())
+    }
+
+    fn unlock(&mut self) {
+        if self.holds_lock {
+            self.vault().locked = false;
+            self.holds_lock = false;
+        }
+    }
+
+    fn is_locked(&self) -> bool {
+        self.holds_lock
+    }
+
+    fn load_backup(&self, generation: u32) -> io::Result<Vec<u8>> {
+        match generation {
+            0 => Err(io::Error::from(ErrorKind::NotFound)),
+            generation => self
+                .generation(generation as usize)
+                .map(|(contents, _)| contents),
+        }
+    }
+
+    // generation 0 is the vault itself
+    fn backup_metadata(&self, generation: u32) -> io::Result<StorageMetadata> {
+        self.generation(generation as usize)
+            .map(|(contents, modified)| StorageMetadata {
+                modified,
+                size: contents.len() as u64,
+            })
+    }
+
+    fn replace_backup(&mut self, generation: u32, contents: &[u8]) -> io::Result<()> {
+        let mut vault = self.vault();
+        match vault.generations.get_mut(generation as usize) {
+            Some((backup, _)) if generation > 0 => {
+                *backup = contents.to_vec();
+                Ok(())
+            }
+            _ => Err(io::Error::from(ErrorKind::NotFound)),
+        }
+    }
+}
+
+impl Drop for MemoryStorage {
+    fn drop(&mut self) {
+        self.unlock();
+    }
+}
+
+// Storage picked at run time, such as the shell's --storage option
+impl VaultStorage for Box<dyn VaultStorage> {
+    fn load(&self) -> io::Result<Vec<u8>> {
+        (**self).load()
+    }
+
+    fn store(&mut self, contents: &[u8], backup_generations: u32) -> io::Result<()> {
+        (**self).store(contents, backup_generations)
+    }
+
+    fn store_in_place(&mut self, contents: &[u8]) -> io::Result<()> {
+        (**self).store_in_place(contents)
+    }
+
+    fn metadata(&self) -> io::Result<StorageMetadata> {
+        (**self).metadata()
+    }
+
+    fn lock(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        (**self).lock()
+    }
+
+    fn unlock(&mut self) {
+        (**self).unlock()
+    }
+
+    fn is_locked(&self) -> bool {
+        (**self).is_locked()
+    }
+
+    fn stale_lock_pid(&self) -> Option<u32> {
+        (**self).stale_lock_pid()
+    }
+
+    fn load_backup(&self, generation: u32) -> io::Result<Vec<u8>> {
+        (**self).load_backup(generation)
+    }
+
+    fn backup_metadata(&self, generation: u32) -> io::Result<StorageMetadata> {
+        (**self).backup_metadata(generation)
+    }
+
+    fn replace_backup(&mut self, generation: u32, contents: &[u8]) -> io::Result<()> {
+        (**self).replace_backup(generation, contents)
+    }
+
+    fn keeps_backups(&self) -> bool {
+        (**self).keeps_backups()
+    }
+
+    fn entries(&self) -> Option<&dyn EntryStorage> {
+        (**self).entries()
+    }
+
+    fn entries_mut(&mut self) -> Option<&mut dyn EntryStorage> {
+        (**self).entries_mut()
+    }
+}
+
+// The vault as a SQLite database, for vaults with more entries than are worth writing out whole
+// on every change. The vault itself, its header and key slots, is a single row, each entry a row
+// of its own sealed with the same data key, and one more row holds the manifest binding the entry
+// rows together. It keeps no backups, rolling back the header would not roll back the entries with
+// it, so back the database file up as a whole instead. Its lock is in vault.lock, as for
+// FileStorage.
+#[cfg(feature = "sqlite")]
+pub struct SqliteStorage {
+    path: PathBuf,
+    connection: rusqlite::Connection,
+    lock: Option<VaultLock>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStorage {
+    // Opens the database, creating it if it does not exist yet
+    pub fn open(path: PathBuf) -> io::Result<SqliteStorage> {
+        match create_private_file(&path) {
+            Err(e) if e.kind() != ErrorKind::AlreadyExists => return Err(e),
+            _ => {}
+        }
+        let connection = rusqlite::Connection::open(&path).map_err(sqlite_error)?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS vault (
+                    id INTEGER PRIMARY KEY CHECK (id = 0),
+                    contents BLOB NOT NULL,
+                    modified INTEGER NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS entries (
+                    lookup BLOB PRIMARY KEY,
+                    nonce BLOB NOT NULL,
+                    ciphertext BLOB NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS manifest (
+                    id INTEGER PRIMARY KEY CHECK (id = 0),
+                    mac BLOB NOT NULL
+                );",
+            )
+            .map_err(sqlite_error)?;
+        Ok(SqliteStorage {
+            path,
+            connection,
+            lock: None,
+        })
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    fn select_entries(
+        &self,
+        sql: &str,
+        params: impl rusqlite::Params,
+    ) -> io::Result<Vec<StoredEntry>> {
+        let mut statement = self.connection.prepare(sql).map_err(sqlite_error)?;
+        let rows = statement
+            .query_map(params, |row| {
+                Ok(StoredEntry {
+                    lookup: row.get(0)?,
+                    nonce: row.get(1)?,
+                    ciphertext: row.get(2)?,
+                })
+            })
+            .map_err(sqlite_error)?;
+        rows.collect::<Result<_, _>>().map_err(sqlite_error)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl VaultStorage for SqliteStorage {
+    fn load(&self) -> io::Result<Vec<u8>> {
+        use rusqlite::OptionalExtension;
+        self.connection
+            .query_row("SELECT contents FROM vault WHERE id = 0", [], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(sqlite_error)?
+            .ok_or_else(|| io::Error::from(ErrorKind::NotFound))
+    }
+
+    fn store(&mut self, contents: &[u8], _backup_generations: u32) -> io::Result<()> {
+        // every store is seen as a change, however quickly it follows the last one
+        let now = unix_nanos(SystemTime::now());
+        let modified = match self.metadata() {
+            Ok(previous) => now.max(unix_nanos(previous.modified) + 1),
+            Err(e) if e.kind() == ErrorKind::NotFound => now,
+            Err(e) => return Err(e),
+        };
+        self.connection
+            .execute(
+                "INSERT OR REPLACE INTO vault (id, contents, modified) VALUES (0, ?1, ?2)",
+                rusqlite::params![contents, modified],
+            )
+            .map_err(sqlite_error)?;
+        Ok(())
+    }
+
+    // there are no backups to leave alone
+    fn store_in_place(&mut self, contents: &[u8]) -> io::Result<()> {
+        self.store(contents, 0)
+    }
+
+    fn metadata(&self) -> io::Result<StorageMetadata> {
+        use rusqlite::OptionalExtension;
+        let (modified, size): (i64, i64) = self
+            .connection
+            .query_row(
+                "SELECT modified, length(contents) FROM vault WHERE id = 0",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(sqlite_error)?
+            .ok_or_else(|| io::Error::from(ErrorKind::NotFound))?;
+        Ok(StorageMetadata {
+            modified: SystemTime::UNIX_EPOCH + Duration::from_nanos(modified as u64),
+            size: size as u64,
+        })
+    }
+
+    fn lock(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.lock.is_none() {
+            self.lock = Some(VaultLock::acquire(&self.path)?);
+        }
+        Ok(())
+    }
+
+    fn unlock(&mut self) {
+        self.lock = None;
+    }
+
+    fn is_locked(&self) -> bool {
+        self.lock.is_some()
+    }
+
+    fn stale_lock_pid(&self) -> Option<u32> {
+        self.lock.as_ref().and_then(|lock| lock.stale_pid)
+    }
+
+    fn load_backup(&self, _generation: u32) -> io::Result<Vec<u8>> {
+        Err(io::Error::from(ErrorKind::NotFound))
+    }
+
+    fn backup_metadata(&self, _generation: u32) -> io::Result<StorageMetadata> {
+        Err(io::Error::from(ErrorKind::NotFound))
+    }
+
+    fn replace_backup(&mut self, _generation: u32, _contents: &[u8]) -> io::Result<()> {
+        Err(io::Error::from(ErrorKind::NotFound))
+    }
+
+    fn keeps_backups(&self) -> bool {
+        false
+    }
+
+    fn entries(&self) -> Option<&dyn EntryStorage> {
+        Some(self)
+    }
+
+    fn entries_mut(&mut self) -> Option<&mut dyn EntryStorage> {
+        Some(self)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl EntryStorage for SqliteStorage {
+    fn load_entries(&self) -> io::Result<Vec<StoredEntry>> {
+        self.select_entries(
+            "SELECT lookup, nonce, ciphertext FROM entries ORDER BY rowid",
+            [],
+        )
+    }
+
+    fn write_entries(
+        &mut self,
+        entries: &[StoredEntry],
+        deleted: &[Vec<u8>],
+        manifest: &[u8],
+    ) -> io::Result<()> {
+        let transaction = self.connection.transaction().map_err(sqlite_error)?;
+        for lookup in deleted {
+            transaction
+                .execute("DELETE FROM entries WHERE lookup = ?1", [lookup])
+                .map_err(sqlite_error)?;
+        }
+        for entry in entries {
+            // an update keeps the row where it is, so entries list in the order they were created
+            let updated = transaction
+                .execute(
+                    "UPDATE entries SET nonce = ?2, ciphertext = ?3 WHERE lookup = ?1",
+                    rusqlite::params![entry.lookup, entry.nonce, entry.ciphertext],
+                )
+                .map_err(sqlite_error)?;
+            if updated == 0 {
+                transaction
+                    .execute(
+                        "INSERT INTO entries (lookup, nonce, ciphertext) VALUES (?1, ?2, ?3)",
+                        rusqlite::params![entry.lookup, entry.nonce, entry.ciphertext],
+                    )
+                    .map_err(sqlite_error)?;
+            }
+        }
+        transaction
+            .execute(
+                "INSERT OR REPLACE INTO manifest (id, mac) VALUES (0, ?1)",
+                [manifest],
+            )
+            .map_err(sqlite_error)?;
+        transaction.commit().map_err(sqlite_error)
+    }
+
+    fn load_manifest(&self) -> io::Result<Option<Vec<u8>>> {
+        use rusqlite::OptionalExtension;
+        self.connection
+            .query_row("SELECT mac FROM manifest WHERE id = 0", [], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(sqlite_error)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite_error(error: rusqlite::Error) -> io::Error {
+    io::Error::other(error)
+}
+
+#[cfg(feature = "sqlite")]
+fn unix_nanos(time: SystemTime) -> i64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as i64
+}
+
+// An advisory lock on vault.lock, which holds the pid of the instance that has the vault open.
+// The OS drops the lock when its holder exits, however it exits, so a lock can never be left held
+// by a dead process. A clean exit also clears the pid, so finding one when taking the lock means
+// the last holder crashed.
+struct VaultLock {
+    file: File,
+    stale_pid: Option<u32>,
+}
+
+impl VaultLock {
+    fn acquire(vault_path: &Path) -> Result<VaultLock, Box<dyn std::error::Error>> {
+        let mut lock_file_name = vault_path.file_name().unwrap_or_default().to_os_string();
+        lock_file_name.push(".lock");
+        let lock_path = vault_path.with_file_name(lock_file_name);
+
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(false);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(lock_path)?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(Box::new(CryptoError::VaultLocked(read_pid(&mut file))))
+            }
+            Err(TryLockError::Error(e)) => return Err(Box::new(e)),
+        }
+
+        let stale_pid = read_pid(&mut file);
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+
+        Ok(VaultLock { file, stale_pid })
+    }
+}
+
+impl Drop for VaultLock {
+    fn drop(&mut self) {
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+// Writes the new vault next to the old one and swaps it in with a rename, so a crash, full disk or
+// kill part way through leaves either the old vault or the new one on disk, never a broken file.
+// The temp file is fsynced before the rename and the directory after it, otherwise the rename can
+// reach the disk before the data it points at.
+fn write_atomically<F>(path: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut File) -> io::Result<()>,
+{
+    let mut temp_file_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_file_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_file_name);
+
+    // leftovers from a crashed write may have been created with looser permissions
+    match std::fs::remove_file(&temp_path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+
+    let result = create_private_file(&temp_path).and_then(|mut file| {
+        write(&mut file)?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&temp_path, path)?;
+        sync_parent_directory(path)
+    });
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+// 0600 from the moment the file exists, there is no window where other users could open it
+pub fn create_private_file(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(path)
+}
+
+#[cfg(unix)]
+fn sync_parent_directory(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
+
+// directories can not be opened as files on windows, NTFS journals the rename itself
+#[cfg(not(unix))]
+fn sync_parent_directory(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn generate_unique_file_path() -> PathBuf {
+        PathBuf::from(format!("/tmp/test_{}.enc", Uuid::new_v4()))
+    }
+
+    fn teardown(storage: &FileStorage) {
+        let _ = std::fs::remove_file(storage.path());
+        for generation in 1..=3 {
+            let _ = std::fs::remove_file(storage.backup_path(generation));
+        }
+        let _ = std::fs::remove_file(format!("{}.lock", storage.path().display()));
+    }
+
+    // runs the same checks against every implementation
+    fn check_store_and_backups(storage: &mut dyn VaultStorage) {
+        assert_eq!(storage.load().unwrap_err().kind(), ErrorKind::NotFound);
+
+        for contents in [b"first", b"secnd", b"third", b"forth"] {
+            storage.store(contents, 2).unwrap();
+        }
+        assert_eq!(storage.load().unwrap(), b"forth");
+        assert_eq!(storage.metadata().unwrap().size, 5);
+        assert_eq!(storage.load_backup(1).unwrap(), b"third");
+        assert_eq!(storage.load_backup(2).unwrap(), b"secnd");
+        assert_eq!(
+            storage.backup_metadata(3).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+
+        storage.store(b"fifth", 0).unwrap();
+        assert_eq!(storage.load().unwrap(), b"fifth");
+        assert_eq!(
+            storage.load_backup(1).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn test_file_storage_stores_and_rotates_backups() {
+        let mut storage = FileStorage::new(generate_unique_file_path());
+        check_store_and_backups(&mut storage);
+        teardown(&storage);
+    }
+
+    #[test]
+    fn test_memory_storage_stores_and_rotates_backups() {
+        check_store_and_backups(&mut MemoryStorage::new());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_storage_keeps_the_vault_and_each_entry_in_rows() {
+        let path = generate_unique_file_path().with_extension("db");
+        let mut storage = SqliteStorage::open(path.clone()).unwrap();
+        assert_eq!(storage.load().unwrap_err().kind(), ErrorKind::NotFound);
+
+        storage.store(b"first", 2).unwrap();
+        let first_modified = storage.metadata().unwrap().modified;
+        storage.store(b"secnd", 2).unwrap();
+        assert_eq!(storage.load().unwrap(), b"secnd");
+        assert!(storage.metadata().unwrap().modified > first_modified);
+        assert_eq!(
+            storage.load_backup(1).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+
+        let entry = |lookup: &[u8], ciphertext: &[u8]| StoredEntry {
+            lookup: lookup.to_vec(),
+            nonce: vec![0; 12],
+            ciphertext: ciphertext.to_vec(),
+        };
+        assert_eq!(storage.load_manifest().unwrap(), None);
+        storage
+            .write_entries(&[entry(b"a1", b"one"), entry(b"b1", b"two")], &[], b"m1")
+            .unwrap();
+        storage
+            .write_entries(&[entry(b"a1", b"new")], &[], b"m2")
+            .unwrap();
+        storage
+            .write_entries(&[entry(b"a2", b"three")], &[], b"m3")
+            .unwrap();
+        assert_eq!(storage.load_manifest().unwrap(), Some(b"m3".to_vec()));
+        assert_eq!(
+            storage.load_entries().unwrap(),
+            vec![
+                entry(b"a1", b"new"),
+                entry(b"b1", b"two"),
+                entry(b"a2", b"three")
+            ]
+        );
+
+        // a rename, the row under the old lookup goes as the new one is written
+        storage
+            .write_entries(&[entry(b"c1", b"two")], &[b"b1".to_vec()], b"m4")
+            .unwrap();
+        assert!(!storage
+            .load_entries()
+            .unwrap()
+            .iter()
+            .any(|entry| entry.lookup == b"b1"));
+        storage
+            .write_entries(&[], &[b"c1".to_vec()], b"m5")
+            .unwrap();
+        drop(storage);
+
+        let reopened = SqliteStorage::open(path.clone()).unwrap();
+        assert_eq!(reopened.load().unwrap(), b"secnd");
+        assert_eq!(reopened.load_entries().unwrap().len(), 2);
+        assert_eq!(reopened.load_manifest().unwrap(), Some(b"m5".to_vec()));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_failed_write_leaves_the_old_contents_intact() {
+        let mut storage = FileStorage::new(generate_unique_file_path());
+        storage.store(b"Test data", 0).unwrap();
+
+        // the disk fills up half way through writing the new vault
+        let result = write_atomically(storage.path(), |file| {
+            file.write_all(b"Test")?;
+            Err(io::Error::other("No space left on device"))
+        });
+        assert!(result.is_err());
+
+        assert_eq!(storage.load().unwrap(), b"Test data");
+        assert!(!PathBuf::from(format!("{}.tmp", storage.path().display())).exists());
+
+        teardown(&storage);
+    }
+
+    #[test]
+    fn test_memory_storage_lock_is_shared_between_clones() {
+        let mut first = MemoryStorage::new();
+        let mut second = first.clone();
+
+        first.lock().unwrap();
+        let error = second.lock().unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CryptoError>(),
+            Some(&CryptoError::VaultLocked(None))
+        );
+        assert!(!second.is_locked());
+
+        drop(first);
+        second.lock().unwrap();
+        assert!(second.is_locked());
+    }
+}
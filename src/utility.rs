@@ -1,12 +1,27 @@
-use crate::password_manager::{CredentialSet, MaskedCredentialSet};
+use crate::encryption::BackupInfo;
+use crate::entry_type::{EntryType, ENTRY_TYPES};
+use crate::password_manager::{
+    CredentialSet, CustomField, MaskedCredentialSet, MaskedCustomField, MaskedPasswordHistoryEntry,
+    MergeConflict, MergeSide,
+};
+use crate::recovery_code::RecoveryCode;
+use crate::secret::{SecretBytes, SecretString};
+use crate::vault_format::KeySlot;
 use arboard::Clipboard;
+use chrono::{DateTime, Local, Utc};
 use prettytable::{row, Cell, Row, Table};
+use std::borrow::Cow;
 use std::env;
 use std::error::Error;
+use std::fmt;
 use std::fs;
-use std::io::{stdin, stdout, Write};
-use std::path::PathBuf;
+use std::io::{self, stdin, stdout, BufRead, Write};
+use std::path::{Path, PathBuf};
 use std::{thread, time::Duration};
+use zeroize::Zeroize;
+
+const SECRET_INPUT_CAPACITY: usize = 1024;
+const MULTILINE_SECRET_CAPACITY: usize = 64 * 1024;
 
 pub fn validate_identifier(identifier: &str) -> Result<(), ArmorPassError> {
     if !is_at_least_three_characters_long(identifier) {
@@ -16,6 +31,20 @@ pub fn validate_identifier(identifier: &str) -> Result<(), ArmorPassError> {
     }
 }
 
+// every field needs a name, and no two fields of an item can share one
+pub fn validate_custom_fields(fields: &[CustomField]) -> Result<(), ArmorPassError> {
+    for (index, field) in fields.iter().enumerate() {
+        if field.name.trim().is_empty()
+            || fields[..index]
+                .iter()
+                .any(|earlier| earlier.name == field.name)
+        {
+            return Err(ArmorPassError::InvalidCustomFieldName);
+        }
+    }
+    Ok(())
+}
+
 fn is_at_least_three_characters_long(password: &str) -> bool {
     password.len() >= 3
 }
@@ -30,6 +59,121 @@ pub fn prompt(prompttext: &str) -> String {
     input.trim().to_string()
 }
 
+// Like prompt, for passwords. The line is read straight into a locked buffer that is wiped on drop.
+pub fn prompt_for_secret(prompttext: &str) -> SecretString {
+    loop {
+        print!(">> {}", prompttext);
+        stdout().flush().unwrap();
+
+        let mut buffer = SecretBytes::zeroed(SECRET_INPUT_CAPACITY);
+        match read_secret_line(&mut stdin().lock(), &mut buffer, 0).expect("Failed to read line") {
+            SecretLine::Read(end) => return secret_text(&buffer[..end]),
+            SecretLine::End => return SecretString::default(),
+            SecretLine::TooLong => eprintln!(
+                "[ERROR]: Input longer than {} bytes is not accepted, try again",
+                SECRET_INPUT_CAPACITY
+            ),
+        }
+    }
+}
+
+// For text that runs over several lines, such as a secure note. Lines are read until one holding
+// a lone . or the end of the input. Text too long for the buffer is dropped whole.
+pub fn prompt_for_multiline_secret(prompttext: &str) -> SecretString {
+    println!(">> {}", prompttext);
+
+    let mut input = stdin().lock();
+    let mut text = SecretBytes::zeroed(MULTILINE_SECRET_CAPACITY);
+    let mut length = 0;
+    let mut too_long = false;
+    loop {
+        // once the text is too long, the rest is only read to find where it stops
+        let start = if too_long { 0 } else { length };
+        match read_secret_line(&mut input, &mut text, start) {
+            Ok(SecretLine::End) | Err(_) => break,
+            Ok(SecretLine::Read(end)) if text[start..end].trim_ascii_end() == b"." => break,
+            Ok(SecretLine::Read(end)) if too_long || end == text.len() => too_long = true,
+            Ok(SecretLine::Read(end)) => {
+                text[end] = b'\n';
+                length = end + 1;
+            }
+            Ok(SecretLine::TooLong) => too_long = true,
+        }
+    }
+
+    if too_long {
+        eprintln!(
+            "[ERROR]: Text longer than {} bytes is not accepted",
+            MULTILINE_SECRET_CAPACITY
+        );
+        return SecretString::default();
+    }
+    secret_text(&text[..length])
+}
+
+enum SecretLine {
+    Read(usize),
+    TooLong,
+    End,
+}
+
+// Reads one line into buffer from start on, without its line ending, and returns where it ends.
+// The buffer is never grown: a line that does not fit is read to its end and dropped.
+fn read_secret_line(
+    input: &mut impl BufRead,
+    buffer: &mut [u8],
+    start: usize,
+) -> io::Result<SecretLine> {
+    let mut end = start;
+    let mut too_long = false;
+    let mut read_any = false;
+    loop {
+        let available = input.fill_buf()?;
+        if available.is_empty() {
+            break;
+        }
+        read_any = true;
+
+        let newline = available.iter().position(|&byte| byte == b'\n');
+        let line = &available[..newline.unwrap_or(available.len())];
+        if !too_long && end + line.len() <= buffer.len() {
+            buffer[end..end + line.len()].copy_from_slice(line);
+            end += line.len();
+        } else {
+            too_long = true;
+        }
+
+        let used = line.len() + usize::from(newline.is_some());
+        input.consume(used);
+        if newline.is_some() {
+            break;
+        }
+    }
+
+    if too_long {
+        Ok(SecretLine::TooLong)
+    } else if !read_any {
+        Ok(SecretLine::End)
+    } else {
+        if end > start && buffer[end - 1] == b'\r' {
+            end -= 1;
+        }
+        Ok(SecretLine::Read(end))
+    }
+}
+
+// Input that is not valid UTF-8 is taken lossily; the copy that makes is wiped as well.
+fn secret_text(bytes: &[u8]) -> SecretString {
+    match String::from_utf8_lossy(bytes) {
+        Cow::Borrowed(text) => SecretString::from(text.trim()),
+        Cow::Owned(mut text) => {
+            let secret = SecretString::from(text.trim());
+            text.zeroize();
+            secret
+        }
+    }
+}
+
 pub fn prompt_for_number(prompttxt: &str) -> Option<u8> {
     loop {
         let input = prompt(prompttxt);
@@ -47,32 +191,357 @@ pub fn prompt_for_number(prompttxt: &str) -> Option<u8> {
     }
 }
 
+pub fn prompt_for_u32(prompttxt: &str) -> Option<u32> {
+    loop {
+        let input = prompt(prompttxt);
+        if input.trim().is_empty() {
+            return None;
+        } else {
+            match input.trim().parse::<u32>() {
+                Ok(num) => return Some(num),
+                Err(_) => {
+                    eprintln!("Please enter a valid number (0-{})", u32::MAX);
+                    continue;
+                }
+            }
+        }
+    }
+}
+
 pub fn prompt_for_confirmation(prompttxt: &str) -> bool {
     let input = prompt(prompttxt).trim().to_lowercase();
     matches!(input.as_str(), "y" | "yes")
 }
 
+// Asks for a new value of a field, None keeps the one it has. A lone - clears it.
+pub fn prompt_for_edit(name: &str, current: &str) -> Option<String> {
+    let input = prompt(&format!(
+        "Enter a new {} (currently \"{}\", leave empty to keep it or enter - to clear it): ",
+        name, current
+    ));
+    match input.as_str() {
+        "" => None,
+        "-" => Some(String::new()),
+        _ => Some(input),
+    }
+}
+
+// comma separated, blanks and repeats dropped
+pub fn parse_tags(input: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for tag in input.split(',').map(str::trim) {
+        if !tag.is_empty() && !tags.iter().any(|existing| existing == tag) {
+            tags.push(tag.to_string());
+        }
+    }
+    tags
+}
+
+pub fn prompt_for_merge_conflict(conflict: &MergeConflict) -> MergeSide {
+    println!(
+        "[Warn]: {} / {} was changed both here and in the copy on disk",
+        conflict.identifier, conflict.username
+    );
+    let mut table = Table::new();
+    table.add_row(row!["", "Here", "On disk"]);
+    for [field, ours, theirs] in merge_conflict_rows(conflict) {
+        table.add_row(row![field, ours, theirs]);
+    }
+    table.printstd();
+    if prompt_for_confirmation("Keep the copy on disk instead of yours? (default no): ") {
+        MergeSide::Theirs
+    } else {
+        MergeSide::Ours
+    }
+}
+
+// The fields the two sides disagree on, and when each was last modified. Passwords and concealed
+// fields show as the mask, their row alone says they differ.
+fn merge_conflict_rows(conflict: &MergeConflict) -> Vec<[String; 3]> {
+    let (ours, theirs) = match (&conflict.ours, &conflict.theirs) {
+        (Some(ours), Some(theirs)) => (ours, theirs),
+        (ours, theirs) => {
+            let describe = |side: &Option<CredentialSet>| match side {
+                Some(credential) => format!("updated {}", format_timestamp(credential.modified)),
+                None => "deleted".to_string(),
+            };
+            return vec![["Entry".to_string(), describe(ours), describe(theirs)]];
+        }
+    };
+    let (masked_ours, masked_theirs) = (ours.mask(), theirs.mask());
+    let mut rows = Vec::new();
+    let mut compare = |field: &str, here: String, on_disk: String, differs: bool| {
+        if differs {
+            rows.push([field.to_string(), here, on_disk]);
+        }
+    };
+
+    compare(
+        "Kind",
+        ours.entry_type.to_string(),
+        theirs.entry_type.to_string(),
+        ours.entry_type != theirs.entry_type,
+    );
+    compare(
+        "Username",
+        ours.username.clone(),
+        theirs.username.clone(),
+        ours.username != theirs.username,
+    );
+    compare(
+        ours.entry_type.schema().password,
+        masked_ours.password,
+        masked_theirs.password,
+        ours.password != theirs.password,
+    );
+    compare(
+        "URL",
+        ours.url.clone(),
+        theirs.url.clone(),
+        ours.url != theirs.url,
+    );
+    compare(
+        "Notes",
+        ours.notes.clone(),
+        theirs.notes.clone(),
+        ours.notes != theirs.notes,
+    );
+    compare(
+        "Tags",
+        ours.tags.join(", "),
+        theirs.tags.join(", "),
+        ours.tags != theirs.tags,
+    );
+
+    let mut names: Vec<&str> = ours
+        .fields
+        .iter()
+        .map(|field| field.name.as_str())
+        .collect();
+    for field in &theirs.fields {
+        if !names.contains(&field.name.as_str()) {
+            names.push(&field.name);
+        }
+    }
+    for name in names {
+        let find = |fields: &[CustomField]| fields.iter().position(|field| field.name == name);
+        let (here, on_disk) = (find(&ours.fields), find(&theirs.fields));
+        let shown = |masked: &[MaskedCustomField], index: Option<usize>| {
+            index.map_or("-".to_string(), |index| masked[index].value.clone())
+        };
+        let differs = match (here, on_disk) {
+            (Some(here), Some(on_disk)) => ours.fields[here] != theirs.fields[on_disk],
+            _ => true,
+        };
+        compare(
+            name,
+            shown(&masked_ours.fields, here),
+            shown(&masked_theirs.fields, on_disk),
+            differs,
+        );
+    }
+
+    rows.push([
+        "Modified".to_string(),
+        format_timestamp(ours.modified),
+        format_timestamp(theirs.modified),
+    ]);
+    rows
+}
+
+// One table for each kind of item in the list, with the columns of that kind
 pub fn print_credential_list(credential_list: Vec<MaskedCredentialSet>) {
+    let kinds: Vec<EntryType> = ENTRY_TYPES
+        .into_iter()
+        .filter(|kind| credential_list.iter().any(|c| c.entry_type == *kind))
+        .collect();
+    for kind in &kinds {
+        if kinds.len() > 1 {
+            println!("{}:", kind);
+        }
+        let credentials: Vec<&MaskedCredentialSet> = credential_list
+            .iter()
+            .filter(|credential| credential.entry_type == *kind)
+            .collect();
+        print_credential_table(*kind, &credentials);
+    }
+}
+
+// The identifier and the values every item has, with the username, url and schema fields only for
+// kinds that have them. Fields outside the schema share the Fields column.
+fn print_credential_table(entry_type: EntryType, credentials: &[&MaskedCredentialSet]) {
+    let schema = entry_type.schema();
+    let mut header = vec!["Identifier".to_string()];
+    header.extend(schema.username.map(str::to_string));
+    header.push(schema.password.to_string());
+    if schema.has_url {
+        header.push("URL".to_string());
+    }
+    header.extend(schema.fields.iter().map(|field| capitalize(field.name)));
+    header.extend(
+        [
+            "Tags",
+            "Notes",
+            "Fields",
+            "Created",
+            "Modified",
+            "Last used",
+        ]
+        .map(str::to_string),
+    );
+
+    let mut table = Table::new();
+    table.add_row(Row::new(
+        header.iter().map(|name| Cell::new(name)).collect(),
+    ));
+    for credential in credentials {
+        let mut cells = vec![credential.identifier.clone()];
+        if schema.username.is_some() {
+            cells.push(credential.username.clone());
+        }
+        cells.push(credential.password.clone());
+        if schema.has_url {
+            cells.push(credential.url.clone());
+        }
+        for field_schema in schema.fields {
+            cells.push(
+                credential
+                    .fields
+                    .iter()
+                    .find(|field| field.name == field_schema.name)
+                    .map_or(String::new(), |field| field.value.clone()),
+            );
+        }
+        let other_fields: Vec<&MaskedCustomField> = credential
+            .fields
+            .iter()
+            .filter(|field| !schema.fields.iter().any(|f| f.name == field.name))
+            .collect();
+        cells.push(credential.tags.join(", "));
+        cells.push(credential.notes.clone());
+        cells.push(format_custom_fields(&other_fields));
+        cells.push(format_timestamp(credential.created));
+        cells.push(format_timestamp(credential.modified));
+        cells.push(format_timestamp(credential.last_used));
+        table.add_row(Row::new(cells.iter().map(|cell| Cell::new(cell)).collect()));
+    }
+    table.printstd();
+}
+
+fn capitalize(name: &str) -> String {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+// numbered from 1 in the order the item keeps them, for picking one to copy
+pub fn print_custom_field_list(fields: Vec<MaskedCustomField>) {
+    let mut table = Table::new();
+    table.add_row(row!["#", "Name", "Value"]);
+    for (index, field) in fields.iter().enumerate() {
+        table.add_row(Row::new(vec![
+            Cell::new(&(index + 1).to_string()),
+            Cell::new(&field.name),
+            Cell::new(&field.value),
+        ]));
+    }
+    table.printstd();
+}
+
+// the entries sharing an identifier, numbered from 1 so one can be picked
+pub fn print_entry_choices(entries: Vec<MaskedCredentialSet>) {
+    let mut table = Table::new();
+    table.add_row(row!["#", "Kind", "Username", "Tags", "Created", "Modified"]);
+    for (index, entry) in entries.iter().enumerate() {
+        table.add_row(Row::new(vec![
+            Cell::new(&(index + 1).to_string()),
+            Cell::new(entry.entry_type.name()),
+            Cell::new(&entry.username),
+            Cell::new(&entry.tags.join(", ")),
+            Cell::new(&format_timestamp(entry.created)),
+            Cell::new(&format_timestamp(entry.modified)),
+        ]));
+    }
+    table.printstd();
+}
+
+// numbered from 1, the most recent first
+pub fn print_password_history(history: Vec<MaskedPasswordHistoryEntry>) {
+    let mut table = Table::new();
+    table.add_row(row!["#", "Password", "Replaced"]);
+    for (index, entry) in history.iter().enumerate() {
+        table.add_row(Row::new(vec![
+            Cell::new(&(index + 1).to_string()),
+            Cell::new(&entry.password),
+            Cell::new(&format_timestamp(Some(entry.replaced))),
+        ]));
+    }
+    table.printstd();
+}
+
+// one name: value line per field
+fn format_custom_fields(fields: &[&MaskedCustomField]) -> String {
+    fields
+        .iter()
+        .map(|field| format!("{}: {}", field.name, field.value))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// in local time, entries stored before timestamps were kept have none
+fn format_timestamp(timestamp: Option<DateTime<Utc>>) -> String {
+    match timestamp {
+        Some(timestamp) => DateTime::<Local>::from(timestamp)
+            .format("%Y-%m-%d %H:%M")
+            .to_string(),
+        None => "-".to_string(),
+    }
+}
+
+pub fn print_backup_list(backups: &[BackupInfo]) {
     let mut table = Table::new();
-    table.add_row(row!["Identifier", "Username", "Password"]);
-    for credential in credential_list {
+    table.add_row(row!["Generation", "Written", "Size (bytes)"]);
+    for backup in backups {
+        let written: DateTime<Local> = backup.modified.into();
         table.add_row(Row::new(vec![
-            Cell::new(&credential.identifier),
-            Cell::new(&credential.username),
-            Cell::new(&credential.password),
+            Cell::new(&backup.generation.to_string()),
+            Cell::new(&written.format("%Y-%m-%d %H:%M:%S").to_string()),
+            Cell::new(&backup.size.to_string()),
         ]));
     }
     table.printstd();
 }
 
-pub fn print_credential(credential: &CredentialSet) {
+pub fn print_recovery_code(recovery_code: &RecoveryCode) {
+    println!("[INFO]: your recovery code is\n\n    {}\n", recovery_code);
+    println!(
+        "[INFO]: write it down and keep it somewhere safe, starting ArmorPass with --recover lets it set a new master password"
+    );
+}
+
+pub fn print_key_slot_list(slots: &[KeySlot], unlocked_slot: u32) {
     let mut table = Table::new();
-    table.add_row(row!["Identifier", "Username", "Password"]);
-    table.add_row(Row::new(vec![
-        Cell::new(&credential.identifier),
-        Cell::new(&credential.username),
-        Cell::new(&credential.password),
-    ]));
+    table.add_row(row!["Id", "Kind", "Label", "Key file", "Key derivation"]);
+    for slot in slots {
+        let label = if slot.id == unlocked_slot {
+            format!("{} (this session)", slot.label)
+        } else {
+            slot.label.clone()
+        };
+        table.add_row(Row::new(vec![
+            Cell::new(&slot.id.to_string()),
+            Cell::new(&slot.kind.to_string()),
+            Cell::new(&label),
+            Cell::new(if slot.key_file_check.is_some() {
+                "required"
+            } else {
+                "no"
+            }),
+            Cell::new(&slot.kdf.to_string()),
+        ]));
+    }
     table.printstd();
 }
 
@@ -101,12 +570,13 @@ pub fn copy_to_clipboard_then_clear(text: &str) {
                 "[INFO]: Sensitive data copied to clipboard. It will be cleared in 20 seconds."
             );
 
-            let content_to_clear = text.to_owned();
+            // held until the clipboard is cleared, so it is wiped like any other secret
+            let content_to_clear = SecretString::from(text);
 
             // Spawn a new thread to clear the clipboard after 20 seconds
             thread::spawn(move || {
                 thread::sleep(Duration::from_secs(20));
-                match clipboard.get_text() {
+                match clipboard.get_text().map(SecretString::new) {
                     Ok(current_content) if current_content == content_to_clear => {
                         let _ = clipboard.set_text("".to_owned());
                     }
@@ -119,9 +589,7 @@ pub fn copy_to_clipboard_then_clear(text: &str) {
     }
 }
 
-pub fn armor_file_exists() -> bool {
-    let home_dir = get_home_dir().unwrap();
-    let file_path = home_dir.join(".armorpass.enc");
+pub fn armor_file_exists(file_path: &Path) -> bool {
     match fs::metadata(file_path) {
         Ok(_) => true,
         Err(_e) => false,
@@ -135,11 +603,174 @@ pub enum ArmorPassError {
     CreateIdentifierTooShort,
     FailedToPersistToDisk(String),
     NoRecordFound,
+    WrongMasterPassword,
+    NoBackupFound,
+    CorruptBackup(String),
+    ReadOnly,
+    NoKeySlotFound,
+    KeySlotInUse,
+    InvalidEscrowThreshold,
+    NoHistoryEntryFound,
+    InvalidCustomFieldName,
+    InvalidEntryField(String),
+}
+
+impl fmt::Display for ArmorPassError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArmorPassError::CreateDuplicateUsername => write!(f, "username must be unique"),
+            ArmorPassError::CreateDuplicatePassword => write!(f, "password must be unique"),
+            ArmorPassError::CreateIdentifierTooShort => {
+                write!(f, "identifier must be at least 3 characters long")
+            }
+            ArmorPassError::FailedToPersistToDisk(reason) => write!(f, "{}", reason),
+            ArmorPassError::NoRecordFound => write!(f, "no record found"),
+            ArmorPassError::WrongMasterPassword => write!(f, "the master password is wrong"),
+            ArmorPassError::NoBackupFound => write!(f, "no backup found for that generation"),
+            ArmorPassError::CorruptBackup(reason) => {
+                write!(f, "the backup could not be opened: {}", reason)
+            }
+            ArmorPassError::NoKeySlotFound => write!(f, "no key slot found with that id"),
+            ArmorPassError::KeySlotInUse => write!(
+                f,
+                "that key slot unlocked this session, it can not be revoked from here"
+            ),
+            ArmorPassError::InvalidEscrowThreshold => write!(
+                f,
+                "at least 2 shares must be needed, and no more than there are shares"
+            ),
+            ArmorPassError::NoHistoryEntryFound => {
+                write!(f, "no earlier password found with that number")
+            }
+            ArmorPassError::InvalidCustomFieldName => {
+                write!(f, "every custom field needs a name of its own")
+            }
+            ArmorPassError::InvalidEntryField(name) => {
+                write!(
+                    f,
+                    "the {} is missing or not valid for this kind of item",
+                    name
+                )
+            }
+            ArmorPassError::ReadOnly => {
+                write!(f, "the vault is open read-only, changes can not be saved")
+            }
+        }
+    }
 }
 
+impl Error for ArmorPassError {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_read_secret_line_never_grows_the_buffer() {
+        let mut input = io::Cursor::new(b"hunter2\r\ntoo long for it\nok\n".to_vec());
+        let mut buffer = [0u8; 8];
+
+        assert!(matches!(
+            read_secret_line(&mut input, &mut buffer, 0).unwrap(),
+            SecretLine::Read(7)
+        ));
+        assert_eq!(&buffer[..7], b"hunter2");
+        // the long line is dropped whole, and the next one is read as usual
+        assert!(matches!(
+            read_secret_line(&mut input, &mut buffer, 0).unwrap(),
+            SecretLine::TooLong
+        ));
+        assert!(matches!(
+            read_secret_line(&mut input, &mut buffer, 0).unwrap(),
+            SecretLine::Read(2)
+        ));
+        assert_eq!(&buffer[..2], b"ok");
+        assert!(matches!(
+            read_secret_line(&mut input, &mut buffer, 0).unwrap(),
+            SecretLine::End
+        ));
+    }
+
+    #[test]
+    fn test_merge_conflict_rows_show_what_differs_masked() {
+        let ours = CredentialSet {
+            id: Uuid::new_v4(),
+            entry_type: EntryType::Login,
+            identifier: "mail".to_string(),
+            username: "me".to_string(),
+            password: SecretString::from("old secret"),
+            url: "https://mail.example".to_string(),
+            notes: String::new(),
+            tags: Vec::new(),
+            created: None,
+            modified: None,
+            last_used: None,
+            history: Vec::new(),
+            fields: vec![CustomField {
+                name: "pin".to_string(),
+                value: SecretString::from("1234"),
+                concealed: true,
+            }],
+        };
+        let theirs = CredentialSet {
+            password: SecretString::from("new secret"),
+            fields: vec![CustomField {
+                name: "pin".to_string(),
+                value: SecretString::from("5678"),
+                concealed: true,
+            }],
+            ..ours.clone()
+        };
+        let conflict = MergeConflict {
+            id: ours.id,
+            identifier: ours.identifier.clone(),
+            username: ours.username.clone(),
+            ours: Some(ours),
+            theirs: Some(theirs),
+        };
+
+        let rows = merge_conflict_rows(&conflict);
+        let fields: Vec<&str> = rows.iter().map(|row| row[0].as_str()).collect();
+        assert_eq!(fields, vec!["Password", "pin", "Modified"]);
+        for row in &rows {
+            assert!(!row
+                .iter()
+                .any(|cell| cell.contains("secret") || cell.contains("1234")));
+        }
+
+        let deleted = MergeConflict {
+            theirs: None,
+            ..conflict
+        };
+        assert_eq!(merge_conflict_rows(&deleted)[0][2], "deleted");
+    }
+
+    #[test]
+    fn test_parse_tags() {
+        assert_eq!(parse_tags(" work, email,,work , "), vec!["work", "email"]);
+        assert!(parse_tags("").is_empty());
+    }
+
+    #[test]
+    fn test_validate_custom_fields() {
+        let field = |name: &str| CustomField {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            validate_custom_fields(&[field("account id"), field("region")]),
+            Ok(())
+        );
+        assert_eq!(
+            validate_custom_fields(&[field("region"), field(" ")]),
+            Err(ArmorPassError::InvalidCustomFieldName)
+        );
+        assert_eq!(
+            validate_custom_fields(&[field("region"), field("region")]),
+            Err(ArmorPassError::InvalidCustomFieldName)
+        );
+    }
 
     #[test]
     fn test_validate_identifier() {
@@ -0,0 +1,700 @@
+use crate::encryption::CryptoError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+pub const VAULT_MAGIC: &[u8; 8] = b"ARMRPASS";
+pub const CURRENT_FORMAT_VERSION: u16 = 4;
+
+// the legacy, version 1, version 2 and version 3 layouts are frozen, these must never change
+const LEGACY_SALT_LENGTH: usize = 16;
+const LEGACY_IV_LENGTH: usize = 16;
+const LEGACY_BLOCK_SIZE: usize = 16;
+const V1_SALT_LENGTH: usize = 16;
+const V1_NONCE_LENGTH: usize = 12;
+const V1_ITERATIONS: u32 = 100_000;
+
+// OWASP's recommended minimum for argon2id, new vaults start here
+pub const ARGON2ID_DEFAULT_MEMORY_KIB: u32 = 19 * 1024;
+pub const ARGON2ID_DEFAULT_ITERATIONS: u32 = 2;
+pub const ARGON2ID_DEFAULT_PARALLELISM: u32 = 1;
+pub const DEFAULT_BACKUP_GENERATIONS: u32 = 5;
+pub const DEFAULT_PASSWORD_HISTORY_LENGTH: u32 = 5;
+
+const VERSION_LENGTH: usize = 2;
+const SECTION_LENGTH_LENGTH: usize = 4;
+const TAG_LENGTH: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum CipherId {
+    // only ever read from legacy vaults, new vaults are never written with it
+    #[serde(rename = "aes-256-cbc")]
+    Aes256Cbc,
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "id")]
+pub enum KdfParams {
+    #[serde(rename = "pbkdf2-hmac-sha256")]
+    Pbkdf2HmacSha256 { iterations: u32 },
+    #[serde(rename = "argon2id")]
+    Argon2id {
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams::Argon2id {
+            memory_kib: ARGON2ID_DEFAULT_MEMORY_KIB,
+            iterations: ARGON2ID_DEFAULT_ITERATIONS,
+            parallelism: ARGON2ID_DEFAULT_PARALLELISM,
+        }
+    }
+}
+
+impl fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KdfParams::Pbkdf2HmacSha256 { iterations } => {
+                write!(f, "PBKDF2-HMAC-SHA256 (iterations: {})", iterations)
+            }
+            KdfParams::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => write!(
+                f,
+                "Argon2id (memory: {} KiB, iterations: {}, parallelism: {})",
+                memory_kib, iterations, parallelism
+            ),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum KeySlotKind {
+    #[serde(rename = "password")]
+    Password,
+    #[serde(rename = "recovery-code")]
+    RecoveryCode,
+    #[serde(rename = "escrow-shares")]
+    EscrowShares,
+}
+
+impl fmt::Display for KeySlotKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeySlotKind::Password => write!(f, "password"),
+            KeySlotKind::RecoveryCode => write!(f, "recovery code"),
+            KeySlotKind::EscrowShares => write!(f, "escrow shares"),
+        }
+    }
+}
+
+// The data key, sealed with aes_256_gcm under a key derived from one of the secrets that can
+// unlock the vault
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KeySlot {
+    pub id: u32,
+    pub kind: KeySlotKind,
+    pub label: String,
+    pub kdf: KdfParams,
+    pub salt: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub wrapped_key: Vec<u8>,
+    // set on slots that need a key file as well as their secret
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file_check: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VaultHeader {
+    pub cipher: CipherId,
+    pub nonce: Vec<u8>,
+    #[serde(default = "default_backup_generations")]
+    pub backup_generations: u32,
+    // how many earlier passwords each entry keeps, DEFAULT_PASSWORD_HISTORY_LENGTH if it was never
+    // set. Left out when unset so vaults from before it keep the associated data they were
+    // sealed with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_history_length: Option<u32>,
+    // kept in a section of their own after the header, out of the associated data of the records,
+    // so slots can be added and revoked without re-encrypting them. Each slot is authenticated by
+    // its own wrapping instead.
+    #[serde(skip)]
+    pub key_slots: Vec<KeySlot>,
+    // vaults from before key slots sealed their records with a key derived straight from the
+    // master password, they are given a data key on their next write
+    #[serde(skip)]
+    pub legacy_key: Option<LegacyKey>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LegacyKey {
+    pub kdf: KdfParams,
+    pub salt: Vec<u8>,
+    // lets a wrong master password be told apart from a tampered vault, only version 2 vaults
+    // written after it was introduced have one
+    pub key_check: Option<Vec<u8>>,
+}
+
+// version 2 headers as they were written, read only to be normalised
+#[derive(Deserialize)]
+struct V2Header {
+    cipher: CipherId,
+    kdf: KdfParams,
+    salt: Vec<u8>,
+    nonce: Vec<u8>,
+    #[serde(default)]
+    key_check: Option<Vec<u8>>,
+    #[serde(default = "default_backup_generations")]
+    backup_generations: u32,
+}
+
+// version 3 headers as they were written, the key slots were part of the header
+#[derive(Deserialize)]
+struct V3Header {
+    cipher: CipherId,
+    nonce: Vec<u8>,
+    #[serde(default = "default_backup_generations")]
+    backup_generations: u32,
+    #[serde(default)]
+    password_history_length: Option<u32>,
+    #[serde(default)]
+    key_slots: Vec<KeySlot>,
+}
+
+// Version 3 headers as they are serialised, field by field so they can not drift with VaultHeader.
+// With the key slots emptied this is the associated data their records were sealed with.
+#[derive(Serialize)]
+struct V3HeaderFields<'a> {
+    cipher: CipherId,
+    nonce: &'a [u8],
+    backup_generations: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password_history_length: Option<u32>,
+    key_slots: &'a [KeySlot],
+}
+
+fn default_backup_generations() -> u32 {
+    DEFAULT_BACKUP_GENERATIONS
+}
+
+// A vault read from disk, whatever version it was written with. Older layouts are normalised into
+// the current VaultHeader so the rest of the code only deals with one shape, and they are rewritten
+// in the current layout on the next write.
+#[derive(Debug)]
+pub struct VaultFile {
+    pub version: u16,
+    pub header: VaultHeader,
+    pub associated_data: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+impl VaultFile {
+    pub fn parse(contents: &[u8]) -> Result<VaultFile, CryptoError> {
+        if !contents.starts_with(VAULT_MAGIC) {
+            return Self::parse_legacy(contents);
+        }
+
+        let version_offset = VAULT_MAGIC.len();
+        let version_bytes = contents
+            .get(version_offset..version_offset + VERSION_LENGTH)
+            .ok_or(CryptoError::TruncatedVault)?;
+        let version = u16::from_le_bytes([version_bytes[0], version_bytes[1]]);
+
+        match version {
+            1 => Self::parse_v1(contents),
+            2 => Self::parse_v2(contents),
+            3 => Self::parse_v3(contents),
+            4 => Self::parse_v4(contents),
+            _ => Err(CryptoError::UnsupportedVersion(version)),
+        }
+    }
+
+    // [MAGIC][VERSION][HEADER LENGTH][HEADER], the prefix new records are bound to as associated
+    // data. It is written out byte for byte as it was sealed with, never serialised again.
+    pub fn records_associated_data(header: &VaultHeader) -> Result<Vec<u8>, CryptoError> {
+        let header_json =
+            serde_json::to_vec(header).map_err(|e| CryptoError::MalformedHeader(e.to_string()))?;
+        Ok(Self::header_prefix(CURRENT_FORMAT_VERSION, &header_json))
+    }
+
+    // [ASSOCIATED DATA][KEY SLOTS LENGTH][KEY SLOTS][CIPHERTEXT][TAG], where the associated data is
+    // the prefix from records_associated_data the ciphertext was sealed with
+    pub fn serialize(
+        associated_data: &[u8],
+        key_slots: &[KeySlot],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        let key_slots_json = serde_json::to_vec(key_slots)
+            .map_err(|e| CryptoError::MalformedHeader(e.to_string()))?;
+        let mut bytes = Vec::with_capacity(
+            associated_data.len() + SECTION_LENGTH_LENGTH + key_slots_json.len() + ciphertext.len(),
+        );
+        bytes.extend_from_slice(associated_data);
+        bytes.extend_from_slice(&(key_slots_json.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&key_slots_json);
+        bytes.extend_from_slice(ciphertext);
+        Ok(bytes)
+    }
+
+    // This vault in the layout it was read in, with other key slots in place of its own and the
+    // records left as they are. None for the layouts from before key slots.
+    pub fn serialize_with_key_slots(
+        &self,
+        key_slots: &[KeySlot],
+    ) -> Result<Option<Vec<u8>>, CryptoError> {
+        match self.version {
+            3 => {
+                let mut bytes = Self::v3_header_prefix(&self.header, key_slots)?;
+                bytes.extend_from_slice(&self.ciphertext);
+                Ok(Some(bytes))
+            }
+            4 => Self::serialize(&self.associated_data, key_slots, &self.ciphertext).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    fn header_prefix(version: u16, header_json: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(
+            VAULT_MAGIC.len() + VERSION_LENGTH + SECTION_LENGTH_LENGTH + header_json.len(),
+        );
+        bytes.extend_from_slice(VAULT_MAGIC);
+        bytes.extend_from_slice(&version.to_le_bytes());
+        bytes.extend_from_slice(&(header_json.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(header_json);
+        bytes
+    }
+
+    // [SALT][IV][CIPHERTEXT] sealed with aes_256_cbc, anything that can not be that shape is not ours
+    fn parse_legacy(contents: &[u8]) -> Result<VaultFile, CryptoError> {
+        let prefix_length = LEGACY_SALT_LENGTH + LEGACY_IV_LENGTH;
+        if contents.len() <= prefix_length
+            || !(contents.len() - prefix_length).is_multiple_of(LEGACY_BLOCK_SIZE)
+        {
+            return Err(CryptoError::NotAnArmorPassVault);
+        }
+
+        let (salt, rest) = contents.split_at(LEGACY_SALT_LENGTH);
+        let (iv, ciphertext) = rest.split_at(LEGACY_IV_LENGTH);
+        Ok(VaultFile {
+            version: 0,
+            header: VaultHeader {
+                cipher: CipherId::Aes256Cbc,
+                nonce: iv.to_vec(),
+                backup_generations: DEFAULT_BACKUP_GENERATIONS,
+                password_history_length: None,
+                key_slots: Vec::new(),
+                legacy_key: Some(LegacyKey {
+                    kdf: KdfParams::Pbkdf2HmacSha256 {
+                        iterations: V1_ITERATIONS,
+                    },
+                    salt: salt.to_vec(),
+                    key_check: None,
+                }),
+            },
+            associated_data: Vec::new(),
+            ciphertext: ciphertext.to_vec(),
+        })
+    }
+
+    // [MAGIC][VERSION][SALT][NONCE][CIPHERTEXT][TAG]
+    fn parse_v1(contents: &[u8]) -> Result<VaultFile, CryptoError> {
+        let salt_offset = VAULT_MAGIC.len() + VERSION_LENGTH;
+        let nonce_offset = salt_offset + V1_SALT_LENGTH;
+        let header_end = nonce_offset + V1_NONCE_LENGTH;
+        if contents.len() < header_end + TAG_LENGTH {
+            return Err(CryptoError::TruncatedVault);
+        }
+
+        Ok(VaultFile {
+            version: 1,
+            header: VaultHeader {
+                cipher: CipherId::Aes256Gcm,
+                nonce: contents[nonce_offset..header_end].to_vec(),
+                backup_generations: DEFAULT_BACKUP_GENERATIONS,
+                password_history_length: None,
+                key_slots: Vec::new(),
+                legacy_key: Some(LegacyKey {
+                    kdf: KdfParams::Pbkdf2HmacSha256 {
+                        iterations: V1_ITERATIONS,
+                    },
+                    salt: contents[salt_offset..nonce_offset].to_vec(),
+                    key_check: None,
+                }),
+            },
+            associated_data: contents[..header_end].to_vec(),
+            ciphertext: contents[header_end..].to_vec(),
+        })
+    }
+
+    // [MAGIC][VERSION][HEADER LENGTH][HEADER][CIPHERTEXT][TAG], the whole prefix is the associated
+    // data
+    fn parse_v2(contents: &[u8]) -> Result<VaultFile, CryptoError> {
+        let (header_json, header_end) = Self::split_header(contents)?;
+        let header: V2Header = serde_json::from_slice(header_json)
+            .map_err(|e| CryptoError::MalformedHeader(e.to_string()))?;
+
+        Ok(VaultFile {
+            version: 2,
+            header: VaultHeader {
+                cipher: header.cipher,
+                nonce: header.nonce,
+                backup_generations: header.backup_generations,
+                password_history_length: None,
+                key_slots: Vec::new(),
+                legacy_key: Some(LegacyKey {
+                    kdf: header.kdf,
+                    salt: header.salt,
+                    key_check: header.key_check,
+                }),
+            },
+            associated_data: contents[..header_end].to_vec(),
+            ciphertext: contents[header_end..].to_vec(),
+        })
+    }
+
+    // [MAGIC][VERSION][HEADER LENGTH][HEADER][CIPHERTEXT][TAG], laid out like version 2 but the
+    // records are sealed with a data key held in the header's key slots
+    fn parse_v3(contents: &[u8]) -> Result<VaultFile, CryptoError> {
+        let (header_json, header_end) = Self::split_header(contents)?;
+        let header: V3Header = serde_json::from_slice(header_json)
+            .map_err(|e| CryptoError::MalformedHeader(e.to_string()))?;
+        let header = VaultHeader {
+            cipher: header.cipher,
+            nonce: header.nonce,
+            backup_generations: header.backup_generations,
+            password_history_length: header.password_history_length,
+            key_slots: header.key_slots,
+            legacy_key: None,
+        };
+
+        Ok(VaultFile {
+            version: 3,
+            associated_data: Self::v3_header_prefix(&header, &[])?,
+            header,
+            ciphertext: contents[header_end..].to_vec(),
+        })
+    }
+
+    fn v3_header_prefix(
+        header: &VaultHeader,
+        key_slots: &[KeySlot],
+    ) -> Result<Vec<u8>, CryptoError> {
+        let header_json = serde_json::to_vec(&V3HeaderFields {
+            cipher: header.cipher,
+            nonce: &header.nonce,
+            backup_generations: header.backup_generations,
+            password_history_length: header.password_history_length,
+            key_slots,
+        })
+        .map_err(|e| CryptoError::MalformedHeader(e.to_string()))?;
+        Ok(Self::header_prefix(3, &header_json))
+    }
+
+    // [MAGIC][VERSION][HEADER LENGTH][HEADER][KEY SLOTS LENGTH][KEY SLOTS][CIPHERTEXT][TAG], the
+    // key slots moved out of the header so the prefix up to it can be taken as it is on disk
+    fn parse_v4(contents: &[u8]) -> Result<VaultFile, CryptoError> {
+        let (header_json, header_end) = Self::split_header(contents)?;
+        let mut header: VaultHeader = serde_json::from_slice(header_json)
+            .map_err(|e| CryptoError::MalformedHeader(e.to_string()))?;
+        let (key_slots_json, key_slots_end) = Self::split_section(contents, header_end)?;
+        header.key_slots = serde_json::from_slice(key_slots_json)
+            .map_err(|e| CryptoError::MalformedHeader(e.to_string()))?;
+
+        Ok(VaultFile {
+            version: 4,
+            header,
+            associated_data: contents[..header_end].to_vec(),
+            ciphertext: contents[key_slots_end..].to_vec(),
+        })
+    }
+
+    // Finds the json header of a version 2 or later vault, returning it along with where it ends
+    fn split_header(contents: &[u8]) -> Result<(&[u8], usize), CryptoError> {
+        Self::split_section(contents, VAULT_MAGIC.len() + VERSION_LENGTH)
+    }
+
+    // Finds the length prefixed section starting at `offset`, returning it along with where it
+    // ends. There has to be room for at least a tag after it.
+    fn split_section(contents: &[u8], offset: usize) -> Result<(&[u8], usize), CryptoError> {
+        let section_offset = offset + SECTION_LENGTH_LENGTH;
+        let length_bytes = contents
+            .get(offset..section_offset)
+            .ok_or(CryptoError::TruncatedVault)?;
+        let section_length = u32::from_le_bytes([
+            length_bytes[0],
+            length_bytes[1],
+            length_bytes[2],
+            length_bytes[3],
+        ]) as usize;
+
+        let section_end = section_offset
+            .checked_add(section_length)
+            .ok_or(CryptoError::TruncatedVault)?;
+        if contents.len() < section_end.saturating_add(TAG_LENGTH) {
+            return Err(CryptoError::TruncatedVault);
+        }
+
+        Ok((&contents[section_offset..section_end], section_end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_slot() -> KeySlot {
+        KeySlot {
+            id: 0,
+            kind: KeySlotKind::Password,
+            label: "master password".to_string(),
+            kdf: KdfParams::Pbkdf2HmacSha256 {
+                iterations: V1_ITERATIONS,
+            },
+            salt: vec![1; 16],
+            nonce: vec![4; 12],
+            wrapped_key: vec![5; 48],
+            key_file_check: None,
+        }
+    }
+
+    fn test_header() -> VaultHeader {
+        VaultHeader {
+            cipher: CipherId::Aes256Gcm,
+            nonce: vec![2; 12],
+            backup_generations: DEFAULT_BACKUP_GENERATIONS,
+            password_history_length: None,
+            key_slots: vec![test_slot()],
+            legacy_key: None,
+        }
+    }
+
+    fn serialize(header: &VaultHeader) -> Vec<u8> {
+        let associated_data = VaultFile::records_associated_data(header).unwrap();
+        VaultFile::serialize(&associated_data, &header.key_slots, &[3; 32]).unwrap()
+    }
+
+    fn legacy_header(nonce: Vec<u8>, key_check: Option<Vec<u8>>) -> VaultHeader {
+        VaultHeader {
+            cipher: CipherId::Aes256Gcm,
+            nonce,
+            backup_generations: DEFAULT_BACKUP_GENERATIONS,
+            password_history_length: None,
+            key_slots: Vec::new(),
+            legacy_key: Some(LegacyKey {
+                kdf: KdfParams::Pbkdf2HmacSha256 {
+                    iterations: V1_ITERATIONS,
+                },
+                salt: vec![1; 16],
+                key_check,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_parse_round_trips_current_header() {
+        let header = test_header();
+        let contents = serialize(&header);
+
+        let vault_file = VaultFile::parse(&contents).unwrap();
+        assert_eq!(vault_file.version, CURRENT_FORMAT_VERSION);
+        assert_eq!(vault_file.header, header);
+        assert_eq!(
+            vault_file.associated_data,
+            VaultFile::records_associated_data(&header).unwrap()
+        );
+        assert!(contents.starts_with(&vault_file.associated_data));
+        assert_eq!(vault_file.ciphertext, vec![3; 32]);
+    }
+
+    #[test]
+    fn test_key_slots_are_not_part_of_the_records_associated_data() {
+        let header = test_header();
+        let mut more_slots = header.clone();
+        more_slots.key_slots.push(KeySlot {
+            id: 1,
+            ..test_slot()
+        });
+        let mut other_nonce = header.clone();
+        other_nonce.nonce = vec![6; 12];
+
+        let associated_data = VaultFile::records_associated_data(&header).unwrap();
+        assert_eq!(
+            VaultFile::records_associated_data(&more_slots).unwrap(),
+            associated_data
+        );
+        assert_ne!(
+            VaultFile::records_associated_data(&other_nonce).unwrap(),
+            associated_data
+        );
+    }
+
+    #[test]
+    fn test_parse_reads_kdf_parameters_from_key_slots() {
+        let mut header = test_header();
+        header.key_slots[0].kdf = KdfParams::Argon2id {
+            memory_kib: 65536,
+            iterations: 3,
+            parallelism: 4,
+        };
+        let contents = serialize(&header);
+
+        assert_eq!(
+            VaultFile::parse(&contents).unwrap().header.key_slots[0].kdf,
+            header.key_slots[0].kdf
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_random_files() {
+        assert_eq!(
+            VaultFile::parse(b"just some notes I left lying around").unwrap_err(),
+            CryptoError::NotAnArmorPassVault
+        );
+        assert_eq!(
+            VaultFile::parse(&[]).unwrap_err(),
+            CryptoError::NotAnArmorPassVault
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_truncated_vaults() {
+        let contents = serialize(&test_header());
+        let header_end = VaultFile::records_associated_data(&test_header())
+            .unwrap()
+            .len();
+
+        for length in [
+            VAULT_MAGIC.len(),
+            VAULT_MAGIC.len() + 3,
+            20,
+            header_end + 2,
+            header_end + 20,
+            contents.len() - 32,
+        ] {
+            assert_eq!(
+                VaultFile::parse(&contents[..length]).unwrap_err(),
+                CryptoError::TruncatedVault
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_rejects_unknown_versions() {
+        let mut contents = serialize(&test_header());
+        contents[VAULT_MAGIC.len()..VAULT_MAGIC.len() + VERSION_LENGTH]
+            .copy_from_slice(&99u16.to_le_bytes());
+
+        assert_eq!(
+            VaultFile::parse(&contents).unwrap_err(),
+            CryptoError::UnsupportedVersion(99)
+        );
+    }
+
+    #[test]
+    fn test_parse_reads_version_1_vaults() {
+        let mut contents = Vec::new();
+        contents.extend_from_slice(VAULT_MAGIC);
+        contents.extend_from_slice(&1u16.to_le_bytes());
+        contents.extend_from_slice(&[1; V1_SALT_LENGTH]);
+        contents.extend_from_slice(&[2; V1_NONCE_LENGTH]);
+        contents.extend_from_slice(&[3; 32]);
+
+        let vault_file = VaultFile::parse(&contents).unwrap();
+        assert_eq!(vault_file.version, 1);
+        assert_eq!(vault_file.header, legacy_header(vec![2; 12], None));
+        assert_eq!(vault_file.associated_data, contents[..contents.len() - 32]);
+    }
+
+    #[test]
+    fn test_parse_reads_version_2_vaults() {
+        let header_json = br#"{"cipher":"aes-256-gcm","kdf":{"id":"pbkdf2-hmac-sha256","iterations":100000},"salt":[1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1],"nonce":[2,2,2,2,2,2,2,2,2,2,2,2],"key_check":[7,7],"backup_generations":5}"#;
+        let mut contents = Vec::new();
+        contents.extend_from_slice(VAULT_MAGIC);
+        contents.extend_from_slice(&2u16.to_le_bytes());
+        contents.extend_from_slice(&(header_json.len() as u32).to_le_bytes());
+        contents.extend_from_slice(header_json);
+        contents.extend_from_slice(&[3; 32]);
+
+        let vault_file = VaultFile::parse(&contents).unwrap();
+        assert_eq!(vault_file.version, 2);
+        assert_eq!(
+            vault_file.header,
+            legacy_header(vec![2; 12], Some(vec![7, 7]))
+        );
+        assert_eq!(vault_file.associated_data, contents[..contents.len() - 32]);
+    }
+
+    #[test]
+    fn test_parse_reads_version_3_vaults_with_the_associated_data_they_were_sealed_with() {
+        let slot_json = serde_json::to_string(&test_slot()).unwrap();
+        let header_json = format!(
+            r#"{{"cipher":"aes-256-gcm","nonce":[2,2,2,2,2,2,2,2,2,2,2,2],"backup_generations":5,"password_history_length":3,"key_slots":[{}]}}"#,
+            slot_json
+        );
+        let mut contents = Vec::new();
+        contents.extend_from_slice(VAULT_MAGIC);
+        contents.extend_from_slice(&3u16.to_le_bytes());
+        contents.extend_from_slice(&(header_json.len() as u32).to_le_bytes());
+        contents.extend_from_slice(header_json.as_bytes());
+        contents.extend_from_slice(&[3; 32]);
+
+        let vault_file = VaultFile::parse(&contents).unwrap();
+        assert_eq!(vault_file.version, 3);
+        assert_eq!(
+            vault_file.header,
+            VaultHeader {
+                password_history_length: Some(3),
+                ..test_header()
+            }
+        );
+        assert_eq!(vault_file.ciphertext, vec![3; 32]);
+
+        let associated_json = br#"{"cipher":"aes-256-gcm","nonce":[2,2,2,2,2,2,2,2,2,2,2,2],"backup_generations":5,"password_history_length":3,"key_slots":[]}"#;
+        let mut associated_data = Vec::new();
+        associated_data.extend_from_slice(VAULT_MAGIC);
+        associated_data.extend_from_slice(&3u16.to_le_bytes());
+        associated_data.extend_from_slice(&(associated_json.len() as u32).to_le_bytes());
+        associated_data.extend_from_slice(associated_json);
+        assert_eq!(vault_file.associated_data, associated_data);
+        assert_eq!(
+            vault_file
+                .serialize_with_key_slots(&vault_file.header.key_slots)
+                .unwrap(),
+            Some(contents)
+        );
+    }
+
+    #[test]
+    fn test_key_slots_are_replaced_without_touching_the_records() {
+        let header = test_header();
+        let other_slots = vec![KeySlot {
+            id: 3,
+            ..test_slot()
+        }];
+        let vault_file = VaultFile::parse(&serialize(&header)).unwrap();
+
+        let replaced = vault_file
+            .serialize_with_key_slots(&other_slots)
+            .unwrap()
+            .unwrap();
+        let reparsed = VaultFile::parse(&replaced).unwrap();
+        assert_eq!(reparsed.header.key_slots, other_slots);
+        assert_eq!(reparsed.associated_data, vault_file.associated_data);
+        assert_eq!(reparsed.ciphertext, vault_file.ciphertext);
+
+        let legacy = VaultFile {
+            version: 2,
+            header: legacy_header(vec![2; 12], None),
+            associated_data: Vec::new(),
+            ciphertext: vec![3; 32],
+        };
+        assert_eq!(legacy.serialize_with_key_slots(&other_slots).unwrap(), None);
+    }
+}
@@ -9,19 +9,32 @@ const IDENTIFIER: &str = "website.com";
 //const SALT: [u8; 16] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
 const MASTERPASSWORD: &str = "heynowbrowncowaylmao";
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
-use ArmorPass::password_manager::CredentialSet;
-use ArmorPass::password_manager::PasswordManager;
+use ArmorPass::encryption::{CryptoError, CryptoManager};
+use ArmorPass::entry_type::EntryType;
+use ArmorPass::password_manager::{CredentialSet, CustomField};
+use ArmorPass::password_manager::{MergeSide, PasswordManager};
+use ArmorPass::recovery_code::RecoveryCode;
+use ArmorPass::secret::SecretString;
 use ArmorPass::shell::CreatePasswordOptions;
 use ArmorPass::shell::DeletePasswordOptions;
 use ArmorPass::shell::RetrieveAllOptions;
 use ArmorPass::shell::RetrieveSingleOptions;
 use ArmorPass::shell::UpdatePasswordOptions;
+use ArmorPass::storage::MemoryStorage;
+#[cfg(feature = "sqlite")]
+use ArmorPass::storage::SqliteStorage;
+use ArmorPass::storage::{FileStorage, VaultStorage};
 use ArmorPass::utility::ArmorPassError;
+use ArmorPass::vault_format::{KdfParams, DEFAULT_BACKUP_GENERATIONS};
 
 fn teardown(filepath: &PathBuf) {
     let _ = std::fs::remove_file(filepath);
+    for generation in 1..=DEFAULT_BACKUP_GENERATIONS {
+        let _ = std::fs::remove_file(format!("{}.{}", filepath.display(), generation));
+    }
+    let _ = std::fs::remove_file(format!("{}.lock", filepath.display()));
 }
 
 fn generate_unique_file_path() -> PathBuf {
@@ -35,7 +48,8 @@ fn store_identifier1_user1_password1(
     let options = CreatePasswordOptions {
         identifier: IDENTIFIER.to_string(),
         username: USERNAME.to_string(),
-        password: PASSWORD.to_string(),
+        password: SecretString::from(PASSWORD),
+        ..Default::default()
     };
     password_manager.store_password(&options)
 }
@@ -56,7 +70,8 @@ fn update_identifier1_user1_password1(
     let options = UpdatePasswordOptions {
         identifier: IDENTIFIER.to_string(),
         username: USERNAME.to_string(),
-        password: NEW_PASSWORD.to_string(),
+        password: SecretString::from(NEW_PASSWORD),
+        ..Default::default()
     };
     password_manager.update_password(&options)
 }
@@ -67,7 +82,8 @@ fn store_identifier1_user2_password2(
     let options = CreatePasswordOptions {
         identifier: IDENTIFIER.to_string(),
         username: USERNAME2.to_string(),
-        password: PASSWORD2.to_string(),
+        password: SecretString::from(PASSWORD2),
+        ..Default::default()
     };
     password_manager.store_password(&options)
 }
@@ -101,7 +117,7 @@ fn it_stores_a_password() {
     let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
         .expect("could not create password manager");
     let result = store_identifier1_user1_password1(&mut password_manager);
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     assert!(password_manager.has_password(IDENTIFIER, USERNAME));
     teardown(&tmpfile);
 }
@@ -342,3 +358,1399 @@ fn it_does_not_allow_identical_passwords() {
 //     );
 //     teardown(&tmpfile);
 // }
+
+#[test]
+fn it_reencrypts_the_vault_with_new_kdf_settings() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+    let kdf = KdfParams::Argon2id {
+        memory_kib: 8 * 1024,
+        iterations: 1,
+        parallelism: 2,
+    };
+
+    assert_eq!(
+        password_manager.change_kdf("not the master password", kdf.clone()),
+        Err(ArmorPassError::WrongMasterPassword),
+        "KDF settings should not change without the correct master password."
+    );
+    assert_eq!(
+        password_manager.change_kdf(MASTERPASSWORD, kdf.clone()),
+        Ok(())
+    );
+    drop(password_manager);
+
+    let mut reopened = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not reopen password manager");
+    assert_eq!(reopened.kdf_params(), &kdf);
+    assert_eq!(
+        retrieve_identifier1_user1(&mut reopened).unwrap().password,
+        PASSWORD
+    );
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_changes_the_master_password() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+
+    assert_eq!(
+        password_manager.change_master_password("not the master password", NEW_PASSWORD),
+        Err(ArmorPassError::WrongMasterPassword),
+        "The master password should not change without the current one."
+    );
+    assert_eq!(
+        password_manager.change_master_password(MASTERPASSWORD, NEW_PASSWORD),
+        Ok(())
+    );
+    drop(password_manager);
+
+    assert!(
+        PasswordManager::new(tmpfile.clone(), MASTERPASSWORD).is_err(),
+        "The old master password should no longer unlock the vault."
+    );
+    let mut reopened = PasswordManager::new(tmpfile.clone(), NEW_PASSWORD)
+        .expect("could not reopen password manager with the new master password");
+    assert_eq!(
+        retrieve_identifier1_user1(&mut reopened).unwrap().password,
+        PASSWORD
+    );
+    assert!(
+        !PathBuf::from(format!("{}.tmp", tmpfile.display())).exists(),
+        "No temporary vault should be left behind."
+    );
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_rejects_a_wrong_master_password_on_an_empty_vault() {
+    let tmpfile = generate_unique_file_path();
+    let password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    assert!(
+        tmpfile.exists(),
+        "A new vault should be written straight away."
+    );
+    drop(password_manager);
+
+    let error = PasswordManager::new(tmpfile.clone(), "not the master password")
+        .err()
+        .expect("A wrong master password should not unlock an empty vault.");
+    assert_eq!(
+        error.downcast_ref::<CryptoError>(),
+        Some(&CryptoError::WrongMasterPassword)
+    );
+    assert!(PasswordManager::new(tmpfile.clone(), MASTERPASSWORD).is_ok());
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_restores_a_backup() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+    let _ = store_identifier1_user2_password2(&mut password_manager);
+
+    assert_eq!(
+        password_manager.restore_backup(1, "not the master password"),
+        Err(ArmorPassError::WrongMasterPassword)
+    );
+    assert_eq!(
+        password_manager.restore_backup(DEFAULT_BACKUP_GENERATIONS + 1, MASTERPASSWORD),
+        Err(ArmorPassError::NoBackupFound)
+    );
+
+    // backup 1 is the vault as it was before the second credential was stored
+    assert_eq!(password_manager.restore_backup(1, MASTERPASSWORD), Ok(()));
+    assert!(password_manager.has_password(IDENTIFIER, USERNAME));
+    assert!(!password_manager.has_password(IDENTIFIER, USERNAME2));
+    drop(password_manager);
+
+    let reopened = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not reopen password manager");
+    assert!(!reopened.has_password(IDENTIFIER, USERNAME2));
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_refuses_a_second_writer_but_allows_a_read_only_open() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+
+    let error = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .err()
+        .expect("A second instance should not be able to open the vault for writing.");
+    assert!(matches!(
+        error.downcast_ref::<CryptoError>(),
+        Some(CryptoError::VaultLocked(_))
+    ));
+
+    let mut read_only = PasswordManager::open_read_only(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not open password manager read-only");
+    assert!(read_only.is_read_only());
+    assert!(read_only.has_password(IDENTIFIER, USERNAME));
+    assert_eq!(
+        store_identifier1_user2_password2(&mut read_only),
+        Err(ArmorPassError::ReadOnly),
+        "A read-only vault should not accept changes."
+    );
+    teardown(&tmpfile);
+}
+
+// edits a copy of the vault elsewhere and puts it back, the way a sync tool would
+fn change_vault_behind_its_back(tmpfile: &PathBuf, change: impl FnOnce(&mut PasswordManager)) {
+    let other_copy = generate_unique_file_path();
+    std::fs::copy(tmpfile, &other_copy).unwrap();
+    let mut other = PasswordManager::new(other_copy.clone(), MASTERPASSWORD)
+        .expect("could not open the other copy");
+    change(&mut other);
+    drop(other);
+    std::fs::copy(&other_copy, tmpfile).unwrap();
+    teardown(&other_copy);
+}
+
+fn update_identifier1_user1(password_manager: &mut PasswordManager, password: &str) {
+    let options = UpdatePasswordOptions {
+        identifier: IDENTIFIER.to_string(),
+        username: USERNAME.to_string(),
+        password: SecretString::from(password),
+        ..Default::default()
+    };
+    assert_eq!(password_manager.update_password(&options), Ok(()));
+}
+
+#[test]
+fn it_merges_changes_made_to_the_vault_on_disk() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+    let _ = store_identifier1_user2_password2(&mut password_manager);
+
+    change_vault_behind_its_back(&tmpfile, |other| {
+        let delete_options = DeletePasswordOptions {
+            identifier: IDENTIFIER.to_string(),
+            username: USERNAME2.to_string(),
+        };
+        assert_eq!(other.delete_credential(&delete_options), Ok(()));
+        let create_options = CreatePasswordOptions {
+            identifier: "othersite.com".to_string(),
+            username: "muhthirduser".to_string(),
+            password: SecretString::from(PASSWORD2),
+            ..Default::default()
+        };
+        assert_eq!(other.store_password(&create_options), Ok(()));
+    });
+
+    update_identifier1_user1(&mut password_manager, NEW_PASSWORD);
+    drop(password_manager);
+
+    let mut reopened = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not reopen password manager");
+    assert_eq!(
+        retrieve_identifier1_user1(&mut reopened).unwrap().password,
+        NEW_PASSWORD,
+        "The change made here should be kept."
+    );
+    assert!(
+        !reopened.has_password(IDENTIFIER, USERNAME2),
+        "The entry deleted on disk should stay deleted."
+    );
+    assert!(
+        reopened.has_password("othersite.com", "muhthirduser"),
+        "The entry added on disk should be kept."
+    );
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_asks_which_side_wins_when_both_changed_the_same_entry() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+
+    change_vault_behind_its_back(&tmpfile, |other| {
+        update_identifier1_user1(other, PASSWORD2);
+    });
+
+    password_manager.set_conflict_resolver(|conflict| {
+        assert_eq!(conflict.ours.as_ref().unwrap().password, NEW_PASSWORD);
+        assert_eq!(conflict.theirs.as_ref().unwrap().password, PASSWORD2);
+        MergeSide::Theirs
+    });
+    update_identifier1_user1(&mut password_manager, NEW_PASSWORD);
+    assert_eq!(
+        retrieve_identifier1_user1(&mut password_manager)
+            .unwrap()
+            .password,
+        PASSWORD2
+    );
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_keeps_a_key_slot_revoked_on_disk_revoked() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+    let spare_slot = password_manager
+        .add_password_slot(MASTERPASSWORD, "spare", NEW_PASSWORD)
+        .expect("could not add a key slot");
+
+    change_vault_behind_its_back(&tmpfile, |other| {
+        assert_eq!(other.revoke_key_slot(MASTERPASSWORD, spare_slot), Ok(()));
+    });
+
+    let _ = store_identifier1_user2_password2(&mut password_manager);
+    assert_eq!(password_manager.key_slots().len(), 1);
+    drop(password_manager);
+
+    assert!(
+        PasswordManager::new(tmpfile.clone(), NEW_PASSWORD).is_err(),
+        "A password revoked on disk should not come back with our next write."
+    );
+    let reopened = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not reopen password manager");
+    assert!(reopened.has_password(IDENTIFIER, USERNAME2));
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_refuses_to_write_when_the_unlocking_slot_was_revoked_on_disk() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+    let spare_slot = password_manager
+        .add_password_slot(MASTERPASSWORD, "spare", NEW_PASSWORD)
+        .expect("could not add a key slot");
+    drop(password_manager);
+
+    let mut spare = PasswordManager::new(tmpfile.clone(), NEW_PASSWORD)
+        .expect("could not open with the spare password");
+    change_vault_behind_its_back(&tmpfile, |other| {
+        assert_eq!(other.revoke_key_slot(MASTERPASSWORD, spare_slot), Ok(()));
+    });
+    assert!(matches!(
+        store_identifier1_user2_password2(&mut spare),
+        Err(ArmorPassError::FailedToPersistToDisk(_))
+    ));
+    drop(spare);
+
+    assert!(
+        PasswordManager::new(tmpfile.clone(), NEW_PASSWORD).is_err(),
+        "The revoked password should not have been written back."
+    );
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_merges_settings_changed_on_disk() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+
+    change_vault_behind_its_back(&tmpfile, |other| {
+        assert_eq!(other.set_backup_generations(7), Ok(()));
+    });
+    assert_eq!(password_manager.set_password_history_length(2), Ok(()));
+    assert_eq!(password_manager.backup_generations(), 7);
+
+    change_vault_behind_its_back(&tmpfile, |other| {
+        assert_eq!(other.set_password_history_length(9), Ok(()));
+    });
+    let _ = store_identifier1_user2_password2(&mut password_manager);
+    drop(password_manager);
+
+    let reopened = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not reopen password manager");
+    assert_eq!(
+        reopened.backup_generations(),
+        7,
+        "The backup setting changed on disk should be kept."
+    );
+    assert_eq!(
+        reopened.password_history_length(),
+        9,
+        "The history length changed on disk should be kept."
+    );
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_unlocks_with_an_added_password_until_it_is_revoked() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+
+    assert_eq!(
+        password_manager.add_password_slot("not the master password", "spare", NEW_PASSWORD),
+        Err(ArmorPassError::WrongMasterPassword)
+    );
+    let spare_slot = password_manager
+        .add_password_slot(MASTERPASSWORD, "spare", NEW_PASSWORD)
+        .expect("could not add a key slot");
+    assert_eq!(password_manager.key_slots().len(), 2);
+    drop(password_manager);
+
+    let mut spare = PasswordManager::new(tmpfile.clone(), NEW_PASSWORD)
+        .expect("The added password should unlock the vault.");
+    assert_eq!(
+        retrieve_identifier1_user1(&mut spare).unwrap().password,
+        PASSWORD
+    );
+    assert_eq!(
+        spare.revoke_key_slot(NEW_PASSWORD, spare_slot),
+        Err(ArmorPassError::KeySlotInUse)
+    );
+    drop(spare);
+
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not reopen password manager");
+    assert_eq!(
+        password_manager.revoke_key_slot(MASTERPASSWORD, spare_slot + 1),
+        Err(ArmorPassError::NoKeySlotFound)
+    );
+    assert_eq!(
+        password_manager.revoke_key_slot(MASTERPASSWORD, spare_slot),
+        Ok(())
+    );
+    drop(password_manager);
+
+    assert!(
+        PasswordManager::new(tmpfile.clone(), NEW_PASSWORD).is_err(),
+        "A revoked password should no longer unlock the vault."
+    );
+    teardown(&tmpfile);
+}
+
+fn backup_path(tmpfile: &Path, generation: u32) -> PathBuf {
+    PathBuf::from(format!("{}.{}", tmpfile.display(), generation))
+}
+
+#[test]
+fn it_takes_a_revoked_password_out_of_the_backups_too() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let spare_slot = password_manager
+        .add_password_slot(MASTERPASSWORD, "spare", NEW_PASSWORD)
+        .expect("could not add a key slot");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+    let _ = store_identifier1_user2_password2(&mut password_manager);
+    assert!(PasswordManager::open_read_only(backup_path(&tmpfile, 1), NEW_PASSWORD).is_ok());
+
+    assert_eq!(
+        password_manager.revoke_key_slot(MASTERPASSWORD, spare_slot),
+        Ok(())
+    );
+    let backups = password_manager.backups().unwrap();
+    assert!(backups.len() >= 3);
+    for backup in backups {
+        let path = backup_path(&tmpfile, backup.generation);
+        assert!(
+            PasswordManager::open_read_only(path.clone(), NEW_PASSWORD).is_err(),
+            "A revoked password should not open backup {} either.",
+            backup.generation
+        );
+        assert!(PasswordManager::open_read_only(path, MASTERPASSWORD).is_ok());
+    }
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_stops_the_old_master_password_opening_the_backups() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+    assert_eq!(
+        password_manager.change_master_password(MASTERPASSWORD, NEW_PASSWORD),
+        Ok(())
+    );
+
+    for backup in password_manager.backups().unwrap() {
+        let path = backup_path(&tmpfile, backup.generation);
+        assert!(PasswordManager::open_read_only(path.clone(), MASTERPASSWORD).is_err());
+        assert!(PasswordManager::open_read_only(path, NEW_PASSWORD).is_ok());
+    }
+    assert_eq!(password_manager.restore_backup(1, NEW_PASSWORD), Ok(()));
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_keeps_needing_the_key_file_after_a_password_change() {
+    let tmpfile = generate_unique_file_path();
+    let key_file = vec![7u8; 64];
+    let mut password_manager =
+        PasswordManager::with_key_file(tmpfile.clone(), MASTERPASSWORD, Some(&key_file))
+            .expect("could not create password manager");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+    assert_eq!(
+        password_manager.change_master_password(MASTERPASSWORD, NEW_PASSWORD),
+        Ok(())
+    );
+    drop(password_manager);
+
+    let error = PasswordManager::new(tmpfile.clone(), NEW_PASSWORD)
+        .err()
+        .expect("The vault should not open without its key file.");
+    assert_eq!(
+        error.downcast_ref::<CryptoError>(),
+        Some(&CryptoError::KeyFileMissingOrWrong)
+    );
+    let mut reopened =
+        PasswordManager::with_key_file(tmpfile.clone(), NEW_PASSWORD, Some(&key_file))
+            .expect("could not reopen password manager with the key file");
+    assert_eq!(
+        retrieve_identifier1_user1(&mut reopened).unwrap().password,
+        PASSWORD
+    );
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_resets_a_forgotten_master_password_with_the_recovery_code() {
+    let tmpfile = generate_unique_file_path();
+    let key_file = vec![7u8; 64];
+    let mut password_manager =
+        PasswordManager::with_key_file(tmpfile.clone(), MASTERPASSWORD, Some(&key_file))
+            .expect("could not create password manager");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+    let recovery_code = password_manager
+        .add_recovery_code(MASTERPASSWORD)
+        .expect("could not add a recovery code");
+    drop(password_manager);
+
+    let wrong_code = RecoveryCode::generate().unwrap();
+    let error = PasswordManager::with_recovery_code(tmpfile.clone(), &wrong_code, NEW_PASSWORD)
+        .err()
+        .expect("Another recovery code should not unlock the vault.");
+    assert_eq!(
+        error.downcast_ref::<CryptoError>(),
+        Some(&CryptoError::WrongRecoveryCode)
+    );
+
+    let typed_back = RecoveryCode::parse(&recovery_code.to_string().to_lowercase()).unwrap();
+    let mut recovered =
+        PasswordManager::with_recovery_code(tmpfile.clone(), &typed_back, NEW_PASSWORD)
+            .expect("The recovery code should unlock the vault without the key file.");
+    assert_eq!(
+        retrieve_identifier1_user1(&mut recovered).unwrap().password,
+        PASSWORD
+    );
+    drop(recovered);
+
+    assert!(
+        PasswordManager::with_key_file(tmpfile.clone(), MASTERPASSWORD, Some(&key_file)).is_err(),
+        "The forgotten master password should no longer unlock the vault."
+    );
+    let mut reopened = PasswordManager::new(tmpfile.clone(), NEW_PASSWORD)
+        .expect("The new master password should unlock the vault.");
+    assert_eq!(
+        retrieve_identifier1_user1(&mut reopened).unwrap().password,
+        PASSWORD
+    );
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_resets_the_master_password_with_enough_escrow_shares() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+    assert_eq!(
+        password_manager.split_into_escrow_shares(MASTERPASSWORD, 4, 3),
+        Err(ArmorPassError::InvalidEscrowThreshold)
+    );
+    let shares = password_manager
+        .split_into_escrow_shares(MASTERPASSWORD, 2, 3)
+        .expect("could not split the vault key");
+    drop(password_manager);
+
+    let error = PasswordManager::with_escrow_shares(tmpfile.clone(), &shares[..1], NEW_PASSWORD)
+        .err()
+        .expect("One share should not be enough to unlock the vault.");
+    assert!(matches!(
+        error.downcast_ref::<CryptoError>(),
+        Some(CryptoError::InvalidEscrowShare(_))
+    ));
+
+    let two_leads = [shares[2].clone(), shares[0].clone()];
+    let mut recovered =
+        PasswordManager::with_escrow_shares(tmpfile.clone(), &two_leads, NEW_PASSWORD)
+            .expect("Two of the three shares should unlock the vault.");
+    assert_eq!(
+        retrieve_identifier1_user1(&mut recovered).unwrap().password,
+        PASSWORD
+    );
+    recovered
+        .split_into_escrow_shares(NEW_PASSWORD, 2, 3)
+        .expect("could not split the vault key again");
+    drop(recovered);
+
+    assert!(
+        PasswordManager::new(tmpfile.clone(), MASTERPASSWORD).is_err(),
+        "The old master password should no longer unlock the vault."
+    );
+    let error = PasswordManager::with_escrow_shares(tmpfile.clone(), &two_leads, MASTERPASSWORD)
+        .err()
+        .expect("Shares from an earlier split should no longer unlock the vault.");
+    assert_eq!(
+        error.downcast_ref::<CryptoError>(),
+        Some(&CryptoError::WrongEscrowShares)
+    );
+    let mut reopened = PasswordManager::new(tmpfile.clone(), NEW_PASSWORD)
+        .expect("The new master password should unlock the vault.");
+    assert_eq!(
+        retrieve_identifier1_user1(&mut reopened).unwrap().password,
+        PASSWORD
+    );
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_keeps_passwords_out_of_debug_output_and_masked_listings() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+    let _ = store_identifier1_user2_password2(&mut password_manager);
+
+    let credential = retrieve_identifier1_user1(&mut password_manager).unwrap();
+    let debug = format!("{:?}", credential);
+    assert!(!debug.contains(PASSWORD), "{}", debug);
+    assert!(debug.contains("[REDACTED]"));
+    assert_eq!(credential.password.expose(), PASSWORD);
+
+    let options = RetrieveAllOptions {
+        identifier: IDENTIFIER.to_string(),
+    };
+    let masked = password_manager.retrieve_all_credentials_masked(&options);
+    assert_eq!(masked.len(), 2);
+    assert_eq!(masked[0].password, masked[1].password);
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_keeps_a_vault_in_any_storage() {
+    let storage = MemoryStorage::new();
+    let mut password_manager = PasswordManager::with_storage(storage.clone(), MASTERPASSWORD, None)
+        .expect("could not create password manager");
+    let options = CreatePasswordOptions {
+        identifier: IDENTIFIER.to_string(),
+        username: USERNAME.to_string(),
+        password: SecretString::from(PASSWORD),
+        ..Default::default()
+    };
+    assert_eq!(password_manager.store_password(&options), Ok(()));
+
+    let error = PasswordManager::with_storage(storage.clone(), MASTERPASSWORD, None)
+        .err()
+        .expect("A second instance should not be able to open the vault for writing.");
+    assert!(matches!(
+        error.downcast_ref::<CryptoError>(),
+        Some(CryptoError::VaultLocked(_))
+    ));
+    assert_eq!(password_manager.backups().unwrap().len(), 1);
+    drop(password_manager);
+
+    let reopened = PasswordManager::with_storage(storage.clone(), MASTERPASSWORD, None)
+        .expect("could not reopen password manager");
+    assert!(reopened.has_password(IDENTIFIER, USERNAME));
+    assert!(
+        PasswordManager::open_storage_read_only(storage, "not the master password", None).is_err()
+    );
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn it_converts_a_vault_to_sqlite_and_back() {
+    let tmpfile = generate_unique_file_path();
+    let database = tmpfile.with_extension("db");
+    let converted_back = generate_unique_file_path();
+
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    for (username, password) in [(USERNAME, PASSWORD), (USERNAME2, PASSWORD2)] {
+        let options = CreatePasswordOptions {
+            identifier: IDENTIFIER.to_string(),
+            username: username.to_string(),
+            password: SecretString::from(password),
+            ..Default::default()
+        };
+        assert_eq!(password_manager.store_password(&options), Ok(()));
+    }
+    let recovery_code = password_manager
+        .add_recovery_code(MASTERPASSWORD)
+        .expect("could not add a recovery code");
+
+    let sqlite = password_manager
+        .convert_into(SqliteStorage::open(database.clone()).unwrap())
+        .expect("could not convert to sqlite");
+    drop(sqlite);
+    let error = password_manager
+        .convert_into(SqliteStorage::open(database.clone()).unwrap())
+        .err()
+        .expect("Converting into a database that already holds a vault should fail.");
+    assert!(error.to_string().contains("already"));
+    drop(password_manager);
+
+    // each change to the converted vault writes the entry's row alone
+    let mut sqlite = PasswordManager::with_storage(
+        SqliteStorage::open(database.clone()).unwrap(),
+        MASTERPASSWORD,
+        None,
+    )
+    .expect("could not open the converted vault");
+    assert_eq!(
+        sqlite
+            .retrieve_all_credentials(&RetrieveAllOptions {
+                identifier: IDENTIFIER.to_string()
+            })
+            .len(),
+        2
+    );
+    let update_options = UpdatePasswordOptions {
+        identifier: IDENTIFIER.to_string(),
+        username: USERNAME.to_string(),
+        password: SecretString::from(NEW_PASSWORD),
+        ..Default::default()
+    };
+    assert_eq!(sqlite.update_password(&update_options), Ok(()));
+    let delete_options = DeletePasswordOptions {
+        identifier: IDENTIFIER.to_string(),
+        username: USERNAME2.to_string(),
+    };
+    assert_eq!(sqlite.delete_credential(&delete_options), Ok(()));
+    drop(sqlite);
+
+    let storage = SqliteStorage::open(database.clone()).unwrap();
+    assert_eq!(storage.entries().unwrap().load_entries().unwrap().len(), 1);
+    let reopened =
+        PasswordManager::with_storage_and_recovery_code(storage, &recovery_code, MASTERPASSWORD)
+            .expect("the recovery code should carry over");
+    assert!(!reopened.has_password(IDENTIFIER, USERNAME2));
+
+    let file = reopened
+        .convert_into(FileStorage::new(converted_back.clone()))
+        .expect("could not convert back to a single file");
+    drop(file);
+    drop(reopened);
+
+    let password_manager = PasswordManager::new(converted_back.clone(), MASTERPASSWORD)
+        .expect("could not open the vault converted back");
+    let retrieve_options = RetrieveSingleOptions {
+        identifier: IDENTIFIER.to_string(),
+        username: USERNAME.to_string(),
+    };
+    assert_eq!(
+        password_manager
+            .retrieve_credential(&retrieve_options)
+            .unwrap()
+            .password,
+        NEW_PASSWORD
+    );
+    assert!(!password_manager.has_password(IDENTIFIER, USERNAME2));
+
+    teardown(&tmpfile);
+    teardown(&converted_back);
+    let _ = std::fs::remove_file(&database);
+    let _ = std::fs::remove_file(format!("{}.lock", database.display()));
+}
+
+// the shell picks its storage at run time and holds it boxed
+#[cfg(feature = "sqlite")]
+#[test]
+fn it_converts_a_vault_held_in_boxed_storage() {
+    let tmpfile = generate_unique_file_path();
+    let database = tmpfile.with_extension("db");
+
+    let file: Box<dyn VaultStorage> = Box::new(FileStorage::new(tmpfile.clone()));
+    let mut password_manager = PasswordManager::with_storage(file, MASTERPASSWORD, None)
+        .expect("could not create password manager");
+    let options = CreatePasswordOptions {
+        identifier: IDENTIFIER.to_string(),
+        username: USERNAME.to_string(),
+        password: SecretString::from(PASSWORD),
+        ..Default::default()
+    };
+    assert_eq!(password_manager.store_password(&options), Ok(()));
+    assert!(password_manager.keeps_backups());
+
+    let database_storage: Box<dyn VaultStorage> =
+        Box::new(SqliteStorage::open(database.clone()).unwrap());
+    drop(
+        password_manager
+            .convert_into(database_storage)
+            .expect("could not convert to sqlite"),
+    );
+    drop(password_manager);
+
+    let database_storage: Box<dyn VaultStorage> =
+        Box::new(SqliteStorage::open(database.clone()).unwrap());
+    let sqlite = PasswordManager::with_storage(database_storage, MASTERPASSWORD, None)
+        .expect("could not open the converted vault");
+    assert!(sqlite.has_password(IDENTIFIER, USERNAME));
+    assert!(!sqlite.keeps_backups());
+    drop(sqlite);
+    let storage = SqliteStorage::open(database.clone()).unwrap();
+    assert_eq!(storage.entries().unwrap().load_entries().unwrap().len(), 1);
+
+    teardown(&tmpfile);
+    let _ = std::fs::remove_file(&database);
+    let _ = std::fs::remove_file(format!("{}.lock", database.display()));
+}
+
+#[test]
+fn it_loads_entries_stored_before_urls_notes_tags_and_timestamps() {
+    let credential: CredentialSet = serde_json::from_str(
+        r#"{"identifier":"website.com","username":"muhusername","password":"p@&^ssW07Rd1Afe"}"#,
+    )
+    .expect("an entry from an older vault should still load");
+    assert_eq!(credential.password, PASSWORD);
+    assert!(credential.url.is_empty() && credential.notes.is_empty());
+    assert!(credential.tags.is_empty());
+    assert_eq!(credential.created, None);
+    assert_eq!(credential.last_used, None);
+}
+
+#[test]
+fn it_keeps_urls_notes_tags_and_timestamps() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let options = CreatePasswordOptions {
+        identifier: IDENTIFIER.to_string(),
+        username: USERNAME.to_string(),
+        password: SecretString::from(PASSWORD),
+        url: "https://website.com/login".to_string(),
+        notes: "the old account".to_string(),
+        tags: vec!["work".to_string(), "email".to_string()],
+        ..Default::default()
+    };
+    assert_eq!(password_manager.store_password(&options), Ok(()));
+    let retrieve_options = RetrieveSingleOptions {
+        identifier: IDENTIFIER.to_string(),
+        username: USERNAME.to_string(),
+    };
+    let created = password_manager
+        .retrieve_credential(&retrieve_options)
+        .unwrap()
+        .clone();
+    assert!(created.created.is_some());
+    assert_eq!(created.modified, created.created);
+    assert_eq!(created.last_used, None);
+
+    // only the fields that are given change
+    let update_options = UpdatePasswordOptions {
+        identifier: IDENTIFIER.to_string(),
+        username: USERNAME.to_string(),
+        password: SecretString::from(NEW_PASSWORD),
+        notes: Some("the new account".to_string()),
+        tags: Some(Vec::new()),
+        ..Default::default()
+    };
+    assert_eq!(password_manager.update_password(&update_options), Ok(()));
+    assert_eq!(password_manager.record_use(IDENTIFIER, USERNAME), Ok(()));
+    assert_eq!(
+        password_manager.record_use(IDENTIFIER, USERNAME2),
+        Err(ArmorPassError::NoRecordFound)
+    );
+    drop(password_manager);
+
+    let password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not reopen password manager");
+    let updated = password_manager
+        .retrieve_credential(&retrieve_options)
+        .unwrap();
+    assert_eq!(updated.url, "https://website.com/login");
+    assert_eq!(updated.notes, "the new account");
+    assert!(updated.tags.is_empty());
+    assert_eq!(updated.created, created.created);
+    assert!(updated.modified >= created.modified);
+    assert!(updated.last_used.is_some());
+
+    let masked = password_manager.retrieve_all_credentials_masked(&RetrieveAllOptions {
+        identifier: IDENTIFIER.to_string(),
+    });
+    assert_eq!(masked[0].url, "https://website.com/login");
+    assert_eq!(masked[0].last_used, updated.last_used);
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_records_a_use_without_pushing_out_the_backups() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+    let _ = store_identifier1_user2_password2(&mut password_manager);
+    let backups = password_manager.backups().unwrap().len();
+    let newest_backup = std::fs::read(backup_path(&tmpfile, 1)).unwrap();
+
+    for _ in 0..DEFAULT_BACKUP_GENERATIONS + 1 {
+        assert_eq!(password_manager.record_use(IDENTIFIER, USERNAME), Ok(()));
+    }
+    assert_eq!(password_manager.backups().unwrap().len(), backups);
+    assert_eq!(
+        std::fs::read(backup_path(&tmpfile, 1)).unwrap(),
+        newest_backup,
+        "Recording a use should not rotate the backups."
+    );
+    drop(password_manager);
+
+    let mut reopened = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not reopen password manager");
+    assert!(retrieve_identifier1_user1(&mut reopened)
+        .unwrap()
+        .last_used
+        .is_some());
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_gives_every_entry_an_id_that_survives_a_rename() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+    let _ = store_identifier1_user2_password2(&mut password_manager);
+    let retrieve_options = RetrieveSingleOptions {
+        identifier: IDENTIFIER.to_string(),
+        username: USERNAME.to_string(),
+    };
+    let id = password_manager
+        .retrieve_credential(&retrieve_options)
+        .unwrap()
+        .id;
+    assert!(!id.is_nil());
+
+    let rename_options = UpdatePasswordOptions {
+        identifier: "renamed.com".to_string(),
+        username: USERNAME.to_string(),
+        password: SecretString::from(NEW_PASSWORD),
+        ..Default::default()
+    };
+    assert_eq!(
+        password_manager.update_credential_by_id(id, &rename_options),
+        Ok(())
+    );
+    let taken_options = UpdatePasswordOptions {
+        username: USERNAME2.to_string(),
+        ..rename_options
+    };
+    assert_eq!(
+        password_manager.update_credential_by_id(id, &taken_options),
+        Err(ArmorPassError::CreateDuplicateUsername)
+    );
+    drop(password_manager);
+
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not reopen password manager");
+    assert!(!password_manager.has_password(IDENTIFIER, USERNAME));
+    let renamed = password_manager.retrieve_credential_by_id(id).unwrap();
+    assert_eq!(renamed.identifier, "renamed.com");
+    assert_eq!(renamed.password, NEW_PASSWORD);
+
+    assert_eq!(password_manager.delete_credential_by_id(id), Ok(()));
+    assert_eq!(
+        password_manager.delete_credential_by_id(id),
+        Err(ArmorPassError::NoRecordFound)
+    );
+    assert!(password_manager.has_password(IDENTIFIER, USERNAME2));
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_backfills_ids_for_entries_stored_before_them() {
+    let tmpfile = generate_unique_file_path();
+    let mut crypto_manager =
+        CryptoManager::new(&tmpfile, MASTERPASSWORD).expect("could not create crypto manager");
+    crypto_manager
+        .encrypt_and_persist(
+            br#"[{"identifier":"website.com","username":"muhusername","password":"p@&^ssW07Rd1Afe"}]"#,
+        )
+        .unwrap();
+    drop(crypto_manager);
+
+    let retrieve_options = RetrieveSingleOptions {
+        identifier: IDENTIFIER.to_string(),
+        username: USERNAME.to_string(),
+    };
+    let password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not open password manager");
+    let id = password_manager
+        .retrieve_credential(&retrieve_options)
+        .unwrap()
+        .id;
+    assert!(!id.is_nil());
+    drop(password_manager);
+
+    // the id was written back, it is the same the next time round
+    let password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not reopen password manager");
+    assert_eq!(
+        password_manager
+            .retrieve_credential_by_id(id)
+            .unwrap()
+            .password,
+        PASSWORD
+    );
+    teardown(&tmpfile);
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn it_moves_the_row_of_an_entry_renamed_in_sqlite() {
+    let database = generate_unique_file_path().with_extension("db");
+    let mut password_manager = PasswordManager::with_storage(
+        SqliteStorage::open(database.clone()).unwrap(),
+        MASTERPASSWORD,
+        None,
+    )
+    .expect("could not create password manager");
+    let create_options = CreatePasswordOptions {
+        identifier: IDENTIFIER.to_string(),
+        username: USERNAME.to_string(),
+        password: SecretString::from(PASSWORD),
+        ..Default::default()
+    };
+    assert_eq!(password_manager.store_password(&create_options), Ok(()));
+    let id = password_manager
+        .retrieve_credential(&RetrieveSingleOptions {
+            identifier: IDENTIFIER.to_string(),
+            username: USERNAME.to_string(),
+        })
+        .unwrap()
+        .id;
+    let rename_options = UpdatePasswordOptions {
+        identifier: "renamed.com".to_string(),
+        username: USERNAME.to_string(),
+        password: SecretString::from(PASSWORD),
+        ..Default::default()
+    };
+    assert_eq!(
+        password_manager.update_credential_by_id(id, &rename_options),
+        Ok(())
+    );
+    drop(password_manager);
+
+    let storage = SqliteStorage::open(database.clone()).unwrap();
+    assert_eq!(storage.entries().unwrap().load_entries().unwrap().len(), 1);
+    let password_manager = PasswordManager::with_storage(storage, MASTERPASSWORD, None)
+        .expect("could not reopen password manager");
+    assert!(password_manager.has_password("renamed.com", USERNAME));
+    assert_eq!(
+        password_manager
+            .retrieve_credential_by_id(id)
+            .unwrap()
+            .identifier,
+        "renamed.com"
+    );
+    let _ = std::fs::remove_file(&database);
+    let _ = std::fs::remove_file(format!("{}.lock", database.display()));
+}
+
+#[test]
+fn it_keeps_a_bounded_history_of_earlier_passwords() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+    assert_eq!(password_manager.password_history_length(), 5);
+    for round in 0..7 {
+        let options = UpdatePasswordOptions {
+            identifier: IDENTIFIER.to_string(),
+            username: USERNAME.to_string(),
+            password: SecretString::from(format!("password{}", round)),
+            ..Default::default()
+        };
+        assert_eq!(password_manager.update_password(&options), Ok(()));
+    }
+    let history = &retrieve_identifier1_user1(&mut password_manager)
+        .unwrap()
+        .history;
+    assert_eq!(history.len(), 5);
+    assert_eq!(history[0].password, "password5");
+    assert_eq!(history[4].password, "password1");
+    assert!(history[0].replaced >= history[4].replaced);
+    drop(password_manager);
+
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not reopen password manager");
+    let credential = retrieve_identifier1_user1(&mut password_manager).unwrap();
+    assert_eq!(credential.history.len(), 5);
+    assert_eq!(credential.history[0].mask().password, "********");
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_reverts_to_an_earlier_password_and_back() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+    let _ = update_identifier1_user1_password1(&mut password_manager);
+    let id = retrieve_identifier1_user1(&mut password_manager)
+        .unwrap()
+        .id;
+
+    assert_eq!(
+        password_manager.revert_password(id, 1),
+        Err(ArmorPassError::NoHistoryEntryFound)
+    );
+    assert_eq!(password_manager.revert_password(id, 0), Ok(()));
+    let credential = retrieve_identifier1_user1(&mut password_manager).unwrap();
+    assert_eq!(credential.password, PASSWORD);
+    assert_eq!(credential.history.len(), 1);
+    assert_eq!(credential.history[0].password, NEW_PASSWORD);
+    drop(password_manager);
+
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not reopen password manager");
+    assert_eq!(password_manager.revert_password(id, 0), Ok(()));
+    assert_eq!(
+        retrieve_identifier1_user1(&mut password_manager)
+            .unwrap()
+            .password,
+        NEW_PASSWORD
+    );
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_trims_histories_to_a_shorter_length_kept_in_the_vault() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+    for round in 0..3 {
+        let options = UpdatePasswordOptions {
+            identifier: IDENTIFIER.to_string(),
+            username: USERNAME.to_string(),
+            password: SecretString::from(format!("password{}", round)),
+            ..Default::default()
+        };
+        assert_eq!(password_manager.update_password(&options), Ok(()));
+    }
+    assert_eq!(password_manager.set_password_history_length(1), Ok(()));
+    drop(password_manager);
+
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not reopen password manager");
+    assert_eq!(password_manager.password_history_length(), 1);
+    let history = &retrieve_identifier1_user1(&mut password_manager)
+        .unwrap()
+        .history;
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].password, "password1");
+
+    assert_eq!(password_manager.set_password_history_length(0), Ok(()));
+    let _ = update_identifier1_user1_password1(&mut password_manager);
+    assert!(retrieve_identifier1_user1(&mut password_manager)
+        .unwrap()
+        .history
+        .is_empty());
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_keeps_custom_fields_in_order_and_masks_the_concealed_ones() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let options = CreatePasswordOptions {
+        identifier: IDENTIFIER.to_string(),
+        username: USERNAME.to_string(),
+        password: SecretString::from(PASSWORD),
+        fields: vec![
+            CustomField {
+                name: "region".to_string(),
+                value: SecretString::from("eu-west-1"),
+                concealed: false,
+            },
+            CustomField {
+                name: "api secret".to_string(),
+                value: SecretString::from("s3cr3t"),
+                concealed: true,
+            },
+        ],
+        ..Default::default()
+    };
+    assert_eq!(password_manager.store_password(&options), Ok(()));
+    drop(password_manager);
+
+    let password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not reopen password manager");
+    let masked = password_manager.retrieve_all_credentials_masked(&RetrieveAllOptions {
+        identifier: IDENTIFIER.to_string(),
+    });
+    let fields = &masked[0].fields;
+    assert_eq!(fields.len(), 2);
+    assert_eq!(
+        (fields[0].name.as_str(), fields[0].value.as_str()),
+        ("region", "eu-west-1")
+    );
+    assert_eq!(
+        (fields[1].name.as_str(), fields[1].value.as_str()),
+        ("api secret", "********")
+    );
+    let credential = password_manager
+        .retrieve_credential(&RetrieveSingleOptions {
+            identifier: IDENTIFIER.to_string(),
+            username: USERNAME.to_string(),
+        })
+        .unwrap();
+    assert_eq!(credential.fields[1].value, "s3cr3t");
+    teardown(&tmpfile);
+}
+
+#[test]
+fn it_rejects_custom_fields_without_a_name_of_their_own() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let region = CustomField {
+        name: "region".to_string(),
+        value: SecretString::from("eu-west-1"),
+        concealed: false,
+    };
+    let options = CreatePasswordOptions {
+        identifier: IDENTIFIER.to_string(),
+        username: USERNAME.to_string(),
+        password: SecretString::from(PASSWORD),
+        fields: vec![region.clone(), region.clone()],
+        ..Default::default()
+    };
+    assert_eq!(
+        password_manager.store_password(&options),
+        Err(ArmorPassError::InvalidCustomFieldName)
+    );
+    assert!(!password_manager.has_password(IDENTIFIER, USERNAME));
+
+    let _ = store_identifier1_user1_password1(&mut password_manager);
+    let update_options = UpdatePasswordOptions {
+        identifier: IDENTIFIER.to_string(),
+        username: USERNAME.to_string(),
+        password: SecretString::from(PASSWORD),
+        fields: Some(vec![region.clone()]),
+        ..Default::default()
+    };
+    assert_eq!(password_manager.update_password(&update_options), Ok(()));
+    let unnamed_options = UpdatePasswordOptions {
+        fields: Some(vec![region, CustomField::default()]),
+        ..update_options
+    };
+    assert_eq!(
+        password_manager.update_password(&unnamed_options),
+        Err(ArmorPassError::InvalidCustomFieldName)
+    );
+    assert_eq!(
+        retrieve_identifier1_user1(&mut password_manager)
+            .unwrap()
+            .fields
+            .len(),
+        1
+    );
+    teardown(&tmpfile);
+}
+
+fn payment_card_options() -> CreatePasswordOptions {
+    CreatePasswordOptions {
+        entry_type: EntryType::PaymentCard,
+        identifier: "bank card".to_string(),
+        username: "A Holder".to_string(),
+        password: SecretString::from("4111 1111 1111 1111"),
+        fields: vec![
+            CustomField {
+                name: "expiry".to_string(),
+                value: SecretString::from("09/28"),
+                concealed: false,
+            },
+            CustomField {
+                name: "cvv".to_string(),
+                value: SecretString::from("123"),
+                concealed: true,
+            },
+        ],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn it_stores_typed_entries_and_checks_them_against_their_schema() {
+    let tmpfile = generate_unique_file_path();
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not create password manager");
+    let card = payment_card_options();
+    assert_eq!(password_manager.store_password(&card), Ok(()));
+
+    let expired_card = CreatePasswordOptions {
+        username: "Another Holder".to_string(),
+        password: SecretString::from("4111 1111 1111 1112"),
+        ..payment_card_options()
+    };
+    assert_eq!(
+        password_manager.store_password(&expired_card),
+        Err(ArmorPassError::InvalidEntryField("card number".to_string()))
+    );
+
+    let note = CreatePasswordOptions {
+        entry_type: EntryType::SecureNote,
+        identifier: "wifi at home".to_string(),
+        password: SecretString::from("network: home\nkey: 4111 1111 1111 1111"),
+        ..Default::default()
+    };
+    assert_eq!(password_manager.store_password(&note), Ok(()));
+    let named_note = CreatePasswordOptions {
+        entry_type: EntryType::SecureNote,
+        identifier: "another note".to_string(),
+        username: USERNAME.to_string(),
+        password: SecretString::from("text"),
+        ..Default::default()
+    };
+    assert_eq!(
+        password_manager.store_password(&named_note),
+        Err(ArmorPassError::InvalidEntryField("username".to_string()))
+    );
+    drop(password_manager);
+
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not reopen password manager");
+    let masked = password_manager.retrieve_all_credentials_masked(&RetrieveAllOptions {
+        identifier: "bank card".to_string(),
+    });
+    assert_eq!(masked.len(), 1);
+    assert_eq!(masked[0].entry_type, EntryType::PaymentCard);
+    assert_eq!(masked[0].fields[1].value, "********");
+
+    let missing_cvv = UpdatePasswordOptions {
+        identifier: "bank card".to_string(),
+        username: "A Holder".to_string(),
+        password: SecretString::from("4111 1111 1111 1111"),
+        fields: Some(card.fields[..1].to_vec()),
+        ..Default::default()
+    };
+    assert_eq!(
+        password_manager.update_password(&missing_cvv),
+        Err(ArmorPassError::InvalidEntryField("cvv".to_string()))
+    );
+    assert!(password_manager.has_password("wifi at home", ""));
+    teardown(&tmpfile);
+}
+
+// notes have no username, so two with the same title are told apart by id alone
+fn check_notes_can_share_an_identifier<S: VaultStorage>(open: impl Fn() -> S) {
+    let mut password_manager = PasswordManager::with_storage(open(), MASTERPASSWORD, None)
+        .expect("could not create password manager");
+    let note = |text: &str| CreatePasswordOptions {
+        entry_type: EntryType::SecureNote,
+        identifier: "recipes".to_string(),
+        password: SecretString::from(text),
+        ..Default::default()
+    };
+    assert_eq!(password_manager.store_password(&note("bread")), Ok(()));
+    assert_eq!(password_manager.store_password(&note("soup")), Ok(()));
+    let ids: Vec<Uuid> = password_manager
+        .retrieve_all_credentials(&RetrieveAllOptions {
+            identifier: "recipes".to_string(),
+        })
+        .iter()
+        .map(|note| note.id)
+        .collect();
+    assert_eq!(ids.len(), 2);
+
+    let update = UpdatePasswordOptions {
+        identifier: "recipes".to_string(),
+        password: SecretString::from("better soup"),
+        ..Default::default()
+    };
+    assert_eq!(
+        password_manager.update_credential_by_id(ids[1], &update),
+        Ok(())
+    );
+    assert_eq!(password_manager.record_use_by_id(ids[0]), Ok(()));
+    drop(password_manager);
+
+    let mut password_manager = PasswordManager::with_storage(open(), MASTERPASSWORD, None)
+        .expect("could not reopen password manager");
+    let bread = password_manager.retrieve_credential_by_id(ids[0]).unwrap();
+    assert_eq!(bread.password, "bread");
+    assert!(bread.last_used.is_some());
+    assert_eq!(
+        password_manager
+            .retrieve_credential_by_id(ids[1])
+            .unwrap()
+            .password,
+        "better soup"
+    );
+    assert_eq!(password_manager.delete_credential_by_id(ids[0]), Ok(()));
+    drop(password_manager);
+
+    let password_manager = PasswordManager::with_storage(open(), MASTERPASSWORD, None)
+        .expect("could not reopen password manager");
+    assert!(password_manager.retrieve_credential_by_id(ids[0]).is_none());
+    assert!(password_manager.retrieve_credential_by_id(ids[1]).is_some());
+}
+
+#[test]
+fn it_keeps_notes_sharing_an_identifier_apart() {
+    let tmpfile = generate_unique_file_path();
+    check_notes_can_share_an_identifier(|| FileStorage::new(tmpfile.clone()));
+    teardown(&tmpfile);
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn it_keeps_notes_sharing_an_identifier_apart_in_sqlite() {
+    let database = generate_unique_file_path().with_extension("db");
+    check_notes_can_share_an_identifier(|| SqliteStorage::open(database.clone()).unwrap());
+    let _ = std::fs::remove_file(&database);
+    let _ = std::fs::remove_file(format!("{}.lock", database.display()));
+}
+
+#[test]
+fn it_loads_entries_stored_before_kinds_as_logins() {
+    let tmpfile = generate_unique_file_path();
+    let mut crypto_manager =
+        CryptoManager::new(&tmpfile, MASTERPASSWORD).expect("could not create crypto manager");
+    crypto_manager
+        .encrypt_and_persist(
+            br#"[{"identifier":"website.com","username":"muhusername","password":"p@&^ssW07Rd1Afe"}]"#,
+        )
+        .unwrap();
+    drop(crypto_manager);
+
+    let mut password_manager = PasswordManager::new(tmpfile.clone(), MASTERPASSWORD)
+        .expect("could not open password manager");
+    assert_eq!(
+        retrieve_identifier1_user1(&mut password_manager)
+            .unwrap()
+            .entry_type,
+        EntryType::Login
+    );
+    // only logins are held to unique passwords and usernames across the vault
+    let card_with_a_login_username = CreatePasswordOptions {
+        username: USERNAME.to_string(),
+        ..payment_card_options()
+    };
+    assert_eq!(
+        password_manager.store_password(&card_with_a_login_username),
+        Ok(())
+    );
+    teardown(&tmpfile);
+}